@@ -6,29 +6,38 @@ use ungeckit::utils::net::ws;
 use simplelog::*;
 use fake_user_agent::get_rua;
 
-fn main() {
+#[tokio::main]
+async fn main() {
     TermLogger::init(LevelFilter::Debug, Config::default(), TerminalMode::Mixed, ColorChoice::Auto).unwrap();
 
-    let context = service::Context::new();
     let session_id = "c8165f2e-f79f-4af6-b0f4-03227570212e";
     let ws_url = format!("ws://127.0.0.1:9222/session/{}", session_id);
     //let ws_url = "ws://127.0.0.1:9222";
-    let mut ws = cdp::CDP::new(context, &ws_url);
+    let ws = cdp::CDP::new(&ws_url).await.unwrap();
     //let cmd = format!(r#"{{"id": {}, "cmd": "protocol"}}"#, session_id);
     //let url = r#"/json/protocol"#;
     //let cmd = r#"{"method": "/json/protocol", "params": "", "waitingForDebugger":""}"#;
-    let mut resp = ws.send("session.subscribe", r#"{"events":["browsingContext.domContentLoaded"]}"#).unwrap();
-    resp = ws.send("browser.createUserContext", "{}").unwrap();
+    let mut resp = ws
+        .send(
+            "session.subscribe",
+            r#"{"events":["browsingContext.domContentLoaded"]}"#,
+        )
+        .await
+        .unwrap();
+    resp = ws.send("browser.createUserContext", "{}").await.unwrap();
     println!("{:?}", resp);
     let mut userContext = &resp["result"]["userContext"].as_str().unwrap();
 
     let mut scriptParams = format!(r#"{{ "type":"tab", "userContext": "{}" }}"#, userContext);
-    let mut browsingContext = ws.send("browsingContext.create", &scriptParams).unwrap();
+    let mut browsingContext = ws
+        .send("browsingContext.create", &scriptParams)
+        .await
+        .unwrap();
     let rua = get_rua();
 
-    //resp = ws.send("Network.setUserAgentOverride", &format!(r#"{{"userAgent": "{}"}}"#, rua)).unwrap();
+    //resp = ws.send("Network.setUserAgentOverride", &format!(r#"{{"userAgent": "{}"}}"#, rua)).await.unwrap();
     scriptParams = format!(r#"{{"expression": "return navigator.webdriver", "awaitPromise":true, "userContext": "{}" }}"#, userContext);
     println!("{}", scriptParams);
-    resp = ws.send("script.evaluate", &scriptParams).unwrap();
+    resp = ws.send("script.evaluate", &scriptParams).await.unwrap();
     println!("{:?}", resp);
 }
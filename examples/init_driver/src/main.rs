@@ -9,11 +9,11 @@ fn main() {
     TermLogger::init(LevelFilter::Debug, Config::default(), TerminalMode::Mixed, ColorChoice::Auto).unwrap();
     if(fs::exists("intoli.png").unwrap()) {
         fs::remove_file("intoli.png").unwrap();
-        save_screenshot!("https://intoli.com/blog/not-possible-to-block-chrome-headless/chrome-headless-test.html", "intoli.png");
+        save_screenshot!("https://intoli.com/blog/not-possible-to-block-chrome-headless/chrome-headless-test.html", "intoli.png").unwrap();
     }
     if(fs::exists("sannysoft.png").unwrap()) {
         fs::remove_file("sannysoft.png").unwrap();
-        save_screenshot!("https://bot.sannysoft.com/", "sannysoft.png");
+        save_screenshot!("https://bot.sannysoft.com/", "sannysoft.png").unwrap();
     }
 
     
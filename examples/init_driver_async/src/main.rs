@@ -1,6 +1,4 @@
-use std::vec::Vec;
-use std::{thread, time};
-use rust_geck::sync;
+use ungeckit::*;
 
 use simplelog::*;
 use tokio::signal;
@@ -8,7 +6,8 @@ use tokio::signal;
 #[tokio::main]
 async fn main() {
     TermLogger::init(LevelFilter::Debug, Config::default(), TerminalMode::Mixed, ColorChoice::Auto).unwrap();
-    let mut driver = sync::Driver::new(None).await;
-    let mut driver = sync::Driver::get("https://yukselyonsel.com").await;
+    let driver = DriverBuilder::new(None).build_async().await.unwrap();
+    driver.get("https://yukselyonsel.com").await.unwrap();
     signal::ctrl_c().await.expect("failed to listen sigint");
+    driver.quit().await.unwrap();
 }
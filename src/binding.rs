@@ -0,0 +1,339 @@
+/*
+Page bindings: a global function of the page calling back into Rust. Calls travel as
+`script.message` events over a BiDi channel, the result is sent back with a follow-up
+`script.callFunction` in the realm of the caller.
+ */
+use futures::future::BoxFuture;
+use log::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+
+use crate::cdp::CDP;
+use crate::schemas::script::Target;
+use crate::schemas::value::{ChannelValue, LocalValue, RemoteValue};
+use crate::utils::error::GeckError;
+
+/// Defines the binding as a global function returning a promise. `send` is the channel, the
+/// function under `key` settles the pending calls.
+const PRELOAD: &str = r#"(send, name, key) => {
+    const pending = new Map();
+    let next = 0;
+    const call = (...args) => new Promise((resolve, reject) => {
+        const id = next++;
+        pending.set(id, { resolve, reject });
+        send({ id, args });
+    });
+    const settle = (id, value, error) => {
+        const call = pending.get(id);
+        pending.delete(id);
+        if (call) error === null ? call.resolve(value) : call.reject(new Error(error));
+    };
+    Object.defineProperty(window, name, { value: call, configurable: true });
+    Object.defineProperty(window, key, { value: settle });
+}"#;
+
+const SETTLE: &str = "(key, id, value, error) => window[key](id, value, error)";
+
+/// A call of a binding from the page
+#[derive(Debug, Clone)]
+pub struct BindingCall {
+    /// Browsing context of the caller
+    pub context: Option<String>,
+    /// Realm of the caller, the result is sent there
+    pub realm: String,
+    /// The arguments as JSON, see `args` to deserialize them
+    pub args: Vec<Value>,
+}
+
+impl BindingCall {
+    /// Deserializes the arguments, e.g. into a tuple
+    pub fn args<T: DeserializeOwned>(&self) -> Result<T, GeckError> {
+        Ok(serde_json::from_value(Value::Array(self.args.clone()))?)
+    }
+}
+
+#[derive(Deserialize)]
+struct Message {
+    id: u64,
+    #[serde(default)]
+    args: Vec<Value>,
+}
+
+type Callback =
+    Arc<dyn Fn(BindingCall) -> BoxFuture<'static, Result<LocalValue, GeckError>> + Send + Sync>;
+
+/// An installed binding, the page keeps the function until `remove` is called. Dropping the
+/// binding stops answering the calls.
+pub struct Binding {
+    name: String,
+    script: String,
+    bidi: Arc<CDP>,
+    subscription: Option<String>,
+    listener: JoinHandle<()>,
+}
+
+impl Binding {
+    /// Defines `window[name]` in the new documents of the contexts, of every context when
+    /// none is given. Calling it from the page runs the callback, the promise it returns
+    /// settles with the value or the error of the callback.
+    pub async fn install<F, Fut, R>(
+        bidi: Arc<CDP>,
+        name: &str,
+        contexts: Option<&[String]>,
+        sandbox: Option<&str>,
+        callback: F,
+    ) -> Result<Self, GeckError>
+    where
+        F: Fn(BindingCall) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R, GeckError>> + Send + 'static,
+        R: Serialize,
+    {
+        let callback: Callback = Arc::new(move |call| {
+            let result = callback(call);
+            Box::pin(async move { LocalValue::from_serialize(result.await?) })
+        });
+        let token = RandomState::new().build_hasher().finish();
+        let channel = format!("binding-{}-{:x}", name, token);
+        let key = format!("__{:x}", token);
+
+        let mut events = bidi.events();
+        let subscription = bidi
+            .subscribe(&["script.message"], contexts.unwrap_or_default())
+            .await?;
+        let args = [
+            LocalValue::Channel(ChannelValue {
+                channel: channel.clone(),
+                serialization_options: None,
+                ownership: None,
+            }),
+            LocalValue::String(name.to_owned()),
+            LocalValue::String(key.clone()),
+        ];
+        let script = bidi
+            .add_preload_script(PRELOAD, &args, contexts, sandbox)
+            .await?;
+
+        let listener = tokio::spawn({
+            let (bidi, name) = (bidi.clone(), name.to_owned());
+            async move {
+                loop {
+                    let event = match events.recv().await {
+                        Ok(event) => event,
+                        Err(RecvError::Lagged(missed)) => {
+                            warn!("Binding {} missed {} events", name, missed);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => break,
+                    };
+                    let params = &event["params"];
+                    if event["method"] != "script.message" || params["channel"] != channel.as_str()
+                    {
+                        continue;
+                    }
+                    let message = serde_json::from_value::<RemoteValue>(params["data"].clone())
+                        .map_err(GeckError::from)
+                        .and_then(|data| data.into_typed::<Message>());
+                    let message = match message {
+                        Ok(message) => message,
+                        Err(e) => {
+                            warn!("Binding {} got an unexpected message: {}", name, e);
+                            continue;
+                        }
+                    };
+                    let call = BindingCall {
+                        context: params["source"]["context"].as_str().map(|c| c.to_owned()),
+                        realm: params["source"]["realm"]
+                            .as_str()
+                            .unwrap_or_default()
+                            .to_owned(),
+                        args: message.args,
+                    };
+                    let (bidi, callback, key, name) =
+                        (bidi.clone(), callback.clone(), key.clone(), name.clone());
+                    tokio::spawn(async move {
+                        let target = Target::realm(&call.realm);
+                        let (value, error) = match callback(call).await {
+                            Ok(value) => (value, LocalValue::Null),
+                            Err(e) => (LocalValue::Undefined, LocalValue::String(e.to_string())),
+                        };
+                        let args = [
+                            LocalValue::String(key),
+                            LocalValue::Number(message.id as f64),
+                            value,
+                            error,
+                        ];
+                        if let Err(e) = bidi.call_function(&target, SETTLE, &args).await {
+                            // The page may be gone already
+                            debug!(
+                                "Cannot answer call {} of binding {}: {}",
+                                message.id, name, e
+                            );
+                        }
+                    });
+                }
+            }
+        });
+        Ok(Self {
+            name: name.to_owned(),
+            script,
+            bidi,
+            subscription,
+            listener,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Id of the preload script defining the binding
+    pub fn script(&self) -> &str {
+        &self.script
+    }
+
+    /// Removes the binding from the next documents and stops answering calls
+    pub async fn remove(mut self) -> Result<(), GeckError> {
+        self.listener.abort();
+        if let Some(subscription) = self.subscription.take() {
+            self.bidi.unsubscribe(&subscription).await?;
+        }
+        self.bidi.remove_preload_script(&self.script).await
+    }
+}
+
+impl Drop for Binding {
+    fn drop(&mut self) {
+        self.listener.abort();
+        let (Some(subscription), Ok(handle)) = (self.subscription.take(), Handle::try_current())
+        else {
+            return;
+        };
+        let bidi = self.bidi.clone();
+        handle.spawn(async move {
+            if let Err(e) = bidi.unsubscribe(&subscription).await {
+                debug!("Cannot end the subscription {}: {}", subscription, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tab::Tab;
+    use crate::utils::error::{ErrorKind, GeckError};
+    use crate::utils::testing::StandIn;
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn test_binding() {
+        let browser = StandIn::start("binding").await;
+        let driver = browser.driver().await.unwrap();
+        let tab = Tab::new(driver.clone(), "tab-uc-a");
+        let (sender, mut calls) = mpsc::unbounded_channel();
+        let binding = tab
+            .expose_function("onCaptcha", move |call: BindingCall| {
+                let sender = sender.clone();
+                async move {
+                    let (kind, count): (String, u32) = call.args()?;
+                    sender.send((call.context, kind.clone())).unwrap();
+                    match kind.as_str() {
+                        "fail" => Err(GeckError::new(
+                            ErrorKind::Other,
+                            None::<GeckError>,
+                            "Unsolved",
+                        )),
+                        _ => Ok(count * 2),
+                    }
+                }
+            })
+            .await
+            .unwrap();
+
+        let (channel, key) = {
+            let state = browser.browser();
+            let (script, params) = &state.preload_scripts[0];
+            assert_eq!(script, binding.script());
+            assert_eq!(params["contexts"], json!(["tab-uc-a"]));
+            assert_eq!(
+                params["arguments"][1],
+                json!({"type": "string", "value": "onCaptcha"})
+            );
+            let channel = params["arguments"][0]["value"]["channel"]
+                .as_str()
+                .unwrap()
+                .to_owned();
+            (
+                channel,
+                params["arguments"][2]["value"].as_str().unwrap().to_owned(),
+            )
+        };
+        let message = |channel: &str, id: u32, kind: &str| {
+            json!({ "channel": channel, "source": { "realm": "realm-7", "context": "tab-uc-a" },
+                "data": { "type": "object", "value": [
+                    ["id", { "type": "number", "value": id }],
+                    ["args", { "type": "array", "value": [
+                        { "type": "string", "value": kind }, { "type": "number", "value": 21 }] }],
+                ] } })
+        };
+        browser.emit("script.message", message("other-channel", 0, "ignored"));
+        browser.emit("script.message", message(&channel, 0, "recaptcha"));
+        browser.emit("script.message", message(&channel, 1, "fail"));
+
+        let mut seen = [calls.recv().await.unwrap(), calls.recv().await.unwrap()];
+        seen.sort();
+        assert_eq!(seen[0], (Some("tab-uc-a".to_owned()), "fail".to_owned()));
+        assert_eq!(seen[1].1, "recaptcha");
+        let settled = tokio::time::timeout(Duration::from_secs(1), async {
+            loop {
+                let calls = browser.browser().calls.clone();
+                if calls.len() == 2 {
+                    break calls;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let mut settled: Vec<Value> = settled
+            .into_iter()
+            .map(|call| {
+                assert_eq!(call["target"], json!({ "realm": "realm-7" }));
+                assert_eq!(call["arguments"][0]["value"], key.as_str());
+                call["arguments"].clone()
+            })
+            .collect();
+        settled.sort_by_key(|args| args[1]["value"].as_f64().unwrap() as u32);
+        assert_eq!(settled[0][2], json!({ "type": "number", "value": 42.0 }));
+        assert_eq!(settled[0][3], json!({ "type": "null" }));
+        assert_eq!(
+            settled[1][3],
+            json!({ "type": "string", "value": "Error: Unsolved" })
+        );
+        assert!(calls.try_recv().is_err());
+
+        let subscription = browser
+            .browser()
+            .subscriptions
+            .iter()
+            .position(|params| params["contexts"] == json!(["tab-uc-a"]))
+            .unwrap();
+        binding.remove().await.unwrap();
+        let state = browser.browser();
+        assert!(state.preload_scripts.is_empty());
+        assert_eq!(
+            state.unsubscriptions,
+            vec![json!({ "subscriptions": [format!("sub-{}", subscription + 1)] })]
+        );
+    }
+}
@@ -0,0 +1,663 @@
+/*
+Pool of warm Firefox instances leased to concurrent jobs. Every lease runs in its own
+BiDi user context, removing it on return resets the cookies, storage and tabs of the lease.
+ */
+use futures::future::{join_all, BoxFuture};
+use log::*;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use crate::cdp::CDP;
+use crate::driver_async::WebDriver;
+use crate::tab::Tab;
+use crate::utils::error::{ErrorKind, GeckError};
+use crate::DriverBuilder;
+
+/// Starts a browser for the pool
+pub type Launcher = Arc<dyn Fn() -> BoxFuture<'static, Result<WebDriver, GeckError>> + Send + Sync>;
+
+/// Size and recycling policy of a pool
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolConfig {
+    /// Number of Firefox instances
+    pub browsers: usize,
+    /// Leases served at the same time by a browser, each in its own user context
+    pub contexts: usize,
+    /// Leases served by a browser before it is restarted
+    pub max_uses: Option<u32>,
+    /// How long `acquire` waits for a free slot, forever when not set
+    pub acquire_timeout: Option<Duration>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            browsers: 4,
+            contexts: 1,
+            max_uses: Some(100),
+            acquire_timeout: None,
+        }
+    }
+}
+
+/// A snapshot of the pool
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PoolStats {
+    /// Running browsers
+    pub browsers: usize,
+    /// Browsers being started
+    pub launching: usize,
+    /// Leases currently held by jobs
+    pub leased: usize,
+    /// Leases that can be served without starting a browser
+    pub available: usize,
+    pub launched: u64,
+    pub launch_failures: u64,
+    /// Browsers stopped after reaching `max_uses` or failing a reset
+    pub recycled: u64,
+    /// Leases served since the pool was created
+    pub leases: u64,
+    pub failed_resets: u64,
+}
+
+struct Browser {
+    id: usize,
+    /// `None` while the browser is starting
+    driver: Option<WebDriver>,
+    leased: usize,
+    uses: u32,
+    /// Retiring browsers serve no new leases and stop once their leases are returned
+    retiring: bool,
+}
+
+#[derive(Default)]
+struct State {
+    browsers: Vec<Browser>,
+    next_id: usize,
+    stats: PoolStats,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// One permit per lease slot
+    permits: Arc<Semaphore>,
+    /// Wakes the jobs waiting for a browser to start or to stop
+    changed: Notify,
+    launcher: Launcher,
+    config: PoolConfig,
+}
+
+/// Keeps warm browsers and leases them to jobs. Cloning the pool shares its browsers.
+#[derive(Clone)]
+pub struct BrowserPool {
+    shared: Arc<Shared>,
+}
+
+/// A free port for a pooled geckodriver
+fn free_port() -> Result<u16, GeckError> {
+    Ok(std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .port())
+}
+
+impl BrowserPool {
+    /// Browsers are built by the builder, each geckodriver listens on its own free port
+    pub fn new(builder: DriverBuilder, config: PoolConfig) -> Self {
+        let launcher: Launcher = Arc::new(move || {
+            let mut builder = builder.clone();
+            Box::pin(async move {
+                builder.port(free_port()?)?;
+                builder.build_async().await
+            })
+        });
+        Self::with_launcher(launcher, config)
+    }
+
+    /// Browsers are started by the launcher, e.g. to attach to remote geckodrivers. The pool
+    /// keeps at least one browser with one context.
+    pub fn with_launcher(launcher: Launcher, mut config: PoolConfig) -> Self {
+        config.browsers = config.browsers.max(1);
+        config.contexts = config.contexts.max(1);
+        let slots = config.browsers * config.contexts;
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State::default()),
+                permits: Arc::new(Semaphore::new(slots)),
+                changed: Notify::new(),
+                launcher,
+                config,
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &PoolConfig {
+        &self.shared.config
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let state = self.shared.state.lock().unwrap();
+        let mut stats = state.stats.clone();
+        stats.browsers = state.browsers.iter().filter(|b| b.driver.is_some()).count();
+        stats.launching = state.browsers.len() - stats.browsers;
+        stats.leased = state.browsers.iter().map(|b| b.leased).sum();
+        stats.available = state
+            .browsers
+            .iter()
+            .filter(|b| b.driver.is_some() && !b.retiring)
+            .map(|b| self.shared.config.contexts - b.leased)
+            .sum();
+        stats
+    }
+
+    /// Starts the missing browsers concurrently, returns the number of running browsers
+    pub async fn warm(&self) -> Result<usize, GeckError> {
+        let ids: Vec<usize> = {
+            let mut state = self.shared.state.lock().unwrap();
+            let missing = self
+                .shared
+                .config
+                .browsers
+                .saturating_sub(state.browsers.len());
+            (0..missing).map(|_| Self::reserve(&mut state, 0)).collect()
+        };
+        let results = join_all(ids.iter().map(|id| self.launch(*id))).await;
+        for result in results {
+            result?;
+        }
+        Ok(self.stats().browsers)
+    }
+
+    /// Adds a browser being started, with `leased` slots taken by the caller
+    fn reserve(state: &mut State, leased: usize) -> usize {
+        let id = state.next_id;
+        state.next_id += 1;
+        state.browsers.push(Browser {
+            id,
+            driver: None,
+            leased,
+            uses: leased as u32,
+            retiring: false,
+        });
+        id
+    }
+
+    /// Starts a reserved browser, it is stopped right away when the pool closed meanwhile
+    /// and no lease waits for it
+    async fn launch(&self, id: usize) -> Result<WebDriver, GeckError> {
+        let result = (self.shared.launcher)().await;
+        let retired = {
+            let mut state = self.shared.state.lock().unwrap();
+            match &result {
+                Ok(driver) => {
+                    state.stats.launched += 1;
+                    let position = state.browsers.iter().position(|b| b.id == id);
+                    match position {
+                        Some(position) if state.browsers[position].retiring => {
+                            let browser = &mut state.browsers[position];
+                            browser.driver = Some(driver.clone());
+                            (browser.leased == 0).then(|| state.browsers.remove(position))
+                        }
+                        Some(position) => {
+                            state.browsers[position].driver = Some(driver.clone());
+                            None
+                        }
+                        None => None,
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to start pooled browser {}: {}", id, e);
+                    state.stats.launch_failures += 1;
+                    state.browsers.retain(|b| b.id != id);
+                    None
+                }
+            }
+        };
+        self.shared.changed.notify_waiters();
+        match (retired, result) {
+            (Some(browser), Ok(driver)) => {
+                if let Err(e) = driver.quit().await {
+                    warn!("Failed to quit pooled browser {}: {}", browser.id, e);
+                }
+                Err(Self::pool_error("Browser pool is closed"))
+            }
+            (_, result) => result,
+        }
+    }
+
+    /// Waits for the future until the deadline of `acquire`
+    async fn until<F: Future>(
+        deadline: Option<Instant>,
+        future: F,
+    ) -> Result<F::Output, GeckError> {
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, future)
+                .await
+                .map_err(|_| Self::pool_error("Timed out waiting for a pooled browser")),
+            None => Ok(future.await),
+        }
+    }
+
+    /// Leases a browser, waits for a free slot and starts a browser when the pool is not full.
+    /// `acquire_timeout` bounds the waits for a slot and for a browser, not the start of one.
+    pub async fn acquire(&self) -> Result<Lease, GeckError> {
+        let deadline = self
+            .shared
+            .config
+            .acquire_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let permits = self.shared.permits.clone();
+        let permit = Self::until(deadline, permits.acquire_owned())
+            .await?
+            .map_err(|_| Self::pool_error("Browser pool is closed"))?;
+
+        let (id, driver) = loop {
+            let changed = self.shared.changed.notified();
+            if self.shared.permits.is_closed() {
+                return Err(Self::pool_error("Browser pool is closed"));
+            }
+            let launching = {
+                let mut state = self.shared.state.lock().unwrap();
+                let contexts = self.shared.config.contexts;
+                let max_uses = self.shared.config.max_uses;
+                let browsers = state.browsers.len();
+                let free = state
+                    .browsers
+                    .iter_mut()
+                    .filter(|b| {
+                        b.driver.is_some()
+                            && !b.retiring
+                            && b.leased < contexts
+                            && max_uses.is_none_or(|max| b.uses < max)
+                    })
+                    .min_by_key(|b| b.leased);
+                match free {
+                    Some(browser) => {
+                        browser.leased += 1;
+                        browser.uses += 1;
+                        break (browser.id, browser.driver.clone());
+                    }
+                    None if browsers < self.shared.config.browsers => {
+                        Some(Self::reserve(&mut state, 1))
+                    }
+                    // Another job starts a browser or a retiring browser is draining
+                    None => None,
+                }
+            };
+            match launching {
+                Some(id) => break (id, Some(self.launch(id).await?)),
+                None => Self::until(deadline, changed).await?,
+            }
+        };
+        let driver = driver.ok_or_else(|| Self::pool_error("Pooled browser is not running"))?;
+        self.shared.state.lock().unwrap().stats.leases += 1;
+
+        match Self::open(&driver).await {
+            Ok((user_context, context)) => Ok(Lease {
+                pool: self.clone(),
+                browser: id,
+                driver,
+                user_context,
+                context,
+                permit: Some(permit),
+                handle: Handle::current(),
+            }),
+            Err(e) => {
+                self.release(id, &driver, None, permit, None).await;
+                Err(e)
+            }
+        }
+    }
+
+    /// The user context and the tab of a new lease
+    async fn open(driver: &WebDriver) -> Result<(String, String), GeckError> {
+        let bidi = driver.bidi().await?;
+        let user_context = bidi.create_user_context(None).await?;
+        let context = bidi.create_tab(Some(&user_context)).await?;
+        Ok((user_context, context))
+    }
+
+    /// Resets the lease and hands its slot back, the browser is stopped when it is due for
+    /// recycling. The lease is discarded when there is no user context to remove.
+    async fn release(
+        &self,
+        id: usize,
+        driver: &WebDriver,
+        user_context: Option<&str>,
+        permit: OwnedSemaphorePermit,
+        context: Option<&str>,
+    ) {
+        // The tab is closed with its user context
+        if let (Some(limiter), Some(context)) = (driver.rate_limiter(), context) {
+            limiter.leave(context);
+        }
+        let reset = match user_context {
+            Some(user_context) => match driver.bidi().await {
+                Ok(bidi) => bidi.remove_user_context(user_context).await.map(|_| true),
+                Err(e) => Err(e),
+            },
+            None => Ok(false),
+        };
+        let stopped = {
+            let mut state = self.shared.state.lock().unwrap();
+            if let Err(e) = &reset {
+                warn!(
+                    "Recycling pooled browser {} after a failed reset: {}",
+                    id, e
+                );
+                state.stats.failed_resets += 1;
+            }
+            let max_uses = self.shared.config.max_uses;
+            let position = state.browsers.iter().position(|b| b.id == id);
+            let done = position.and_then(|position| {
+                let browser = &mut state.browsers[position];
+                browser.leased -= 1;
+                browser.retiring |=
+                    !matches!(reset, Ok(true)) || max_uses.is_some_and(|max| browser.uses >= max);
+                (browser.retiring && browser.leased == 0).then_some(position)
+            });
+            done.map(|position| {
+                state.stats.recycled += 1;
+                state.browsers.remove(position)
+            })
+        };
+        if let Some(browser) = stopped {
+            info!(
+                "Recycling pooled browser {} after {} uses",
+                browser.id, browser.uses
+            );
+            if let Err(e) = driver.quit().await {
+                warn!("Failed to quit pooled browser {}: {}", browser.id, e);
+            }
+        }
+        self.shared.changed.notify_waiters();
+        drop(permit);
+    }
+
+    /// Closes the pool, `acquire` fails from now on, waiting jobs included. Stops the idle
+    /// browsers, the leased ones stop when they are returned.
+    pub async fn close(&self) {
+        self.shared.permits.close();
+        let idle: Vec<Browser> = {
+            let mut state = self.shared.state.lock().unwrap();
+            for browser in state.browsers.iter_mut() {
+                browser.retiring = true;
+            }
+            let (idle, leased) = state
+                .browsers
+                .drain(..)
+                .partition(|b| b.leased == 0 && b.driver.is_some());
+            state.browsers = leased;
+            idle
+        };
+        for driver in idle.iter().filter_map(|b| b.driver.as_ref()) {
+            if let Err(e) = driver.quit().await {
+                warn!("Failed to quit pooled browser: {}", e);
+            }
+        }
+        self.shared.changed.notify_waiters();
+    }
+
+    fn pool_error(msg: &str) -> GeckError {
+        GeckError::new(ErrorKind::Pool, None::<GeckError>, msg)
+    }
+}
+
+/// A browser leased from a `BrowserPool`, isolated in its own user context with its own tab.
+/// The lease is reset and returned when dropped, `release` waits for it.
+pub struct Lease {
+    pool: BrowserPool,
+    browser: usize,
+    driver: WebDriver,
+    user_context: String,
+    context: String,
+    permit: Option<OwnedSemaphorePermit>,
+    handle: Handle,
+}
+
+impl Lease {
+    pub fn driver(&self) -> &WebDriver {
+        &self.driver
+    }
+
+    /// The BiDi connection of the browser
+    pub async fn bidi(&self) -> Result<Arc<CDP>, GeckError> {
+        self.driver.bidi().await
+    }
+
+    /// Id of the user context isolating the lease
+    pub fn user_context(&self) -> &str {
+        &self.user_context
+    }
+
+    /// Browsing context id of the tab opened for the lease
+    pub fn context(&self) -> &str {
+        &self.context
+    }
+
+    /// The tab opened for the lease
+    pub fn tab(&self) -> Tab {
+        Tab::new(self.driver.clone(), &self.context)
+    }
+
+    /// Resets the lease and returns it to the pool
+    pub async fn release(mut self) {
+        if let Some(permit) = self.permit.take() {
+            self.pool
+                .release(
+                    self.browser,
+                    &self.driver,
+                    Some(&self.user_context),
+                    permit,
+                    Some(&self.context),
+                )
+                .await;
+        }
+    }
+
+    /// Returns the lease without a reset and recycles its browser, e.g. after a crash
+    pub async fn discard(mut self) {
+        if let Some(permit) = self.permit.take() {
+            self.pool
+                .release(
+                    self.browser,
+                    &self.driver,
+                    None,
+                    permit,
+                    Some(&self.context),
+                )
+                .await;
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            let pool = self.pool.clone();
+            let browser = self.browser;
+            let driver = self.driver.clone();
+            let user_context = self.user_context.clone();
+            let context = self.context.clone();
+            self.handle.spawn(async move {
+                pool.release(
+                    browser,
+                    &driver,
+                    Some(&user_context),
+                    permit,
+                    Some(&context),
+                )
+                .await;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::StandIn;
+
+    async fn settled(pool: &BrowserPool) -> PoolStats {
+        for _ in 0..100 {
+            if pool.stats().leased == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        pool.stats()
+    }
+
+    #[tokio::test]
+    async fn test_leases() {
+        let browser = StandIn::start("pool-leases").await;
+        let pool = BrowserPool::with_launcher(
+            browser.launcher(),
+            PoolConfig {
+                browsers: 1,
+                contexts: 2,
+                max_uses: Some(3),
+                acquire_timeout: Some(Duration::from_millis(200)),
+            },
+        );
+        assert_eq!(pool.warm().await.unwrap(), 1);
+        assert_eq!(pool.stats().available, 2);
+
+        let (first, second) = tokio::join!(pool.acquire(), pool.acquire());
+        let (first, second) = (first.unwrap(), second.unwrap());
+        assert_ne!(first.user_context(), second.user_context());
+        assert_eq!(first.context(), format!("tab-{}", first.user_context()));
+        let stats = pool.stats();
+        assert_eq!((stats.browsers, stats.leased, stats.available), (1, 2, 0));
+        assert!(pool.acquire().await.is_err());
+
+        let user_context = first.user_context().to_owned();
+        first.release().await;
+        assert_eq!(browser.browser().removed, vec![user_context]);
+        drop(second);
+        assert_eq!(settled(&pool).await.available, 2);
+        assert_eq!(browser.browser().removed.len(), 2);
+
+        // The third use recycles the browser, the next lease starts a new one
+        pool.acquire().await.unwrap().release().await;
+        let stats = pool.stats();
+        assert_eq!((stats.browsers, stats.recycled, stats.leases), (0, 1, 3));
+        assert_eq!(browser.browser().quits, 1);
+        let lease = pool.acquire().await.unwrap();
+        assert_eq!(pool.stats().launched, 2);
+        assert_eq!(browser.browser().sessions, 2);
+
+        lease.discard().await;
+        let stats = pool.stats();
+        assert_eq!(
+            (stats.browsers, stats.recycled, stats.failed_resets),
+            (0, 2, 0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_waits_for_slot() {
+        let browser = StandIn::start("pool-wait").await;
+        let pool = BrowserPool::with_launcher(
+            browser.launcher(),
+            PoolConfig {
+                browsers: 1,
+                contexts: 1,
+                max_uses: None,
+                acquire_timeout: None,
+            },
+        );
+        let lease = pool.acquire().await.unwrap();
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move {
+                pool.acquire()
+                    .await
+                    .map(|lease| lease.user_context().to_owned())
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        let user_context = lease.user_context().to_owned();
+        drop(lease);
+        assert_ne!(waiting.await.unwrap().unwrap(), user_context);
+        assert_eq!(pool.stats().launched, 1);
+
+        // Closing fails the waiting jobs and the later ones
+        let lease = pool.acquire().await.unwrap();
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.acquire().await.map(|_| ()) }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        pool.close().await;
+        assert!(waiting.await.unwrap().is_err());
+        assert!(pool.acquire().await.is_err());
+        lease.release().await;
+        assert_eq!(settled(&pool).await.browsers, 0);
+    }
+
+    #[tokio::test]
+    async fn test_close_while_warming() {
+        let browser = StandIn::start("pool-close-warm").await;
+        let launcher = browser.launcher();
+        let slow: Launcher = Arc::new(move || {
+            let launcher = launcher.clone();
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                launcher().await
+            })
+        });
+        let pool = BrowserPool::with_launcher(slow, PoolConfig::default());
+        let warming = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.warm().await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        pool.close().await;
+        assert!(warming.await.unwrap().is_err());
+        let stats = pool.stats();
+        assert_eq!((stats.browsers, stats.launching, stats.launched), (0, 0, 4));
+        assert_eq!(browser.browser().quits, 4);
+    }
+
+    #[tokio::test]
+    async fn test_empty_config() {
+        let browser = StandIn::start("pool-empty").await;
+        let pool = BrowserPool::with_launcher(
+            browser.launcher(),
+            PoolConfig {
+                browsers: 0,
+                contexts: 0,
+                max_uses: None,
+                acquire_timeout: Some(Duration::from_millis(500)),
+            },
+        );
+        assert_eq!((pool.config().browsers, pool.config().contexts), (1, 1));
+        pool.acquire().await.unwrap().release().await;
+    }
+
+    #[tokio::test]
+    async fn test_max_uses() {
+        let browser = StandIn::start("pool-max-uses").await;
+        let pool = BrowserPool::with_launcher(
+            browser.launcher(),
+            PoolConfig {
+                browsers: 1,
+                contexts: 2,
+                max_uses: Some(1),
+                acquire_timeout: Some(Duration::from_millis(100)),
+            },
+        );
+        // The browser has a free context but no use left, the next lease waits for a new one
+        let lease = pool.acquire().await.unwrap();
+        assert!(pool.acquire().await.is_err());
+        lease.release().await;
+        assert_eq!(pool.stats().recycled, 1);
+        pool.acquire().await.unwrap().release().await;
+        assert_eq!(browser.browser().sessions, 2);
+    }
+}
@@ -1,18 +1,27 @@
 /*
 Holds the logic to communicate with dev tools via CDP
  */
-use crate::utils::error::{ErrorKind, GeckError};
+use crate::utils::error::{ErrorKind, GeckError, WebDriverError};
 use crate::utils::webdriver_commands::WebdriverCmd;
-use crate::utils::net::ws::WebSocketClient;
-use crate::service::Context;
+use crate::utils::net::ws::{WebSocketClient, WsStream};
+use crate::schemas::navigation::{Navigation, ReadinessState};
+use crate::schemas::proxy::{Proxy, ProxyAuth};
+use crate::schemas::script::{self, RealmInfo, Target};
+use crate::schemas::value::{LocalValue, RemoteValue};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::{Arc, Mutex};
 
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::{broadcast, oneshot};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::protocol::Message;
+
 use serde::Deserialize;
 use serde::de::Visitor;
 use serde_json::{Value};
-use tokio_tungstenite::tungstenite::protocol::Message;
-use tracing_subscriber::fmt::format;
 
 
 #[derive(Debug)]
@@ -42,34 +51,37 @@ impl<'de> Deserialize<'de> for CDPMessage {
                 impl<'de> Visitor<'de> for CDPMessageVisitor {
                     type Value = CDPMessage;
                     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-                        write!(formatter, "a string, but got something else ungeck")
+                        write!(formatter, "a BiDi message object")
                     }
                     fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
                         where
                             A: serde::de::MapAccess<'de> {
                         let mut m_type = String::new();
                         let mut id = -1;
-                        let mut result = String::new();
-                        let mut error = String::new();
-                        let mut stacktrace = String::new();
-                        let mut message = String::new();
-                        while let Some(key) = map.next_key::<&str>()? {
-                            match key {
+                        let mut result = Value::Null;
+                        let mut error = None;
+                        let mut stacktrace = None;
+                        let mut message = None;
+                        while let Some(key) = map.next_key::<String>()? {
+                            match key.as_str() {
                                 "type" => m_type = map.next_value()?,
                                 "id" => id = map.next_value::<i32>()?,
                                 "result" => result = map.next_value()?,
                                 "error" => error = map.next_value()?,
                                 "stacktrace" => stacktrace = map.next_value()?,
                                 "message" => message = map.next_value()?,
-                                &_ => todo!()
+                                // Events and newer protocol fields are not modelled here
+                                _ => {
+                                    map.next_value::<serde::de::IgnoredAny>()?;
+                                }
                             }
                         }
                         if m_type == "success" {
                             Ok(CDPMessage::CDPResponseSuccess(
                                 CDPResponseSuccess{
-                                    m_type: m_type,
-                                    id: id,
-                                    result: Value::Null // Change later
+                                    m_type,
+                                    id,
+                                    result,
                                 }
                             ))
                         } else {
@@ -77,9 +89,9 @@ impl<'de> Deserialize<'de> for CDPMessage {
                                 CDPResponseFailure {
                                     m_type,
                                     id,
-                                    error: Some(error),
-                                    stacktrace: Some(stacktrace),
-                                    message: Some(message)
+                                    error,
+                                    stacktrace,
+                                    message,
                                 }
                             ))
 
@@ -90,21 +102,34 @@ impl<'de> Deserialize<'de> for CDPMessage {
     }
 
 }
+/// Commands waiting for their response, `None` once the connection is closed
+type Pending = Arc<Mutex<Option<HashMap<i64, oneshot::Sender<Value>>>>>;
+
+/// A shareable BiDi connection. Commands can be sent concurrently from many tasks, a
+/// reader task routes every response to its command by id and broadcasts the events.
 pub struct CDP {
     /// Command dictionary with specific webdriver commands for http endpoints
     pub command_dict: HashMap<&'static str, WebdriverCmd<'static>>,
-    /// WebSocket Client
-    pub ws_client: WebSocketClient,
+    /// Writing half of the websocket, only locked while a message is written
+    writer: tokio::sync::Mutex<SplitSink<WsStream, Message>>,
+    pending: Pending,
+    /// Events received on the connection, see `events`
+    events: broadcast::Sender<Value>,
     /// Id of the sent message, to be autoincremented on each sent message
-    pub id: i32,
+    id: AtomicI64,
+    reader: JoinHandle<()>,
 }
 
 impl CDP {
-    /// Create CDP wrapper
-    pub fn new(context_t: Arc<Mutex<Context>>, ws_url: &str) -> Self {
-        let mut ws_client = WebSocketClient::new(context_t, ws_url);
-        ws_client.connect();
-        Self {
+    /// Create CDP wrapper and connect to the websocket, must run inside a runtime
+    pub async fn new(ws_url: &str) -> Result<Self, GeckError> {
+        let mut ws_client = WebSocketClient::new(ws_url);
+        ws_client.connect().await?;
+        let (writer, stream) = ws_client.split()?;
+        let pending: Pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        let (events, _) = broadcast::channel(256);
+        let reader = tokio::spawn(Self::read(stream, pending.clone(), events.clone()));
+        Ok(Self {
             command_dict: HashMap::from([
 							("json", WebdriverCmd::from(("POST", "/json"))),
 							("protocol", WebdriverCmd::from(("POST", "/json/protocol"))),
@@ -113,25 +138,454 @@ impl CDP {
 							("activate", WebdriverCmd::from(("POST", "/json/activate/{{id}}"))),
 							("close", WebdriverCmd::from(("POST", "/json/close/{{id}}"))),
             ]),
-            ws_client,
-            id: 0,
+            writer: tokio::sync::Mutex::new(writer),
+            pending,
+            events,
+            id: AtomicI64::new(0),
+            reader,
+        })
+    }
+
+    /// Reads the connection until it closes. Pending commands fail once it is closed,
+    /// their senders are dropped with the map.
+    async fn read(
+        mut stream: SplitStream<WsStream>,
+        pending: Pending,
+        events: broadcast::Sender<Value>,
+    ) {
+        while let Some(message) = stream.next().await {
+            let data = match message {
+                Ok(Message::Text(data)) => data,
+                Ok(_) => continue,
+                Err(e) => {
+                    log::error!("BiDi connection failed: {}", e);
+                    break;
+                }
+            };
+            let parsed_msg: Value = match serde_json::from_str(&data) {
+                Ok(parsed_msg) => parsed_msg,
+                Err(e) => {
+                    log::error!("Cannot parse BiDi message {:?}: {}", data, e);
+                    continue;
+                }
+            };
+            let waiting = parsed_msg["id"]
+                .as_i64()
+                .and_then(|id| pending.lock().unwrap().as_mut()?.remove(&id));
+            match waiting {
+                Some(sender) => {
+                    let _ = sender.send(parsed_msg);
+                }
+                // Nobody may be subscribed
+                None => {
+                    let _ = events.send(parsed_msg);
+                }
+            }
         }
+        pending.lock().unwrap().take();
     }
 
-    /// Send CDP message on websocket
+    /// Subscribes to the messages that are not responses, mostly BiDi events.
+    /// Only messages received after subscribing are delivered.
+    pub fn events(&self) -> broadcast::Receiver<Value> {
+        self.events.subscribe()
+    }
+
+    /// Send CDP message on websocket and wait for the response with the same id.
     /// Works with serde_json::Value type as a result.
-    pub fn send(&mut self, command: &str, params: &str) -> Result<Value, GeckError> {
-        let message = format!(r#"{{"id": {}, "method":"{}", "params":{} }}"#, self.id, command, params);
-        let data = self.ws_client.send(&message)?.into_text()?;
-        let parsed_msg: Value = serde_json::from_str(&data).unwrap();
-        match parsed_msg["type"].as_str().unwrap() {
-            "error" => {
-                Err(GeckError::new(ErrorKind::Driver, Some("None"), &format!("Failed to parse successful message for {:?} \nError: {:?} \nStacktrace {:?}", data, parsed_msg["error"], parsed_msg["stacktrace"])))
+    pub async fn send(&self, command: &str, params: &str) -> Result<Value, GeckError> {
+        let id = self.id.fetch_add(1, Ordering::Relaxed);
+        let params: Value = serde_json::from_str(params)?;
+        let message = serde_json::json!({ "id": id, "method": command, "params": params });
+        let closed = || {
+            GeckError::new(
+                ErrorKind::Driver,
+                Some(tungstenite::Error::ConnectionClosed),
+                &format!("BiDi connection closed before the response of {}", command),
+            )
+        };
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .as_mut()
+            .ok_or_else(closed)?
+            .insert(id, sender);
+        let sent = self
+            .writer
+            .lock()
+            .await
+            .send(message.to_string().into())
+            .await;
+        if let Err(e) = sent {
+            if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+                pending.remove(&id);
+            }
+            return Err(e.into());
+        }
+        log::debug!("Message {} is sent successfully", id);
+        let parsed_msg = receiver.await.map_err(|_| closed())?;
+        match parsed_msg["type"].as_str().unwrap_or_default() {
+            "error" => match WebDriverError::from_value(&parsed_msg) {
+                Some(error) => Err(GeckError::webdriver(error)),
+                None => Err(GeckError::new(
+                    ErrorKind::Driver,
+                    None::<GeckError>,
+                    &format!("Failed to parse error message {:?}", parsed_msg),
+                )),
             },
-            &_ => {
-                self.id +=1;
-                Ok(parsed_msg)
+            &_ => Ok(parsed_msg),
+        }
+    }
+
+    /// Creates a BiDi user context, optionally routed through its own proxy. The proxy
+    /// credentials are not part of the command, see `WebDriver::create_user_context` to
+    /// answer the proxy challenges with them. Returns the user context id.
+    pub async fn create_user_context(&self, proxy: Option<&Proxy>) -> Result<String, GeckError> {
+        let params = match proxy {
+            Some(proxy) => {
+                proxy.validate()?;
+                serde_json::json!({ "proxy": proxy })
             }
+            None => serde_json::json!({}),
+        };
+        let resp = self
+            .send("browser.createUserContext", &params.to_string())
+            .await?;
+        resp["result"]["userContext"]
+            .as_str()
+            .map(|s| s.to_owned())
+            .ok_or_else(|| {
+                GeckError::new(
+                    ErrorKind::Driver,
+                    None::<GeckError>,
+                    &format!("No userContext in {:?}", resp),
+                )
+            })
+    }
+
+    /// User context of a browsing context, `default` for the default user context
+    pub async fn user_context_of(&self, context: &str) -> Result<String, GeckError> {
+        let params = serde_json::json!({ "root": context, "maxDepth": 0 });
+        let resp = self
+            .send("browsingContext.getTree", &params.to_string())
+            .await?;
+        resp["result"]["contexts"][0]["userContext"]
+            .as_str()
+            .map(|s| s.to_owned())
+            .ok_or_else(|| {
+                GeckError::new(
+                    ErrorKind::Driver,
+                    None::<GeckError>,
+                    &format!("No userContext in {:?}", resp),
+                )
+            })
+    }
+
+    /// Ids of the top level browsing contexts, the tabs and windows of every user context
+    pub async fn top_level_contexts(&self) -> Result<Vec<String>, GeckError> {
+        let resp = self
+            .send("browsingContext.getTree", r#"{"maxDepth": 0}"#)
+            .await?;
+        Ok(resp["result"]["contexts"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|context| context["context"].as_str().map(|s| s.to_owned()))
+            .collect())
+    }
+
+    /// Subscribes to the events, of the given top level contexts only when there are some.
+    /// Returns the subscription id, browsers before the subscription ids return none.
+    pub async fn subscribe(
+        &self,
+        events: &[&str],
+        contexts: &[String],
+    ) -> Result<Option<String>, GeckError> {
+        let mut params = serde_json::json!({ "events": events });
+        if !contexts.is_empty() {
+            params["contexts"] = contexts.into();
         }
+        let resp = self.send("session.subscribe", &params.to_string()).await?;
+        Ok(resp["result"]["subscription"]
+            .as_str()
+            .map(|s| s.to_owned()))
+    }
+
+    /// Ends a subscription returned by `subscribe`
+    pub async fn unsubscribe(&self, subscription: &str) -> Result<(), GeckError> {
+        let params = serde_json::json!({ "subscriptions": [subscription] });
+        self.send("session.unsubscribe", &params.to_string())
+            .await?;
+        Ok(())
+    }
+
+    /// Removes a user context with its tabs, cookies and storage
+    pub async fn remove_user_context(&self, user_context: &str) -> Result<(), GeckError> {
+        let params = serde_json::json!({ "userContext": user_context });
+        self.send("browser.removeUserContext", &params.to_string())
+            .await?;
+        Ok(())
+    }
+
+    /// Opens a new tab, in the default user context when none is given.
+    /// Returns the browsing context id of the tab.
+    pub async fn create_tab(&self, user_context: Option<&str>) -> Result<String, GeckError> {
+        let mut params = serde_json::json!({ "type": "tab" });
+        if let Some(user_context) = user_context {
+            params["userContext"] = user_context.into();
+        }
+        let resp = self
+            .send("browsingContext.create", &params.to_string())
+            .await?;
+        resp["result"]["context"]
+            .as_str()
+            .map(|s| s.to_owned())
+            .ok_or_else(|| {
+                GeckError::new(
+                    ErrorKind::Driver,
+                    None::<GeckError>,
+                    &format!("No context in {:?}", resp),
+                )
+            })
+    }
+
+    /// Navigates the browsing context and waits for the readiness state
+    pub async fn navigate(
+        &self,
+        context: &str,
+        url: &str,
+        wait: ReadinessState,
+    ) -> Result<Navigation, GeckError> {
+        let params = serde_json::json!({ "context": context, "url": url, "wait": wait });
+        let resp = self
+            .send("browsingContext.navigate", &params.to_string())
+            .await?;
+        Ok(serde_json::from_value(resp["result"].clone())?)
+    }
+
+    /// Reloads the browsing context and waits for the readiness state
+    pub async fn reload(
+        &self,
+        context: &str,
+        wait: ReadinessState,
+    ) -> Result<Navigation, GeckError> {
+        let params = serde_json::json!({ "context": context, "wait": wait });
+        let resp = self
+            .send("browsingContext.reload", &params.to_string())
+            .await?;
+        Ok(serde_json::from_value(resp["result"].clone())?)
+    }
+
+    /// Calls the function in the target realm with the arguments, awaiting a returned promise
+    pub async fn call_function(
+        &self,
+        target: &Target,
+        function: &str,
+        args: &[LocalValue],
+    ) -> Result<RemoteValue, GeckError> {
+        let params = serde_json::json!({
+            "functionDeclaration": function,
+            "arguments": args,
+            "target": target,
+            "awaitPromise": true,
+        });
+        let resp = self
+            .send("script.callFunction", &params.to_string())
+            .await?;
+        script::evaluate_result(&resp["result"])
+    }
+
+    /// Evaluates the expression in the target realm, awaiting a returned promise
+    pub async fn evaluate(
+        &self,
+        target: &Target,
+        expression: &str,
+    ) -> Result<RemoteValue, GeckError> {
+        let params =
+            serde_json::json!({ "expression": expression, "target": target, "awaitPromise": true });
+        let resp = self.send("script.evaluate", &params.to_string()).await?;
+        script::evaluate_result(&resp["result"])
+    }
+
+    /// Realms of the browser, of one browsing context when given. Sandboxes show up once a
+    /// script ran in them.
+    pub async fn get_realms(&self, context: Option<&str>) -> Result<Vec<RealmInfo>, GeckError> {
+        let mut params = serde_json::json!({});
+        if let Some(context) = context {
+            params["context"] = context.into();
+        }
+        let resp = self.send("script.getRealms", &params.to_string()).await?;
+        Ok(serde_json::from_value(resp["result"]["realms"].clone())?)
+    }
+
+    /// Runs the function in every new document before the page scripts, with the arguments,
+    /// e.g. channels. Limited to the given top level contexts when some are given, to the
+    /// `sandbox` realm when named. Returns the preload script id.
+    pub async fn add_preload_script(
+        &self,
+        function: &str,
+        args: &[LocalValue],
+        contexts: Option<&[String]>,
+        sandbox: Option<&str>,
+    ) -> Result<String, GeckError> {
+        let mut params = serde_json::json!({ "functionDeclaration": function, "arguments": args });
+        if let Some(contexts) = contexts {
+            params["contexts"] = contexts.into();
+        }
+        if let Some(sandbox) = sandbox {
+            params["sandbox"] = sandbox.into();
+        }
+        let resp = self
+            .send("script.addPreloadScript", &params.to_string())
+            .await?;
+        resp["result"]["script"]
+            .as_str()
+            .map(|s| s.to_owned())
+            .ok_or_else(|| {
+                GeckError::new(
+                    ErrorKind::Driver,
+                    None::<GeckError>,
+                    &format!("No script in {:?}", resp),
+                )
+            })
+    }
+
+    pub async fn remove_preload_script(&self, script: &str) -> Result<(), GeckError> {
+        let params = serde_json::json!({ "script": script });
+        self.send("script.removePreloadScript", &params.to_string())
+            .await?;
+        Ok(())
+    }
+
+    /// Intercepts authentication challenges such as proxy auth, the `network.authRequired`
+    /// events must be answered with `continue_with_auth`. Returns the intercept id.
+    pub async fn add_auth_intercept(&self) -> Result<String, GeckError> {
+        let resp = self
+            .send("network.addIntercept", r#"{"phases": ["authRequired"]}"#)
+            .await?;
+        resp["result"]["intercept"]
+            .as_str()
+            .map(|s| s.to_owned())
+            .ok_or_else(|| {
+                GeckError::new(
+                    ErrorKind::Driver,
+                    None::<GeckError>,
+                    &format!("No intercept in {:?}", resp),
+                )
+            })
+    }
+
+    /// Removes a network intercept, the requests it already blocked stay blocked
+    pub async fn remove_intercept(&self, intercept: &str) -> Result<(), GeckError> {
+        let params = serde_json::json!({ "intercept": intercept });
+        self.send("network.removeIntercept", &params.to_string())
+            .await?;
+        Ok(())
+    }
+
+    /// Answers a `network.authRequired` event with the proxy credentials,
+    /// falls back to the browser default behaviour without credentials
+    pub async fn continue_with_auth(
+        &self,
+        request: &str,
+        auth: Option<&ProxyAuth>,
+    ) -> Result<(), GeckError> {
+        let params = match auth {
+            Some(auth) => serde_json::json!({
+                "request": request,
+                "action": "provideCredentials",
+                "credentials": {
+                    "type": "password",
+                    "username": auth.username,
+                    "password": auth.password,
+                },
+            }),
+            None => serde_json::json!({ "request": request, "action": "default" }),
+        };
+        self.send("network.continueWithAuth", &params.to_string())
+            .await?;
+        Ok(())
+    }
+}
+
+impl Drop for CDP {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message() {
+        let success: CDPMessage = serde_json::from_str(
+            r#"{"type": "success", "id": 3, "result": {"userContext": "abc"}, "extra": [1]}"#,
+        )
+        .unwrap();
+        match success {
+            CDPMessage::CDPResponseSuccess(msg) => {
+                assert_eq!(msg.id, 3);
+                assert_eq!(msg.result["userContext"], "abc");
+            }
+            _ => panic!("expected a success message"),
+        }
+        let failure: CDPMessage = serde_json::from_str(
+            r#"{"type": "error", "id": 4, "error": "no such frame", "message": "gone"}"#,
+        )
+        .unwrap();
+        match failure {
+            CDPMessage::CDPResponseFailure(msg) => {
+                assert_eq!(msg.error.as_deref(), Some("no such frame"));
+                assert_eq!(msg.stacktrace, None);
+            }
+            _ => panic!("expected a failure message"),
+        }
+        assert!(serde_json::from_str::<CDPMessage>("[1, 2]").is_err());
+    }
+
+    /// Answers the first two commands in reverse order after an event, then closes
+    async fn serve(listener: tokio::net::TcpListener) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        let mut ids = Vec::new();
+        while ids.len() < 2 {
+            let message = ws.next().await.unwrap().unwrap();
+            let command: Value = serde_json::from_str(message.to_text().unwrap()).unwrap();
+            ids.push((command["id"].clone(), command["params"]["context"].clone()));
+        }
+        let event = r#"{"type": "event", "method": "browsingContext.load", "params": {}}"#;
+        ws.send(event.into()).await.unwrap();
+        for (id, context) in ids.into_iter().rev() {
+            let response =
+                serde_json::json!({"type": "success", "id": id, "result": {"context": context}});
+            ws.send(response.to_string().into()).await.unwrap();
+        }
+        ws.close(None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_send() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve(listener));
+
+        let cdp = CDP::new(&url).await.unwrap();
+        let mut events = cdp.events();
+        let (first, second) = tokio::join!(
+            cdp.send("browsingContext.reload", r#"{"context": "first"}"#),
+            cdp.send("browsingContext.reload", r#"{"context": "second"}"#),
+        );
+        assert_eq!(first.unwrap()["result"]["context"], "first");
+        assert_eq!(second.unwrap()["result"]["context"], "second");
+        assert_eq!(
+            events.recv().await.unwrap()["method"],
+            "browsingContext.load"
+        );
+
+        server.await.unwrap();
+        assert!(cdp.send("browsingContext.reload", "{}").await.is_err());
     }
 }
@@ -0,0 +1,333 @@
+/*
+Driver configuration loaded from TOML or JSON files and UNGECKIT_* environment variables
+ */
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use crate::options::prefs::{PrefPreset, PrefValue};
+use crate::options::DriverOptions;
+use crate::schemas::proxy::Proxy;
+use crate::utils::error::{ErrorKind, GeckError};
+use crate::utils::logging::LogLevel;
+use crate::DriverBuilder;
+
+/// Prefix of the environment variables overriding the configuration
+pub const ENV_PREFIX: &str = "UNGECKIT_";
+/// Environment variable pointing to the configuration file
+pub const ENV_CONFIG: &str = "UNGECKIT_CONFIG";
+
+fn config_error(msg: &str) -> GeckError {
+    GeckError::new(ErrorKind::Config, None::<GeckError>, msg)
+}
+
+/// Every driver knob that can be tuned without recompiling.
+///
+/// A TOML file looks like:
+/// ```toml
+/// port = 4445
+/// headless = true
+/// log_level = "debug"
+/// window_size = [1280, 800]
+/// args = ["-private"]
+/// presets = ["privacy", "stealth"]
+/// proxy = "socks5://127.0.0.1:1080"
+///
+/// [prefs]
+/// "dom.ipc.processCount" = 8
+/// ```
+/// Environment variables override the file: `UNGECKIT_GECKODRIVER`, `UNGECKIT_PORT`, `UNGECKIT_BINARY`,
+/// `UNGECKIT_HEADLESS`, `UNGECKIT_LOG_LEVEL`, `UNGECKIT_WINDOW_SIZE` (`1280x800`), `UNGECKIT_ARGS`
+/// (space separated), `UNGECKIT_PRESETS` (comma separated), `UNGECKIT_PROXY` and `UNGECKIT_PREFS`
+/// (comma separated `name=value`).
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DriverConfig {
+    pub geckodriver: Option<String>,
+    pub port: Option<u16>,
+    pub binary: Option<String>,
+    pub headless: Option<bool>,
+    pub log_level: Option<String>,
+    pub window_size: Option<(u32, u32)>,
+    pub args: Vec<String>,
+    pub presets: Vec<String>,
+    pub prefs: BTreeMap<String, serde_json::Value>,
+    pub proxy: Option<String>,
+}
+
+impl DriverConfig {
+    pub fn from_toml_str(s: &str) -> Result<Self, GeckError> {
+        toml::from_str(s)
+            .map_err(|e| GeckError::new(ErrorKind::Config, Some(e), "Cannot parse TOML config"))
+    }
+
+    pub fn from_json_str(s: &str) -> Result<Self, GeckError> {
+        serde_json::from_str(s)
+            .map_err(|e| GeckError::new(ErrorKind::Config, Some(e), "Cannot parse JSON config"))
+    }
+
+    /// Reads a `.toml` or `.json` file
+    pub fn from_file(path: &str) -> Result<Self, GeckError> {
+        let content = fs::read_to_string(path).map_err(|e| {
+            GeckError::new(
+                ErrorKind::Config,
+                Some(e),
+                &format!("Cannot read config {}", path),
+            )
+        })?;
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml_str(&content),
+            Some("json") => Self::from_json_str(&content),
+            _ => Err(config_error(&format!(
+                "Config {} must be a .toml or .json file",
+                path
+            ))),
+        }
+    }
+
+    /// Loads the given file, or the one in `UNGECKIT_CONFIG`, then applies the environment overrides
+    pub fn load(path: Option<&str>) -> Result<Self, GeckError> {
+        let path = path
+            .map(|p| p.to_owned())
+            .or_else(|| env::var(ENV_CONFIG).ok());
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+        config.apply_env(env::vars())?;
+        Ok(config)
+    }
+
+    /// Overrides the configuration with `UNGECKIT_*` variables
+    pub fn apply_env<I>(&mut self, vars: I) -> Result<(), GeckError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        for (key, value) in vars {
+            let Some(name) = key.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let invalid = || config_error(&format!("Invalid value {:?} for {}", value, key));
+            match name {
+                "GECKODRIVER" => self.geckodriver = Some(value.clone()),
+                "PORT" => self.port = Some(value.parse().map_err(|_| invalid())?),
+                "BINARY" => self.binary = Some(value.clone()),
+                "HEADLESS" => {
+                    self.headless = Some(match value.to_ascii_lowercase().as_str() {
+                        "1" | "true" | "yes" => true,
+                        "0" | "false" | "no" => false,
+                        _ => return Err(invalid()),
+                    })
+                }
+                "LOG_LEVEL" => self.log_level = Some(value.clone()),
+                "WINDOW_SIZE" => {
+                    let (width, height) = value.split_once('x').ok_or_else(invalid)?;
+                    self.window_size = Some((
+                        width.trim().parse().map_err(|_| invalid())?,
+                        height.trim().parse().map_err(|_| invalid())?,
+                    ))
+                }
+                "ARGS" => self.args = value.split_whitespace().map(|s| s.to_owned()).collect(),
+                "PRESETS" => {
+                    self.presets = value
+                        .split(',')
+                        .filter(|s| !s.trim().is_empty())
+                        .map(|s| s.trim().to_owned())
+                        .collect()
+                }
+                "PROXY" => self.proxy = Some(value.clone()),
+                "PREFS" => {
+                    for pref in value.split(',').filter(|s| !s.trim().is_empty()) {
+                        let (name, raw) = pref.split_once('=').ok_or_else(invalid)?;
+                        self.prefs
+                            .insert(name.trim().to_owned(), Self::infer_pref(raw.trim()));
+                    }
+                }
+                "CONFIG" => (),
+                _ => log::warn!("Ignoring unknown configuration variable {}", key),
+            }
+        }
+        Ok(())
+    }
+
+    /// `true`/`false` become booleans, integers become numbers, anything else stays a string
+    fn infer_pref(raw: &str) -> serde_json::Value {
+        match raw {
+            "true" => serde_json::Value::Bool(true),
+            "false" => serde_json::Value::Bool(false),
+            _ => raw
+                .parse::<i32>()
+                .map(serde_json::Value::from)
+                .unwrap_or_else(|_| serde_json::Value::String(raw.to_owned())),
+        }
+    }
+
+    fn pref_value(name: &str, value: &serde_json::Value) -> Result<PrefValue, GeckError> {
+        match value {
+            serde_json::Value::Bool(v) => Ok(PrefValue::Bool(*v)),
+            serde_json::Value::String(v) => Ok(PrefValue::String(v.clone())),
+            serde_json::Value::Number(v) => v
+                .as_i64()
+                .and_then(|v| i32::try_from(v).ok())
+                .map(PrefValue::Int)
+                .ok_or_else(|| config_error(&format!("Pref {} must be a 32 bit integer", name))),
+            _ => Err(config_error(&format!(
+                "Pref {} must be a boolean, an integer or a string",
+                name
+            ))),
+        }
+    }
+
+    /// Feeds the configuration into a builder, the builder keeps everything not configured
+    pub fn apply(&self, builder: &mut DriverBuilder) -> Result<(), GeckError> {
+        if let Some(path) = &self.geckodriver {
+            builder.geckodriver(path)?;
+        }
+        if let Some(port) = self.port {
+            builder.port(port)?;
+        }
+        if let Some(headless) = self.headless {
+            builder.headless(headless)?;
+        }
+        if let Some(level) = &self.log_level {
+            let level = LogLevel::parse(&level.to_ascii_uppercase())
+                .ok_or_else(|| config_error(&format!("Unknown log level {:?}", level)))?;
+            builder.log_level(level)?;
+        }
+        if let Some((width, height)) = self.window_size {
+            builder.window_size(width, height)?;
+        }
+        for preset in &self.presets {
+            builder.preset(preset.parse::<PrefPreset>()?)?;
+        }
+        if let Some(proxy) = &self.proxy {
+            builder.proxy(Proxy::from_url(proxy)?)?;
+        }
+
+        let mut options = builder.options.take().unwrap_or_default();
+        let mut configured = DriverOptions::new();
+        if let Some(binary) = &self.binary {
+            configured.binary(binary);
+        }
+        for arg in &self.args {
+            configured.arg(arg);
+        }
+        for (name, value) in &self.prefs {
+            configured.set_pref(name, Self::pref_value(name, value)?);
+        }
+        options.extend(configured);
+        builder.options(options)?;
+        Ok(())
+    }
+
+    /// A builder configured from the given file, or `UNGECKIT_CONFIG`, and the environment
+    pub fn builder(path: Option<&str>) -> Result<DriverBuilder, GeckError> {
+        let mut builder = DriverBuilder::new(None);
+        Self::load(path)?.apply(&mut builder)?;
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+port = 4445
+binary = "/usr/bin/firefox-esr"
+headless = false
+log_level = "debug"
+window_size = [1280, 800]
+args = ["-private"]
+presets = ["privacy"]
+proxy = "socks5://127.0.0.1:1080"
+
+[prefs]
+"dom.ipc.processCount" = 8
+"intl.accept_languages" = "de-DE"
+"#;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_toml_and_json() {
+        let config = DriverConfig::from_toml_str(TOML).unwrap();
+        assert_eq!(config.port, Some(4445));
+        assert_eq!(config.window_size, Some((1280, 800)));
+        assert_eq!(config.prefs["dom.ipc.processCount"], 8);
+
+        let json = r#"{"port": 4445, "binary": "/usr/bin/firefox-esr", "headless": false,
+            "log_level": "debug", "window_size": [1280, 800], "args": ["-private"],
+            "presets": ["privacy"], "proxy": "socks5://127.0.0.1:1080",
+            "prefs": {"dom.ipc.processCount": 8, "intl.accept_languages": "de-DE"}}"#;
+        assert_eq!(DriverConfig::from_json_str(json).unwrap(), config);
+        assert!(DriverConfig::from_toml_str("unknown = 1").is_err());
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let mut config = DriverConfig::from_toml_str(TOML).unwrap();
+        config
+            .apply_env(vars(&[
+                ("UNGECKIT_PORT", "5555"),
+                ("UNGECKIT_HEADLESS", "true"),
+                ("UNGECKIT_WINDOW_SIZE", "800x600"),
+                (
+                    "UNGECKIT_PREFS",
+                    "media.autoplay.default=5,browser.startup.homepage=about:blank",
+                ),
+                ("UNGECKIT_PRESETS", "stealth, performance"),
+                ("PATH", "/usr/bin"),
+            ]))
+            .unwrap();
+        assert_eq!(config.port, Some(5555));
+        assert_eq!(config.headless, Some(true));
+        assert_eq!(config.window_size, Some((800, 600)));
+        assert_eq!(config.presets, vec!["stealth", "performance"]);
+        assert_eq!(config.prefs["media.autoplay.default"], 5);
+        assert_eq!(config.prefs["browser.startup.homepage"], "about:blank");
+        assert_eq!(config.prefs["dom.ipc.processCount"], 8);
+
+        assert!(config
+            .apply_env(vars(&[("UNGECKIT_PORT", "http")]))
+            .is_err());
+        assert!(config
+            .apply_env(vars(&[("UNGECKIT_HEADLESS", "maybe")]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let config = DriverConfig::from_toml_str(TOML).unwrap();
+        let mut builder = DriverBuilder::new(None);
+        config.apply(&mut builder).unwrap();
+        assert_eq!(builder.service.port, 4445);
+
+        let capabilities: serde_json::Value =
+            serde_json::from_str(&builder.serialized_capabilities().unwrap()).unwrap();
+        let always_match = &capabilities["capabilities"]["alwaysMatch"];
+        let options = &always_match["moz:firefoxOptions"];
+        assert_eq!(options["binary"], "/usr/bin/firefox-esr");
+        assert_eq!(
+            options["args"],
+            serde_json::json!(["-private", "--width=1280", "--height=800"])
+        );
+        assert_eq!(options["log"]["level"], "debug");
+        assert_eq!(options["prefs"]["dom.ipc.processCount"], 8);
+        assert_eq!(options["prefs"]["toolkit.telemetry.enabled"], false);
+        assert_eq!(always_match["proxy"]["socksProxy"], "127.0.0.1:1080");
+
+        let config = DriverConfig {
+            log_level: Some("loud".to_owned()),
+            ..Default::default()
+        };
+        assert!(config.apply(&mut DriverBuilder::new(None)).is_err());
+    }
+}
@@ -0,0 +1,502 @@
+/*
+Crawl runner over a browser pool: every url is opened in a leased tab, handed to the
+page handler and its outcome recorded, failed pages are retried with backoff
+ */
+use futures::future::BoxFuture;
+use futures::{Stream, StreamExt};
+use log::*;
+use reqwest::Url;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::browser_pool::BrowserPool;
+use crate::rate_limit::{RateLimiter, RateLimits};
+use crate::schemas::navigation::ReadinessState;
+use crate::tab::Tab;
+use crate::utils::error::{ErrorKind, GeckError};
+
+/// A loaded page handed to the handler
+pub struct Page {
+    pub url: String,
+    /// Page source once the page completed loading
+    pub source: String,
+    /// 1 on the first attempt
+    pub attempt: u32,
+    /// The leased tab the page is open in, returned to the pool after the handler
+    pub tab: Tab,
+}
+
+/// Outcome of a url once it succeeded or ran out of retries
+#[derive(Debug)]
+pub struct CrawlResult<T> {
+    pub url: String,
+    /// 0 when the url was rejected before loading it, e.g. by robots.txt
+    pub attempts: u32,
+    pub outcome: Result<T, GeckError>,
+}
+
+/// Receives the crawl results
+pub trait ResultSink<T>: Send + Sync {
+    fn record(&self, result: CrawlResult<T>);
+}
+
+/// Logs the failed urls and drops the results, the default sink
+pub struct LogResults;
+
+impl<T> ResultSink<T> for LogResults {
+    fn record(&self, result: CrawlResult<T>) {
+        if let Err(e) = &result.outcome {
+            warn!(
+                "Crawling {} failed after {} attempts: {}",
+                result.url, result.attempts, e
+            );
+        }
+    }
+}
+
+/// Keeps the results in memory, clones share the results
+pub struct MemoryResults<T> {
+    results: Arc<Mutex<Vec<CrawlResult<T>>>>,
+}
+
+impl<T> MemoryResults<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the results recorded so far
+    pub fn take(&self) -> Vec<CrawlResult<T>> {
+        std::mem::take(&mut *self.results.lock().unwrap())
+    }
+}
+
+impl<T> Default for MemoryResults<T> {
+    fn default() -> Self {
+        Self {
+            results: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl<T> Clone for MemoryResults<T> {
+    fn clone(&self) -> Self {
+        Self {
+            results: self.results.clone(),
+        }
+    }
+}
+
+impl<T: Send> ResultSink<T> for MemoryResults<T> {
+    fn record(&self, result: CrawlResult<T>) {
+        self.results.lock().unwrap().push(result)
+    }
+}
+
+/// Streams the results, e.g. to a task storing them
+impl<T: Send> ResultSink<T> for mpsc::UnboundedSender<CrawlResult<T>> {
+    fn record(&self, result: CrawlResult<T>) {
+        // The receiver may be gone, the crawl goes on
+        let _ = self.send(result);
+    }
+}
+
+/// How failed pages are retried, the delay doubles after each failure
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retrying the given failed attempt, 1 being the first attempt
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// Totals of a crawl run
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CrawlStats {
+    pub pages: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+    pub retries: u64,
+}
+
+type Handler<T> = Arc<dyn Fn(Page) -> BoxFuture<'static, Result<T, GeckError>> + Send + Sync>;
+type RetryOn = Arc<dyn Fn(&GeckError) -> bool + Send + Sync>;
+
+/// Crawls urls with the tabs of a browser pool
+pub struct Crawler<T> {
+    pool: BrowserPool,
+    handler: Handler<T>,
+    sink: Arc<dyn ResultSink<T>>,
+    concurrency: usize,
+    domain: RateLimits,
+    limiter: RateLimiter,
+    /// Key of the next page in the rate limiter
+    jobs: AtomicU64,
+    retry: RetryPolicy,
+    retry_on: RetryOn,
+}
+
+impl<T: Send + 'static> Crawler<T> {
+    /// The handler is called with every loaded page, runs as many pages at once as the pool
+    /// has lease slots
+    pub fn new<F, Fut>(pool: BrowserPool, handler: F) -> Self
+    where
+        F: Fn(Page) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<T, GeckError>> + Send + 'static,
+    {
+        let concurrency = pool.config().browsers * pool.config().contexts;
+        Self {
+            pool,
+            handler: Arc::new(move |page| Box::pin(handler(page))),
+            sink: Arc::new(LogResults),
+            concurrency,
+            domain: RateLimits::default(),
+            limiter: RateLimiter::new(RateLimits::default()),
+            jobs: AtomicU64::new(0),
+            retry: RetryPolicy::default(),
+            retry_on: Arc::new(Self::retryable),
+        }
+    }
+
+    /// Errors retried by default: WebDriver errors worth retrying and lost connections
+    pub fn retryable(error: &GeckError) -> bool {
+        error.is_retryable() || error.is_disconnected()
+    }
+
+    pub fn sink(&mut self, sink: Arc<dyn ResultSink<T>>) -> &mut Self {
+        self.sink = sink;
+        self
+    }
+
+    /// Pages crawled at the same time
+    pub fn concurrency(&mut self, concurrency: usize) -> &mut Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Pages of a host crawled at the same time
+    pub fn domain_concurrency(&mut self, max_active: usize) -> &mut Self {
+        self.domain.max_tabs = Some(max_active.max(1));
+        self.limiter.defaults(self.domain.clone());
+        self
+    }
+
+    /// Minimum delay between two page loads of a host
+    pub fn domain_delay(&mut self, delay: Duration) -> &mut Self {
+        self.domain.requests_per_second =
+            Some(1.0 / delay.as_secs_f64()).filter(|rps| rps.is_finite());
+        self.limiter.defaults(self.domain.clone());
+        self
+    }
+
+    /// Paces the pages by host with the limiter instead, e.g. one with per host limits and
+    /// robots.txt rules. Sharing it with the drivers of the pool paces the pages twice.
+    pub fn rate_limiter(&mut self, limiter: RateLimiter) -> &mut Self {
+        self.limiter = limiter;
+        self
+    }
+
+    pub fn retry(&mut self, retry: RetryPolicy) -> &mut Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Decides which errors are retried, see `retryable`
+    pub fn retry_on<F>(&mut self, retry_on: F) -> &mut Self
+    where
+        F: Fn(&GeckError) -> bool + Send + Sync + 'static,
+    {
+        self.retry_on = Arc::new(retry_on);
+        self
+    }
+
+    /// Crawls every url of the source, returns once all of them are recorded
+    pub async fn run<S>(&self, urls: S) -> CrawlStats
+    where
+        S: Stream<Item = String>,
+    {
+        let stats = Mutex::new(CrawlStats::default());
+        urls.for_each_concurrent(self.concurrency, |url| async {
+            let result = self.crawl(url, &stats).await;
+            {
+                let mut stats = stats.lock().unwrap();
+                stats.pages += 1;
+                match result.outcome {
+                    Ok(_) => stats.succeeded += 1,
+                    Err(_) => stats.failed += 1,
+                }
+            }
+            self.sink.record(result);
+        })
+        .await;
+        stats.into_inner().unwrap()
+    }
+
+    async fn crawl(&self, url: String, stats: &Mutex<CrawlStats>) -> CrawlResult<T> {
+        if Url::parse(&url)
+            .ok()
+            .and_then(|u| u.host_str().map(|h| h.to_owned()))
+            .is_none()
+        {
+            return CrawlResult {
+                outcome: Err(GeckError::new(
+                    ErrorKind::Other,
+                    None::<GeckError>,
+                    &format!("Cannot crawl {:?}, it has no host", url),
+                )),
+                url,
+                attempts: 0,
+            };
+        }
+        let key = format!("crawl-{}", self.jobs.fetch_add(1, Ordering::Relaxed));
+        let mut attempt = 0;
+        loop {
+            if let Err(e) = self.limiter.enter(&key, &url).await {
+                return CrawlResult {
+                    url,
+                    attempts: attempt,
+                    outcome: Err(e),
+                };
+            }
+            attempt += 1;
+            let outcome = self.attempt(&url, attempt).await;
+            self.limiter.leave(&key);
+            match outcome {
+                Err(e) if attempt <= self.retry.max_retries && (self.retry_on)(&e) => {
+                    let delay = self.retry.delay(attempt);
+                    warn!("Crawling {} failed, retrying in {:?}: {}", url, delay, e);
+                    stats.lock().unwrap().retries += 1;
+                    tokio::time::sleep(delay).await;
+                }
+                outcome => {
+                    return CrawlResult {
+                        url,
+                        attempts: attempt,
+                        outcome,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Loads the url over BiDi, so the leases of a browser load their pages concurrently
+    async fn load(tab: &Tab, url: &str) -> Result<String, GeckError> {
+        tab.navigate(url, ReadinessState::Complete).await?;
+        tab.driver().page_source_in(tab.context()).await
+    }
+
+    async fn attempt(&self, url: &str, attempt: u32) -> Result<T, GeckError> {
+        let lease = self.pool.acquire().await?;
+        let tab = lease.tab();
+        let outcome = match Self::load(&tab, url).await {
+            Ok(source) => {
+                (self.handler)(Page {
+                    url: url.to_owned(),
+                    source,
+                    attempt,
+                    tab,
+                })
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        lease.release().await;
+        outcome
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::browser_pool::PoolConfig;
+    use crate::rate_limit::Robots;
+    use crate::utils::error::WebDriverError;
+    use crate::utils::testing::StandIn;
+    use std::collections::HashMap;
+    use std::sync::atomic::AtomicUsize;
+    use tokio::time::Instant;
+
+    #[test]
+    fn test_backoff() {
+        let retry = RetryPolicy {
+            max_retries: 5,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(350),
+        };
+        let delays: Vec<u128> = (1..=4)
+            .map(|attempt| retry.delay(attempt).as_millis())
+            .collect();
+        assert_eq!(delays, vec![100, 200, 350, 350]);
+    }
+
+    #[tokio::test]
+    async fn test_retryable() {
+        let pool = BrowserPool::with_launcher(
+            StandIn::start("crawler-retryable").await.launcher(),
+            PoolConfig::default(),
+        );
+        pool.close().await;
+        let closed = pool.acquire().await.err().unwrap();
+        assert_eq!(closed.kind(), ErrorKind::Pool);
+        assert!(!Crawler::<()>::retryable(&closed));
+
+        let timeout =
+            serde_json::json!({"type": "error", "id": 1, "error": "timeout", "message": "x"});
+        let timeout = GeckError::webdriver(WebDriverError::from_value(&timeout).unwrap());
+        assert!(Crawler::<()>::retryable(&timeout));
+        let invalid = serde_json::json!({"type": "error", "id": 1, "error": "invalid argument", "message": "x"});
+        let invalid = GeckError::webdriver(WebDriverError::from_value(&invalid).unwrap());
+        assert!(!Crawler::<()>::retryable(&invalid));
+        let json = GeckError::from(serde_json::from_str::<u32>("x").unwrap_err());
+        assert!(!Crawler::<()>::retryable(&json));
+        let lost = GeckError::from(reqwest::get("http://127.0.0.1:1/").await.unwrap_err());
+        assert!(Crawler::<()>::retryable(&lost));
+    }
+
+    #[tokio::test]
+    async fn test_crawl() {
+        let browser = StandIn::start("crawler").await;
+        browser
+            .browser()
+            .timeouts
+            .insert("https://flaky.test/".to_owned(), 1);
+        let pool = BrowserPool::with_launcher(
+            browser.launcher(),
+            PoolConfig {
+                browsers: 2,
+                contexts: 2,
+                max_uses: None,
+                acquire_timeout: None,
+            },
+        );
+
+        let active = Arc::new(AtomicUsize::new(0));
+        let most_active = Arc::new(AtomicUsize::new(0));
+        let results = MemoryResults::new();
+        let mut crawler = Crawler::new(pool, {
+            let (active, most_active) = (active.clone(), most_active.clone());
+            move |page: Page| {
+                let (active, most_active) = (active.clone(), most_active.clone());
+                async move {
+                    if page.url.starts_with("https://a.test/") {
+                        let now = active.fetch_add(1, Ordering::SeqCst) + 1;
+                        most_active.fetch_max(now, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        active.fetch_sub(1, Ordering::SeqCst);
+                    }
+                    if page.url == "https://fail.test/" {
+                        return Err(GeckError::new(
+                            ErrorKind::Other,
+                            None::<GeckError>,
+                            "No data",
+                        ));
+                    }
+                    Ok((page.source, page.attempt))
+                }
+            }
+        });
+        crawler
+            .sink(Arc::new(results.clone()))
+            .domain_concurrency(1)
+            .domain_delay(Duration::from_millis(20))
+            .retry(RetryPolicy {
+                max_retries: 2,
+                backoff: Duration::from_millis(10),
+                max_backoff: Duration::from_millis(50),
+            });
+
+        let urls = vec![
+            "https://a.test/1",
+            "https://a.test/2",
+            "https://a.test/3",
+            "https://b.test/",
+            "https://flaky.test/",
+            "https://fail.test/",
+            "not a url",
+        ];
+        let start = Instant::now();
+        let stats = crawler
+            .run(futures::stream::iter(
+                urls.into_iter().map(|url| url.to_owned()),
+            ))
+            .await;
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert_eq!(
+            stats,
+            CrawlStats {
+                pages: 7,
+                succeeded: 5,
+                failed: 2,
+                retries: 1
+            }
+        );
+        assert_eq!(most_active.load(Ordering::SeqCst), 1);
+        // Pages are loaded over BiDi, no classic command switched windows
+        assert!(browser.browser().current.is_none());
+
+        let results: HashMap<String, CrawlResult<(String, u32)>> = results
+            .take()
+            .into_iter()
+            .map(|r| (r.url.clone(), r))
+            .collect();
+        assert_eq!(
+            results["https://b.test/"].outcome.as_ref().unwrap().0,
+            "<html>https://b.test/</html>"
+        );
+        let flaky = &results["https://flaky.test/"];
+        assert_eq!((flaky.attempts, flaky.outcome.as_ref().unwrap().1), (2, 2));
+        assert_eq!(results["https://fail.test/"].attempts, 1);
+        assert_eq!(results["not a url"].attempts, 0);
+    }
+
+    #[tokio::test]
+    async fn test_robots_refusal() {
+        let browser = StandIn::start("crawler-robots").await;
+        let pool = BrowserPool::with_launcher(browser.launcher(), PoolConfig::default());
+        let mut limiter = RateLimiter::new(RateLimits::default());
+        limiter.robots(
+            "c.test",
+            Robots::parse("User-agent: *\nDisallow: /private\n", "ungeckit"),
+        );
+        let results = MemoryResults::new();
+        let mut crawler = Crawler::new(pool, |page: Page| async move { Ok(page.source) });
+        crawler
+            .sink(Arc::new(results.clone()))
+            .rate_limiter(limiter);
+
+        let urls = ["https://c.test/private/1", "https://c.test/open"];
+        let stats = crawler
+            .run(futures::stream::iter(
+                urls.into_iter().map(|url| url.to_owned()),
+            ))
+            .await;
+        assert_eq!((stats.succeeded, stats.failed, stats.retries), (1, 1, 0));
+        let refused = results
+            .take()
+            .into_iter()
+            .find(|r| r.url == "https://c.test/private/1")
+            .unwrap();
+        assert_eq!(refused.attempts, 0);
+        assert_eq!(refused.outcome.unwrap_err().kind(), ErrorKind::Politeness);
+        assert_eq!(browser.browser().navigations, vec!["https://c.test/open"]);
+    }
+}
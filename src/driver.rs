@@ -1,234 +1,264 @@
-use base64::prelude::*;
-use core::{panic, str};
-use log::info;
-use serde::de::{self};
-use std::convert::From;
-use std::fs::{self, OpenOptions};
-use std::path::Path;
-use std::io::Write;
-use std::sync::{Arc, Mutex};
-
-use crate::options::{capabilities, Capabilities, DriverOptions};
-use crate::schemas::session::{self, *};
-use crate::{cdp::*, service::*};
-use crate::utils::error::GeckError;
-use crate::utils::*;
+/*
+Blocking facade over `driver_async::WebDriver`, the facade owns the runtime driving it
+ */
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::{Handle, Runtime};
 
+use crate::driver_async;
+use crate::rate_limit::RateLimiter;
+use crate::schemas::navigation::{Navigation, ReadinessState};
+use crate::schemas::proxy::{Proxy, ProxyAuth};
+use crate::schemas::script::RealmInfo;
+use crate::service::*;
+use crate::utils::error::{ErrorKind, GeckError};
+
+/// Generates blocking methods running the async method of the same name on the runtime
+macro_rules! blocking {
+    ($($(#[$meta:meta])* pub fn $name:ident(&self $(, $arg:ident: $ty:ty)*) -> $ret:ty;)*) => {
+        $(
+            $(#[$meta])*
+            pub fn $name(&self $(, $arg: $ty)*) -> $ret {
+                block_on(self.runtime.get(), self.inner.$name($($arg),*))
+            }
+        )*
+    };
+}
+
+/// Runs a future to completion on the runtime. From within another runtime the future
+/// runs on a scoped thread, `Runtime::block_on` panics when called from async code.
+fn block_on<F>(runtime: &Runtime, future: F) -> F::Output
+where
+    F: Future + Send,
+    F::Output: Send,
+{
+    match Handle::try_current() {
+        Ok(_) => std::thread::scope(|scope| {
+            scope
+                .spawn(|| runtime.block_on(future))
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        }),
+        Err(_) => runtime.block_on(future),
+    }
+}
+
+/// The runtime shared by a driver and its clones, shut down with the last of them
+struct OwnedRuntime(Option<Runtime>);
+
+impl OwnedRuntime {
+    fn get(&self) -> &Runtime {
+        match &self.0 {
+            Some(runtime) => runtime,
+            None => unreachable!("the runtime lives until the driver is dropped"),
+        }
+    }
+}
+
+impl Drop for OwnedRuntime {
+    fn drop(&mut self) {
+        // Dropping a runtime blocks, which panics in async code. Tasks are dropped
+        // without waiting, geckodriver is killed when its task is dropped.
+        if let Some(runtime) = self.0.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// A blocking Firefox driver. Clones share the browser and can be used from many threads,
+/// see `driver_async::WebDriver`.
+#[derive(Clone)]
 pub struct WebDriver {
-    pub service: Service,
-    pub open_page: Option<String>,
-    pub context: Arc<Mutex<Context>>,
-    pub cdp: Option<CDP>,
-    pub session: Option<Session>,
-    pub capabilities: String,
-    pub driver_url: String,
-    pub http_client: reqwest::Client,
-    pub firefox: webdriver_commands::WebDriver<'static>,
+    inner: driver_async::WebDriver,
+    runtime: Arc<OwnedRuntime>,
 }
+
 impl WebDriver {
     pub fn new(
         remote_url: Option<String>,
         capabilities: String,
         http_client: reqwest::Client,
-    ) -> Self {
-        let context = Context::new();
-        let mut service = Service::new(&context, &String::from("geckodriver"));
-
-        service
-            .start(Vec::from([
-                webdriver_commands::Driver::ARGS_PORT,
-                webdriver_commands::Driver::PORT,
-                webdriver_commands::Driver::ARGS_VERBOSITY,
-            ]))
-            .expect("Failed to execute driver");
-
-        let mut driver_url =
-            webdriver_commands::Driver::HOST.to_owned() + ":" + webdriver_commands::Driver::PORT;
-        if let Some(url) = remote_url {
-            driver_url = url;
-        }
-        while !service.session_is_up().unwrap() {
-            info!("Session is booting up, give it more seconds...");
-        }
-
-        let mut cmds = webdriver_commands::WebDriver::new();
-        cmds.insert("GET_CONTEXT", "GET", "/session/{{sessionId}}/moz/context");
-        cmds.insert("SET_CONTEXT", "POST", "/session/{{sessionId}}/moz/context");
-        cmds.insert(
-            "INSTALL_ADDON",
-            "POST",
-            "/session/{{sessionId}}/moz/addon/install",
-        );
-        cmds.insert(
-            "UNINSTALL_ADDON",
-            "POST",
-            "/session/{{sessionId}}/moz/addon/uninstall",
-        );
-        cmds.insert(
-            "FULL_PAGE_SCREENSHOT",
-            "GET",
-            "/session/{{sessionId}}/moz/screenshot/full",
-        );
-
-        Self {
-            service: service,
-            open_page: None,
-            context: context,
-            cdp: None,
-            session: None,
-            capabilities: capabilities,
-            driver_url: driver_url,
-            http_client: http_client,
-            firefox: cmds,
-        }
+        config: ServiceConfig,
+    ) -> Result<Self, GeckError> {
+        // A worker thread keeps reading geckodriver logs between blocking calls
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .map_err(|e| GeckError::new(ErrorKind::Context, Some(e), "Cannot create a runtime"))?;
+        let inner = block_on(
+            &runtime,
+            driver_async::WebDriver::new(remote_url, capabilities, http_client, config),
+        )?;
+        Ok(Self {
+            inner,
+            runtime: Arc::new(OwnedRuntime(Some(runtime))),
+        })
     }
 
-    /// Generates a session per driver, we maintain a single session per driver at this point
-    pub fn new_session(&mut self) -> Result<(), GeckError> {
-        info!("{:?}", self.capabilities);
-        let session = self
-            .command::<SessionResponse>("NEW_SESSION", r#"{}"#, self.capabilities.clone())
-            .unwrap();
-        self.session = Some(session.value);
-
-        let ws_url = &self.session.as_ref().unwrap().capabilities.web_socket_url;
-        let mut cdp = CDP::new(self.context.clone(), &ws_url);
-
-        // TODO CAN BE BETTER DONE? 
-        // Everytime we create a session we pass some commands to websocket
-        // Credits to: https://github.com/ultrafunkamsterdam/undetected-chromedriver/blob/master/undetected_chromedriver/__init__.py
-        let mut resp = cdp.send("session.subscribe", r#"{"events":["browsingContext.domContentLoaded"]}"#).unwrap();
-        resp = cdp.send("browser.createUserContext", "{}").unwrap();
-        let mut userContext = &resp["result"]["userContext"].as_str().unwrap();
-        //cdp.send("Page.addScriptToEvaluateOnNewDocument", "").unwrap();
-        self.cdp = Some(cdp);
-
-        Ok(())
-    }
-
-    pub fn get(&mut self, url: &str) -> Result<String, GeckError> {
-        match &self.session {
-            Some(_) => (),
-            None => self.new_session().unwrap(),
-        };
-
-        self.command::<Response<String>>(
-            "GET",
-            &format!(
-                r#"{{"sessionId": "{}"}}"#,
-                self.session.as_ref().unwrap().session_id
-            ),
-            format!(r#"{{"url": "{}"}}"#, url),
-        )
-        .unwrap();
-        let page_source = self
-            .command::<Response<String>>(
-                "GET_PAGE_SOURCE",
-                &format!(
-                    r#"{{"sessionId": "{}"}}"#,
-                    self.session.as_ref().unwrap().session_id
-                ),
-                format!(r#"{{"url": "{}"}}"#, url),
-            )
-            .unwrap();
-        self.open_page = Some(url.to_owned());
-        Ok(page_source.value.unwrap())
-    }
-
-    /// Execute a W3C script
-    pub fn execute_script(&mut self, script: &str, args: &str) -> Result<String, GeckError>{
-        match &self.session {
-            Some(_) => (),
-            None => self.new_session().unwrap(),
-        };
-        let data = format!(r#"{{"script": "{script}", args: {args}}}"#);
-        let result = self
-            .command::<Response<String>>(
-                "W3C_EXECUTE_SCRIPT",
-                &format!(
-                    r#"{{"sessionId": "{}"}}"#,
-                    self.session.as_ref().unwrap().session_id
-                ),
-                data
-            )
-            .unwrap();
-        Ok(result.value.unwrap())
-    }
-
-    /// Save the screenshot of the webpage, uses moz capabilities full screenshot option.
-    /// Creates the file if not exists.
-    pub fn save_screenshot(&mut self, path: &str) -> Result<(), GeckError> {
-        match &self.session {
-            Some(_) => (),
-            None => self.new_session().unwrap(),
-        };
-
-        let screenshot = self
-            .command::<Response<String>>(
-                "FULL_PAGE_SCREENSHOT",
-                &format!(
-                    r#"{{"sessionId": "{}"}}"#,
-                    self.session.as_ref().unwrap().session_id
-                ),
-                "".to_owned(),
-            )
-            .unwrap()
-            .value
-            .unwrap();
-
-        let img_bytes = BASE64_STANDARD.decode(screenshot.as_bytes()).unwrap();
-        if Path::new(path).exists() {
-            let mut file = fs::OpenOptions::new().write(true).open(path).unwrap();
-            file.write_all(&img_bytes).unwrap();
-        } else {
-            let mut file = fs::File::create(path).unwrap();
-            file.write_all(&img_bytes).unwrap();
-        }
+    /// The async driver behind this facade
+    pub fn inner(&self) -> &driver_async::WebDriver {
+        &self.inner
+    }
 
-        Ok(())
-    }
-
-    /// A private implementation to communicate with geckodriver
-    ///
-    /// An example usage is as such:
-    /// let session = self
-    ///     .command::<SessionResponse>(
-    ///         "NEW_SESSION",
-    ///         r#"{}"#,
-    ///         r#"{"capabilities": {"alwaysMatch": {"webSocketUrl": true}}}"#.to_owned(),
-    ///     )
-    ///     .unwrap();
-    ///
-    /// This returns a Session object
-    /// TODO A better way to pass args
-    fn command<T>(&mut self, cmd: &str, args: &str, data: String) -> Result<T, GeckError>
+    /// Runs any future on the runtime of this driver, e.g. to use the async driver directly
+    pub fn block_on<F>(&self, future: F) -> F::Output
     where
-        T: de::DeserializeOwned,
+        F: Future + Send,
+        F::Output: Send,
     {
-        let client = self.http_client.clone();
-        let url = self.driver_url.clone();
-        let firefox = &self.firefox;
-        let cmd = firefox.command_dict.get(cmd).unwrap();
-        let url = url.to_owned() + &webdriver_commands::template_str(&cmd.path, args).unwrap();
-        // TODO Macro
-        let body =
-            self.context.lock().unwrap().handle.block_on(async move {
-                net::http::request(&client, &cmd.verb, &url, data).await.unwrap()
-            });
-        SchemaParser::try_parse_response(body)
+        block_on(self.runtime.get(), future)
+    }
+
+    /// Runs the body of a function in the current window and deserializes what it returns,
+    /// see `driver_async::WebDriver::execute`
+    pub fn execute<T: DeserializeOwned + Send>(
+        &self,
+        script: &str,
+        args: impl Serialize,
+    ) -> Result<T, GeckError> {
+        let args = serde_json::to_value(args)?;
+        self.block_on(self.inner.execute(script, args))
+    }
+
+    /// `execute` for asynchronous scripts, they call the callback passed as last argument
+    pub fn execute_async<T: DeserializeOwned + Send>(
+        &self,
+        script: &str,
+        args: impl Serialize,
+    ) -> Result<T, GeckError> {
+        let args = serde_json::to_value(args)?;
+        self.block_on(self.inner.execute_async(script, args))
+    }
+
+    /// Calls a function declaration in the given window over BiDi, see
+    /// `driver_async::WebDriver::call_function`
+    pub fn call_function<T: DeserializeOwned + Send>(
+        &self,
+        handle: &str,
+        function: &str,
+        args: impl Serialize,
+        sandbox: Option<&str>,
+    ) -> Result<T, GeckError> {
+        let args = serde_json::to_value(args)?;
+        self.block_on(self.inner.call_function(handle, function, args, sandbox))
+    }
+
+    /// Evaluates an expression over BiDi in the given window, see
+    /// `driver_async::WebDriver::evaluate`
+    pub fn evaluate<T: DeserializeOwned + Send>(
+        &self,
+        handle: &str,
+        expression: &str,
+        sandbox: Option<&str>,
+    ) -> Result<T, GeckError> {
+        self.block_on(self.inner.evaluate(handle, expression, sandbox))
+    }
+
+    blocking! {
+        /// Generates a session per driver, we maintain a single session per driver at this point
+        pub fn new_session(&self) -> Result<(), GeckError>;
+        /// Creates a BiDi user context, an isolated identity with its own cookies, storage
+        /// and optionally its own proxy. Returns the user context id.
+        pub fn create_user_context(&self, proxy: Option<&Proxy>) -> Result<String, GeckError>;
+        /// Opens the url once it is loaded and returns the page source, see `navigate` to only
+        /// navigate
+        pub fn get(&self, url: &str) -> Result<String, GeckError>;
+        /// Navigates the current window over BiDi, returns once the page reached `wait`
+        pub fn navigate(&self, url: &str, wait: ReadinessState) -> Result<Navigation, GeckError>;
+        /// Reloads the current window, returns once the page reached `wait`
+        pub fn reload(&self, wait: ReadinessState) -> Result<Navigation, GeckError>;
+        /// Goes back in the history of the current window, returns the url it shows then
+        pub fn back(&self) -> Result<Navigation, GeckError>;
+        /// Goes forward in the history of the current window, returns the url it shows then
+        pub fn forward(&self) -> Result<Navigation, GeckError>;
+        /// Url of the current window
+        pub fn current_url(&self) -> Result<String, GeckError>;
+        /// Title of the document of the current window
+        pub fn title(&self) -> Result<String, GeckError>;
+        /// Serialized DOM of the current window
+        pub fn page_source(&self) -> Result<String, GeckError>;
+        /// Execute a W3C script, `args` is a JSON array. Strings are returned as is, other values
+        /// as JSON, see `execute` for typed results.
+        pub fn execute_script(&self, script: &str, args: &str) -> Result<String, GeckError>;
+        /// Waits until no request of the given window, or of any window, was in flight for
+        /// `idle`. A loading document counts as in flight, other requests sent before the call
+        /// are unknown.
+        pub fn network_idle(&self, handle: Option<&str>, idle: Duration) -> Result<(), GeckError>;
+        /// Realms of the browser, or of the given window
+        pub fn realms(&self, handle: Option<&str>) -> Result<Vec<RealmInfo>, GeckError>;
+        /// Save the screenshot of the webpage, uses moz capabilities full screenshot option.
+        /// Creates the file if not exists.
+        pub fn save_screenshot(&self, path: &str) -> Result<(), GeckError>;
+        /// Answers the proxy authentication challenges of the user context with the
+        /// credentials, `None` stands for the proxy of the browser
+        pub fn set_proxy_auth(&self, user_context: Option<&str>, auth: Option<ProxyAuth>) -> Result<(), GeckError>;
+        /// Paces `get`, `navigate` and the navigations the pages of these windows start, such as
+        /// link clicks, by host
+        pub fn set_rate_limiter(&self, limiter: Option<RateLimiter>) -> Result<(), GeckError>;
+        /// Ends the session and closes the browser
+        pub fn quit(&self) -> Result<(), GeckError>;
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::utils::testing::fake_geckodriver;
+    use crate::utils::webdriver_commands;
 
-    use simplelog::*;
-    use std::{thread, time};
-
-    use super::webdriver_commands;
     #[test]
     fn test_dict() {
         let firefox = webdriver_commands::WebDriver::new();
         assert_eq!(firefox.command_dict.get("NEW_SESSION").unwrap().verb, "POST");
     }
+
+    /// A driver whose geckodriver announces a port nothing listens on
+    fn fake_driver(name: &str) -> WebDriver {
+        WebDriver::new(
+            Some("http://127.0.0.1:1".to_owned()),
+            "{}".to_owned(),
+            reqwest::Client::new(),
+            fake_geckodriver(name),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_blocking() {
+        let driver = fake_driver("blocking");
+        assert!(driver.inner().session_id().is_none());
+        assert!(driver.get("https://example.com").is_err());
+        assert_eq!(driver.block_on(async { 1 + 1 }), 2);
+    }
+
+    #[test]
+    fn test_shared() {
+        fn assert_shareable<T: Clone + Send + Sync + 'static>() {}
+        assert_shareable::<WebDriver>();
+        assert_shareable::<driver_async::WebDriver>();
+
+        let driver = fake_driver("shared");
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let driver = driver.clone();
+                std::thread::spawn(move || driver.get("https://example.com").is_err())
+            })
+            .collect();
+        for handle in handles {
+            assert!(handle.join().unwrap());
+        }
+        // The runtime outlives the dropped clones
+        assert_eq!(driver.block_on(async { 1 + 1 }), 2);
+    }
+
+    #[tokio::test]
+    async fn test_blocking_inside_runtime() {
+        let driver = fake_driver("runtime");
+        assert!(driver.new_session().is_err());
+        assert!(driver.quit().is_ok());
+    }
 }
@@ -0,0 +1,564 @@
+/*
+Typed BiDi events: page loads, navigations, prompts and browsing context lifecycle, filtered
+by tab, and waiting for the network of a tab to go idle
+ */
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_tungstenite::tungstenite;
+
+use crate::cdp::CDP;
+use crate::schemas::script::Target;
+use crate::utils::error::{ErrorKind, GeckError};
+
+/// Events of an `EventStream`, by their BiDi method
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    Load,
+    DomContentLoaded,
+    NavigationStarted,
+    FragmentNavigated,
+    UserPromptOpened,
+    ContextCreated,
+    ContextDestroyed,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 7] = [
+        EventKind::Load,
+        EventKind::DomContentLoaded,
+        EventKind::NavigationStarted,
+        EventKind::FragmentNavigated,
+        EventKind::UserPromptOpened,
+        EventKind::ContextCreated,
+        EventKind::ContextDestroyed,
+    ];
+
+    pub fn method(&self) -> &'static str {
+        match self {
+            EventKind::Load => "browsingContext.load",
+            EventKind::DomContentLoaded => "browsingContext.domContentLoaded",
+            EventKind::NavigationStarted => "browsingContext.navigationStarted",
+            EventKind::FragmentNavigated => "browsingContext.fragmentNavigated",
+            EventKind::UserPromptOpened => "browsingContext.userPromptOpened",
+            EventKind::ContextCreated => "browsingContext.contextCreated",
+            EventKind::ContextDestroyed => "browsingContext.contextDestroyed",
+        }
+    }
+
+    pub fn from_method(method: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.method() == method)
+    }
+}
+
+/// A navigation step of a browsing context, the BiDi `browsingContext.NavigationInfo`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NavigationInfo {
+    pub context: String,
+    /// Id of the navigation, see `Navigation::navigation`
+    #[serde(default)]
+    pub navigation: Option<String>,
+    /// Milliseconds since the epoch
+    pub timestamp: u64,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PromptType {
+    Alert,
+    BeforeUnload,
+    Confirm,
+    Prompt,
+}
+
+/// A dialog opened by a page
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UserPrompt {
+    pub context: String,
+    #[serde(rename = "type")]
+    pub prompt_type: PromptType,
+    pub message: String,
+    /// How the session handles the prompt, `accept`, `dismiss` or `ignore`
+    #[serde(default)]
+    pub handler: Option<String>,
+    #[serde(default)]
+    pub default_value: Option<String>,
+}
+
+/// A browsing context, the BiDi `browsingContext.Info`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ContextInfo {
+    pub context: String,
+    pub url: String,
+    /// None for tabs and windows, the parent context of frames
+    #[serde(default)]
+    pub parent: Option<String>,
+    #[serde(default)]
+    pub user_context: Option<String>,
+    #[serde(default)]
+    pub children: Option<Vec<ContextInfo>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PageEvent {
+    Load(NavigationInfo),
+    DomContentLoaded(NavigationInfo),
+    NavigationStarted(NavigationInfo),
+    FragmentNavigated(NavigationInfo),
+    UserPromptOpened(UserPrompt),
+    ContextCreated(ContextInfo),
+    ContextDestroyed(ContextInfo),
+}
+
+impl PageEvent {
+    /// Parses a BiDi event, none for other events
+    pub fn parse(event: &Value) -> Option<Result<Self, GeckError>> {
+        let kind = EventKind::from_method(event["method"].as_str()?)?;
+        let params = event["params"].clone();
+        let parse = || -> Result<Self, serde_json::Error> {
+            Ok(match kind {
+                EventKind::Load => PageEvent::Load(serde_json::from_value(params)?),
+                EventKind::DomContentLoaded => {
+                    PageEvent::DomContentLoaded(serde_json::from_value(params)?)
+                }
+                EventKind::NavigationStarted => {
+                    PageEvent::NavigationStarted(serde_json::from_value(params)?)
+                }
+                EventKind::FragmentNavigated => {
+                    PageEvent::FragmentNavigated(serde_json::from_value(params)?)
+                }
+                EventKind::UserPromptOpened => {
+                    PageEvent::UserPromptOpened(serde_json::from_value(params)?)
+                }
+                EventKind::ContextCreated => {
+                    PageEvent::ContextCreated(serde_json::from_value(params)?)
+                }
+                EventKind::ContextDestroyed => {
+                    PageEvent::ContextDestroyed(serde_json::from_value(params)?)
+                }
+            })
+        };
+        Some(parse().map_err(GeckError::from))
+    }
+
+    pub fn kind(&self) -> EventKind {
+        match self {
+            PageEvent::Load(_) => EventKind::Load,
+            PageEvent::DomContentLoaded(_) => EventKind::DomContentLoaded,
+            PageEvent::NavigationStarted(_) => EventKind::NavigationStarted,
+            PageEvent::FragmentNavigated(_) => EventKind::FragmentNavigated,
+            PageEvent::UserPromptOpened(_) => EventKind::UserPromptOpened,
+            PageEvent::ContextCreated(_) => EventKind::ContextCreated,
+            PageEvent::ContextDestroyed(_) => EventKind::ContextDestroyed,
+        }
+    }
+
+    /// The browsing context the event happened in
+    pub fn context(&self) -> &str {
+        match self {
+            PageEvent::Load(info)
+            | PageEvent::DomContentLoaded(info)
+            | PageEvent::NavigationStarted(info)
+            | PageEvent::FragmentNavigated(info) => &info.context,
+            PageEvent::UserPromptOpened(prompt) => &prompt.context,
+            PageEvent::ContextCreated(info) | PageEvent::ContextDestroyed(info) => &info.context,
+        }
+    }
+}
+
+/// Browsing contexts of some tabs, frames created in them are added as they show up
+struct Contexts(Option<HashSet<String>>);
+
+impl Contexts {
+    /// The tabs and their current frames, from `browsingContext.getTree`
+    async fn new(bidi: &CDP, contexts: Option<&[String]>) -> Result<Self, GeckError> {
+        let Some(contexts) = contexts else {
+            return Ok(Self(None));
+        };
+        let mut known = HashSet::new();
+        for context in contexts {
+            let params = serde_json::json!({ "root": context });
+            let resp = bidi
+                .send("browsingContext.getTree", &params.to_string())
+                .await?;
+            let tree: Vec<ContextInfo> =
+                serde_json::from_value(resp["result"]["contexts"].clone())?;
+            let mut pending = tree;
+            while let Some(info) = pending.pop() {
+                pending.extend(info.children.unwrap_or_default());
+                known.insert(info.context);
+            }
+            known.insert(context.clone());
+        }
+        Ok(Self(Some(known)))
+    }
+
+    /// Whether an event of the context is kept, learns the frames from `contextCreated`
+    fn keep(&mut self, event: &Value) -> bool {
+        let Some(contexts) = &mut self.0 else {
+            return true;
+        };
+        let params = &event["params"];
+        if event["method"] == EventKind::ContextCreated.method() {
+            if let (Some(parent), Some(context)) =
+                (params["parent"].as_str(), params["context"].as_str())
+            {
+                if contexts.contains(parent) {
+                    contexts.insert(context.to_owned());
+                }
+            }
+        }
+        params["context"]
+            .as_str()
+            .is_some_and(|context| contexts.contains(context))
+    }
+}
+
+/// Typed events of a driver or of some tabs and their frames. Events are only received
+/// after the stream is opened. The subscription of the stream ends with `close`, or in the
+/// background when the stream is dropped.
+pub struct EventStream {
+    bidi: Arc<CDP>,
+    subscription: Option<String>,
+    receiver: broadcast::Receiver<Value>,
+    kinds: Vec<EventKind>,
+    contexts: Contexts,
+}
+
+impl EventStream {
+    /// Subscribes to the events, of the given contexts only when there are some
+    pub async fn open(
+        bidi: Arc<CDP>,
+        kinds: &[EventKind],
+        contexts: Option<&[String]>,
+    ) -> Result<Self, GeckError> {
+        let receiver = bidi.events();
+        let mut methods: Vec<&str> = kinds.iter().map(|kind| kind.method()).collect();
+        // Frames of the tabs are learnt from their creation
+        if contexts.is_some() && !kinds.contains(&EventKind::ContextCreated) {
+            methods.push(EventKind::ContextCreated.method());
+        }
+        let subscription = bidi
+            .subscribe(&methods, contexts.unwrap_or_default())
+            .await?;
+        let contexts = Contexts::new(&bidi, contexts).await?;
+        Ok(Self {
+            bidi,
+            subscription,
+            receiver,
+            kinds: kinds.to_vec(),
+            contexts,
+        })
+    }
+
+    /// Ends the subscription of the stream
+    pub async fn close(mut self) -> Result<(), GeckError> {
+        match self.subscription.take() {
+            Some(subscription) => self.bidi.unsubscribe(&subscription).await,
+            None => Ok(()),
+        }
+    }
+
+    /// The next event, none once the connection is closed
+    pub async fn next(&mut self) -> Option<PageEvent> {
+        loop {
+            let event = match self.receiver.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Event stream missed {} events", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return None,
+            };
+            if !self.contexts.keep(&event) {
+                continue;
+            }
+            match PageEvent::parse(&event) {
+                Some(Ok(event)) if self.kinds.contains(&event.kind()) => return Some(event),
+                Some(Err(e)) => warn!("Cannot parse event {}: {}", event["method"], e),
+                _ => {}
+            }
+        }
+    }
+
+    /// Waits for the first event matching the predicate, none once the connection is closed
+    pub async fn wait_for<F>(&mut self, mut predicate: F) -> Option<PageEvent>
+    where
+        F: FnMut(&PageEvent) -> bool,
+    {
+        while let Some(event) = self.next().await {
+            if predicate(&event) {
+                return Some(event);
+            }
+        }
+        None
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        let (Some(subscription), Ok(handle)) = (self.subscription.take(), Handle::try_current())
+        else {
+            return;
+        };
+        let bidi = self.bidi.clone();
+        handle.spawn(async move {
+            if let Err(e) = bidi.unsubscribe(&subscription).await {
+                debug!("Cannot end the subscription {}: {}", subscription, e);
+            }
+        });
+    }
+}
+
+const NETWORK_EVENTS: [&str; 3] = [
+    "network.beforeRequestSent",
+    "network.responseCompleted",
+    "network.fetchError",
+];
+
+/// Waits until no request of the contexts, of any context when none are given, was in
+/// flight for `idle`. Documents still loading count as in flight until their load event.
+/// Other requests sent before the call are unknown, their completion only restarts the wait,
+/// so a request outliving `idle` without any other network event is missed.
+pub async fn network_idle(
+    bidi: &CDP,
+    contexts: Option<&[String]>,
+    idle: Duration,
+) -> Result<(), GeckError> {
+    let events = bidi.events();
+    let mut methods = NETWORK_EVENTS.to_vec();
+    methods.push(EventKind::ContextCreated.method());
+    methods.push(EventKind::Load.method());
+    let subscription = bidi
+        .subscribe(&methods, contexts.unwrap_or_default())
+        .await?;
+    let result = wait_idle(bidi, events, contexts, idle).await;
+    if let Some(subscription) = subscription {
+        if let Err(e) = bidi.unsubscribe(&subscription).await {
+            debug!("Cannot end the subscription {}: {}", subscription, e);
+        }
+    }
+    result
+}
+
+/// Top level contexts whose document is still loading
+async fn loading(bidi: &CDP, contexts: Option<&[String]>) -> Result<Vec<String>, GeckError> {
+    let contexts = match contexts {
+        Some(contexts) => contexts.to_vec(),
+        None => bidi.top_level_contexts().await?,
+    };
+    let mut loading = Vec::new();
+    for context in contexts {
+        let state = bidi
+            .evaluate(&Target::context(&context, None), "document.readyState")
+            .await
+            .and_then(|state| state.into_typed::<String>());
+        match state {
+            Ok(state) if state != "complete" => loading.push(context),
+            Ok(_) => {}
+            Err(e) => debug!("Cannot read the readyState of {}: {}", context, e),
+        }
+    }
+    Ok(loading)
+}
+
+async fn wait_idle(
+    bidi: &CDP,
+    mut events: broadcast::Receiver<Value>,
+    contexts: Option<&[String]>,
+    idle: Duration,
+) -> Result<(), GeckError> {
+    let mut known = Contexts::new(bidi, contexts).await?;
+    // Loading documents are in flight under their context id
+    let mut in_flight: HashSet<String> = loading(bidi, contexts).await?.into_iter().collect();
+    loop {
+        let event = if in_flight.is_empty() {
+            tokio::select! {
+                _ = tokio::time::sleep(idle) => return Ok(()),
+                event = events.recv() => event,
+            }
+        } else {
+            events.recv().await
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                // Completions may be lost, start over from the next events
+                warn!("Network idle missed {} events", missed);
+                in_flight.clear();
+                continue;
+            }
+            Err(RecvError::Closed) => {
+                return Err(GeckError::new(
+                    ErrorKind::Driver,
+                    Some(tungstenite::Error::ConnectionClosed),
+                    "BiDi connection closed while waiting for the network",
+                ))
+            }
+        };
+        if !known.keep(&event) {
+            continue;
+        }
+        let request = event["params"]["request"]["request"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned();
+        match event["method"].as_str().unwrap_or_default() {
+            "network.beforeRequestSent" => {
+                in_flight.insert(request);
+            }
+            "network.responseCompleted" | "network.fetchError" => {
+                in_flight.remove(&request);
+            }
+            "browsingContext.load" => {
+                if let Some(context) = event["params"]["context"].as_str() {
+                    in_flight.remove(context);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tab::Tab;
+    use crate::utils::testing::StandIn;
+    use serde_json::json;
+
+    fn navigation(context: &str, url: &str) -> Value {
+        json!({ "context": context, "navigation": "nav-1", "timestamp": 1724157311434u64, "url": url })
+    }
+
+    #[tokio::test]
+    async fn test_tab_events() {
+        let browser = StandIn::start("events").await;
+        let driver = browser.driver().await.unwrap();
+        let tab = Tab::new(driver.clone(), "tab-uc-a");
+        let mut events = tab
+            .events(&[EventKind::Load, EventKind::UserPromptOpened])
+            .await
+            .unwrap();
+        let mut all = driver.events(&[EventKind::Load]).await.unwrap();
+        assert_eq!(
+            browser.browser().subscriptions[0],
+            json!({ "events": ["browsingContext.load", "browsingContext.userPromptOpened",
+                "browsingContext.contextCreated"], "contexts": ["tab-uc-a"] })
+        );
+
+        browser.emit(
+            "browsingContext.load",
+            navigation("tab-uc-b", "https://b.test/"),
+        );
+        browser.emit(
+            "browsingContext.fragmentNavigated",
+            navigation("tab-uc-a", "https://a.test/#top"),
+        );
+        browser.emit(
+            "browsingContext.contextCreated",
+            json!({
+            "context": "frame-1", "parent": "tab-uc-a", "url": "about:blank", "children": null }),
+        );
+        browser.emit(
+            "browsingContext.load",
+            navigation("frame-1", "https://ads.test/"),
+        );
+        browser.emit(
+            "browsingContext.userPromptOpened",
+            json!({
+            "context": "tab-uc-a", "type": "confirm", "message": "Leave?", "handler": "dismiss" }),
+        );
+
+        let load = events.next().await.unwrap();
+        assert_eq!(load.kind(), EventKind::Load);
+        assert_eq!(load.context(), "frame-1");
+        let prompt = events
+            .wait_for(|e| e.kind() == EventKind::UserPromptOpened)
+            .await
+            .unwrap();
+        match prompt {
+            PageEvent::UserPromptOpened(prompt) => {
+                assert_eq!(
+                    (prompt.prompt_type, prompt.message.as_str()),
+                    (PromptType::Confirm, "Leave?")
+                )
+            }
+            other => panic!("Unexpected {:?}", other),
+        }
+        let first = all.next().await.unwrap();
+        assert_eq!(
+            first,
+            PageEvent::Load(NavigationInfo {
+                context: "tab-uc-b".to_owned(),
+                navigation: Some("nav-1".to_owned()),
+                timestamp: 1724157311434,
+                url: "https://b.test/".to_owned(),
+            })
+        );
+
+        // Closing or dropping a stream ends its subscription
+        events.close().await.unwrap();
+        assert_eq!(
+            browser.browser().unsubscriptions,
+            vec![json!({ "subscriptions": ["sub-1"] })]
+        );
+        drop(all);
+        while browser.browser().unsubscriptions.len() < 2 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(
+            browser.browser().unsubscriptions[1],
+            json!({ "subscriptions": ["sub-2"] })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_network_idle() {
+        let browser = StandIn::start("network-idle").await;
+        let driver = browser.driver().await.unwrap();
+        // The document of the tab is loading and its frame exists before the call
+        browser.browser().loading.push("tab-uc-a".to_owned());
+        browser
+            .browser()
+            .frames
+            .push(("tab-uc-a".to_owned(), "frame-1".to_owned()));
+        let tab = Tab::new(driver.clone(), "tab-uc-a");
+        let idle = tokio::spawn(async move { tab.network_idle(Duration::from_millis(50)).await });
+        while browser.browser().subscriptions.is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert!(!idle.is_finished());
+
+        let request = |method: &str, context: &str, id: &str| {
+            browser.emit(method, json!({ "context": context, "request": { "request": id, "url": "https://a.test/" } }))
+        };
+        request("network.beforeRequestSent", "frame-1", "r-1");
+        request("network.beforeRequestSent", "tab-uc-b", "r-2");
+        browser.emit(
+            "browsingContext.load",
+            navigation("tab-uc-a", "https://a.test/"),
+        );
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert!(!idle.is_finished());
+
+        // Requests of other tabs don't keep the tab busy
+        let start = tokio::time::Instant::now();
+        request("network.responseCompleted", "frame-1", "r-1");
+        idle.await.unwrap().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(
+            browser.browser().unsubscriptions,
+            vec![json!({ "subscriptions": ["sub-1"] })]
+        );
+    }
+}
@@ -1,63 +1,83 @@
+pub mod binding;
+pub mod browser_pool;
+pub mod config;
+pub mod crawler;
 pub mod driver;
+pub mod driver_async;
 pub mod driver_sync;
+pub mod events;
 pub mod options;
 pub mod schemas;
 pub mod service;
 pub mod utils;
 pub mod cdp;
 pub mod package;
+pub mod proxy_pool;
+pub mod rate_limit;
+pub mod tab;
 
 //use crate::package::PackageManager;
 use crate::driver::WebDriver;
+use crate::options::prefs::PrefPreset;
 use crate::options::{capabilities, Capabilities, DriverOptions};
+use crate::proxy_pool::ProxyPool;
+use crate::rate_limit::RateLimiter;
+use crate::schemas::proxy::Proxy;
+use crate::service::ServiceConfig;
 use crate::utils::error::GeckError;
-use log::*;
+use crate::utils::logging::{LogLevel, LogSink};
+use std::sync::Arc;
 
+/// Builds a driver from optional capabilities, evaluates to `Result<WebDriver, GeckError>`
 #[macro_export]
 macro_rules! driver{
     ($x: expr) => {
         {
-            let mut driver = DriverBuilder::new(None)
-                                .build()
-                                .unwrap();
-            if let Some(s) = $x {
-                let driver = DriverBuilder::new(Some(s)).build().unwrap();
-                return driver;
-            } else {
-                let driver = DriverBuilder::new(None).build().unwrap();
-                return driver;
-            }
+            DriverBuilder::new($x).build()
         }
     };
 }
+/// Evaluates to the page source, `Result<String, GeckError>`
 #[macro_export]
 macro_rules! get_page{
     ($x: expr) => {
         {
-            let mut driver = DriverBuilder::new(None)
-                                .build()
-                                .unwrap();
-            let page = driver.get($x);
-            page
+            DriverBuilder::new(None)
+                .build()
+                .and_then(|driver| driver.get($x))
         }
     };
 }
 
+/// Evaluates to `Result<(), GeckError>`
 #[macro_export]
 macro_rules! save_screenshot{
     ($x: expr, $y: expr) => {
         {
-            let mut driver = DriverBuilder::new(None)
-                                .build()
-                                .unwrap();
-            let page = driver.get($x);
-            driver.save_screenshot($y);
+            DriverBuilder::new(None).build().and_then(|driver| {
+                driver.get($x)?;
+                driver.save_screenshot($y)
+            })
         }
     };
 }
+/// Builds Firefox drivers.
+///
+/// User provided capabilities and options are merged over the defaults:
+/// - capabilities: `browserName: firefox`, `acceptInsecureCerts`, `moz:debuggerAddress` and `webSocketUrl`
+/// - options: headless, geckodriver log level `info` and Firefox default window size
+#[derive(Clone)]
 pub struct DriverBuilder {
     pub options: Option<DriverOptions>,
     pub capabilities: Option<Capabilities>,
+    pub service: ServiceConfig,
+    headless: Option<bool>,
+    log_level: Option<LogLevel>,
+    window_size: Option<(u32, u32)>,
+    proxy: Option<Proxy>,
+    proxy_pool: Option<ProxyPool>,
+    presets: Vec<PrefPreset>,
+    rate_limiter: Option<RateLimiter>,
 }
 
 impl DriverBuilder {
@@ -66,11 +86,43 @@ impl DriverBuilder {
     pub fn new(capabilities: Option<Capabilities>) -> Self {
         Self {
             options: None,
-            capabilities: capabilities,
+            capabilities,
+            service: ServiceConfig::default(),
+            headless: None,
+            log_level: None,
+            window_size: None,
+            proxy: None,
+            proxy_pool: None,
+            presets: Vec::new(),
+            rate_limiter: None,
         }
     }
 
-    /// Override options
+    /// Route geckodriver logs to a sink, `TracingSink` by default
+    pub fn log_sink(&mut self, sink: Arc<dyn LogSink>) -> Result<&mut Self, GeckError> {
+        self.service.log_sink = sink;
+        Ok(self)
+    }
+
+    /// Use an explicit geckodriver executable instead of resolving it with `PackageManager`
+    pub fn geckodriver(&mut self, path: &str) -> Result<&mut Self, GeckError> {
+        self.service.driver_path = Some(path.to_owned());
+        Ok(self)
+    }
+
+    /// Port geckodriver listens on, 4444 by default
+    pub fn port(&mut self, port: u16) -> Result<&mut Self, GeckError> {
+        self.service.port = port;
+        Ok(self)
+    }
+
+    /// Override capabilities, merged over the default capabilities
+    pub fn capabilities(&mut self, capabilities: Capabilities) -> Result<&mut Self, GeckError> {
+        self.capabilities = Some(capabilities);
+        Ok(self)
+    }
+
+    /// Override options, merged over the default options
     pub fn options(&mut self, options: DriverOptions) -> Result<&mut Self, GeckError> {
         self.options = Some(options);
         Ok(self)
@@ -79,69 +131,320 @@ impl DriverBuilder {
     /// Insert a new option
     /// TODO: Make Macro
     pub fn option(&mut self, option_type: &str, option: (&str, &str)) -> Result<&mut Self, GeckError> {
-        if !DriverOptions::option_types()
-            .iter()
-            .any(|&i| i == option_type)
-        {
-            panic!("Invalid option")
-        }
-        self.options.as_mut().unwrap().insert(option_type, option);
+        self.options
+            .get_or_insert_with(DriverOptions::new)
+            .insert(option_type, option)?;
         Ok(self)
     }
 
-    /// If no options provided, or some provided
-    pub fn setup_default_options(&mut self) -> Result<&mut Self, GeckError> {
-        self.options
-            .as_mut()
-            .unwrap()
-            .insert("--arg", ("-headless", ""));
-        self.options
-            .as_mut()
-            .unwrap()
-            .insert("--log", ("level", "trace"));
-        let cap = self.options.as_ref().unwrap().to_capabilities().unwrap();
-        self.capabilities.as_mut().unwrap().extend(cap);
+    /// Run Firefox headless, enabled by default
+    pub fn headless(&mut self, headless: bool) -> Result<&mut Self, GeckError> {
+        self.headless = Some(headless);
         Ok(self)
     }
 
-    /// If no capabilities provided, or some provided
-    pub fn setup_default_capabilities(&mut self) -> Result<&mut Self, GeckError> {
-        self.capabilities = Some(capabilities::DesiredCapabilities::FIREFOX.values());
-        self.capabilities
-            .as_mut()
-            .unwrap()
-            .insert("alwaysMatch", "webSocketUrl", true);
+    /// Geckodriver and Marionette log level, `info` by default
+    pub fn log_level(&mut self, level: LogLevel) -> Result<&mut Self, GeckError> {
+        self.log_level = Some(level);
         Ok(self)
     }
 
+    /// Initial browser window size in pixels
+    pub fn window_size(&mut self, width: u32, height: u32) -> Result<&mut Self, GeckError> {
+        self.window_size = Some((width, height));
+        Ok(self)
+    }
+
+    /// Layer a preference preset, user prefs override presets
+    pub fn preset(&mut self, preset: PrefPreset) -> Result<&mut Self, GeckError> {
+        self.presets.push(preset);
+        Ok(self)
+    }
+
+    /// Proxy used by the whole browser, user contexts can override it. The driver answers
+    /// the proxy authentication challenges with its credentials.
+    pub fn proxy(&mut self, proxy: Proxy) -> Result<&mut Self, GeckError> {
+        self.proxy = Some(proxy);
+        self.proxy_pool = None;
+        Ok(self)
+    }
+
+    /// Take the browser proxy from a pool, every built driver acquires its own. User contexts
+    /// can take theirs with `ProxyPool::acquire`.
+    pub fn proxy_pool(&mut self, pool: &ProxyPool) -> Result<&mut Self, GeckError> {
+        self.proxy = None;
+        self.proxy_pool = Some(pool.clone());
+        Ok(self)
+    }
+
+    /// Pace the navigations of the driver by host, clones of the limiter share its state
+    pub fn rate_limiter(&mut self, limiter: RateLimiter) -> Result<&mut Self, GeckError> {
+        self.rate_limiter = Some(limiter);
+        Ok(self)
+    }
+
+    /// Default capabilities, user capabilities are merged over these
+    pub fn default_capabilities() -> Capabilities {
+        let mut capabilities = capabilities::DesiredCapabilities::FIREFOX.values();
+        capabilities.always_match.web_socket_url = Some(true);
+        capabilities
+    }
+
+    /// Default options, user options are merged over these
+    pub fn default_options() -> DriverOptions {
+        let mut options = DriverOptions::new();
+        options.arg("-headless");
+        options.log(("level", LogLevel::Info.as_str()));
+        options
+    }
+
+    /// The options sent to Firefox: defaults, presets, user options, then explicit switches
+    pub fn merged_options(&self) -> DriverOptions {
+        let mut options = Self::default_options();
+        for preset in &self.presets {
+            options.preset(*preset);
+        }
+        if let Some(user) = &self.options {
+            options.extend(user.clone());
+        }
+        if self.headless == Some(false) {
+            options.remove_arg("-headless");
+            options.remove_arg("--headless");
+        }
+        if let Some(level) = self.log_level {
+            options.log(("level", level.as_str()));
+        }
+        if let Some((width, height)) = self.window_size {
+            options.arg(&format!("--width={}", width));
+            options.arg(&format!("--height={}", height));
+        }
+        options
+    }
+
+    /// The capabilities sent with the new session request, without the proxy of a pool
+    pub fn merged_capabilities(&self) -> Result<Capabilities, GeckError> {
+        self.capabilities_with(self.proxy.as_ref())
+    }
+
+    /// The capabilities and proxy of the next session, a pool assigns a proxy to each session
+    fn session_capabilities(&self) -> Result<(Capabilities, Option<Proxy>), GeckError> {
+        let proxy = match &self.proxy_pool {
+            Some(pool) => Some(pool.acquire()?.proxy),
+            None => self.proxy.clone(),
+        };
+        Ok((self.capabilities_with(proxy.as_ref())?, proxy))
+    }
+
+    fn capabilities_with(&self, proxy: Option<&Proxy>) -> Result<Capabilities, GeckError> {
+        let mut capabilities = Self::default_capabilities();
+        if let Some(user) = &self.capabilities {
+            capabilities.extend(user.clone());
+        }
+        capabilities.extend(self.merged_options().to_capabilities()?);
+        if let Some(proxy) = proxy {
+            capabilities.always_match.proxy = Some(proxy.clone());
+        }
+        capabilities.validate()?;
+        Ok(capabilities)
+    }
+
     /// Builds a firefox driver
     pub fn build(&mut self) -> Result<WebDriver, GeckError> {
-        // Setup options
-        self.options = Some(DriverOptions::new());
-        self.setup_default_capabilities();
-        self.setup_default_options();
-        Ok(WebDriver::new(
+        let (capabilities, proxy) = self.session_capabilities()?;
+        let driver = WebDriver::new(
             None,
-            serde_json::to_string(&self.capabilities.as_ref().unwrap()).unwrap(),
+            capabilities.to_session_request()?,
             reqwest::Client::new(),
-        ))
+            self.service_config(&capabilities),
+        )?;
+        if self.rate_limiter.is_some() {
+            driver.set_rate_limiter(self.rate_limiter.clone())?;
+        }
+        if let Some(auth) = proxy.and_then(|proxy| proxy.auth) {
+            driver.set_proxy_auth(None, Some(auth))?;
+        }
+        Ok(driver)
     }
 
-    pub async fn build_async(&mut self) -> Result<driver_sync::sync::WebDriver, GeckError> {
-        // Setup options
-        self.options = Some(DriverOptions::new());
-        self.setup_default_capabilities();
-        self.setup_default_options();
-        Ok(driver_sync::sync::WebDriver::new(
+    /// Builds an async firefox driver, `build` wraps the same driver into a blocking API
+    pub async fn build_async(&mut self) -> Result<driver_async::WebDriver, GeckError> {
+        let (capabilities, proxy) = self.session_capabilities()?;
+        let driver = driver_async::WebDriver::new(
             None,
-            serde_json::to_string(&self.capabilities.as_ref().unwrap()).unwrap(),
+            capabilities.to_session_request()?,
             reqwest::Client::new(),
-        ).await)
+            self.service_config(&capabilities),
+        )
+        .await?;
+        if self.rate_limiter.is_some() {
+            driver.set_rate_limiter(self.rate_limiter.clone()).await?;
+        }
+        if let Some(auth) = proxy.and_then(|proxy| proxy.auth) {
+            driver.set_proxy_auth(None, Some(auth)).await?;
+        }
+        Ok(driver)
+    }
+
+    pub fn serialized_capabilities(&self) -> Result<String, GeckError> {
+        self.merged_capabilities()?.to_session_request()
+    }
+
+    /// The service settings, geckodriver is checked against the Firefox binary of the capabilities
+    pub fn service_config(&self, capabilities: &Capabilities) -> ServiceConfig {
+        let mut service = self.service.clone();
+        if service.firefox.is_none() {
+            service.firefox = capabilities
+                .always_match
+                .firefox_options
+                .as_ref()
+                .and_then(|options| options.binary_path())
+                .map(|binary| binary.to_owned());
+        }
+        service
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn firefox_options(builder: &DriverBuilder) -> serde_json::Value {
+        let capabilities: serde_json::Value =
+            serde_json::from_str(&builder.serialized_capabilities().unwrap()).unwrap();
+        capabilities["capabilities"]["alwaysMatch"]["moz:firefoxOptions"].clone()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let builder = DriverBuilder::new(None);
+        let capabilities: serde_json::Value =
+            serde_json::from_str(&builder.serialized_capabilities().unwrap()).unwrap();
+        assert_eq!(
+            capabilities["capabilities"]["alwaysMatch"]["browserName"],
+            "firefox"
+        );
+        assert_eq!(
+            capabilities["capabilities"]["alwaysMatch"]["webSocketUrl"],
+            true
+        );
+        let options = firefox_options(&builder);
+        assert_eq!(options["args"], serde_json::json!(["-headless"]));
+        assert_eq!(options["log"]["level"], "info");
+    }
+
+    #[test]
+    fn test_user_options() {
+        let mut capabilities = Capabilities::new();
+        capabilities.always_match.accept_insecure_certs = Some(false);
+        capabilities.always_match.page_load_strategy = Some(options::PageLoadStrategy::Eager);
+        let mut options = DriverOptions::new();
+        options.arg("-private");
+        options.pref(("intl.accept_languages", "de-DE"));
+        options.log(("level", "debug"));
+
+        let mut builder = DriverBuilder::new(Some(capabilities));
+        builder
+            .options(options)
+            .unwrap()
+            .option("--binary", ("/opt/firefox/firefox", ""))
+            .unwrap();
+        let serialized: serde_json::Value =
+            serde_json::from_str(&builder.serialized_capabilities().unwrap()).unwrap();
+        let always_match = &serialized["capabilities"]["alwaysMatch"];
+        assert_eq!(always_match["acceptInsecureCerts"], false);
+        assert_eq!(always_match["pageLoadStrategy"], "eager");
+        assert_eq!(always_match["browserName"], "firefox");
+
+        let options = firefox_options(&builder);
+        assert_eq!(
+            options["args"],
+            serde_json::json!(["-headless", "-private"])
+        );
+        assert_eq!(options["prefs"]["intl.accept_languages"], "de-DE");
+        assert_eq!(options["log"]["level"], "debug");
+        assert_eq!(options["binary"], "/opt/firefox/firefox");
+    }
+
+    #[test]
+    fn test_presets() {
+        let mut options = DriverOptions::new();
+        options.set_pref("dom.webdriver.enabled", true);
+        let mut builder = DriverBuilder::new(None);
+        builder
+            .preset(PrefPreset::Privacy)
+            .unwrap()
+            .preset(PrefPreset::HideMarionette)
+            .unwrap()
+            .options(options)
+            .unwrap();
+        let options = firefox_options(&builder);
+        assert_eq!(options["prefs"]["toolkit.telemetry.enabled"], false);
+        assert_eq!(options["prefs"]["dom.webdriver.enabled"], true);
+    }
+
+    #[test]
+    fn test_switches() {
+        let mut builder = DriverBuilder::new(None);
+        builder
+            .headless(false)
+            .unwrap()
+            .log_level(LogLevel::Trace)
+            .unwrap()
+            .window_size(1280, 800)
+            .unwrap();
+        let options = firefox_options(&builder);
+        assert_eq!(
+            options["args"],
+            serde_json::json!(["--width=1280", "--height=800"])
+        );
+        assert_eq!(options["log"]["level"], "trace");
+    }
+
+    #[test]
+    fn test_proxy() {
+        let mut builder = DriverBuilder::new(None);
+        builder
+            .proxy(Proxy::from_url("socks5://127.0.0.1:1080").unwrap())
+            .unwrap();
+        let capabilities: serde_json::Value =
+            serde_json::from_str(&builder.serialized_capabilities().unwrap()).unwrap();
+        assert_eq!(
+            capabilities["capabilities"]["alwaysMatch"]["proxy"],
+            serde_json::json!({"proxyType": "manual", "socksProxy": "127.0.0.1:1080", "socksVersion": 5})
+        );
+        builder.proxy(Proxy::manual()).unwrap();
+        assert!(builder.serialized_capabilities().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy_pool() {
+        let pool = ProxyPool::new("http://127.0.0.1:1/");
+        pool.add("http://127.0.0.1:3128").unwrap();
+        pool.add("http://127.0.0.1:3129").unwrap();
+        let mut builder = DriverBuilder::new(None);
+        builder.service = utils::testing::fake_geckodriver("builder-proxy-pool");
+        builder.proxy_pool(&pool).unwrap();
+        let first = builder.session_capabilities().unwrap();
+        let second = builder.session_capabilities().unwrap();
+        assert_ne!(first.1, second.1);
+        assert_eq!(first.0.always_match.proxy, first.1);
+        assert_eq!(second.0.always_match.proxy, second.1);
+
+        for _ in 0..2 {
+            builder.build_async().await.unwrap();
+        }
+        let assigned: Vec<u32> = pool
+            .stats()
+            .iter()
+            .map(|(_, stats)| stats.assigned)
+            .collect();
+        assert_eq!(assigned, vec![2, 2]);
     }
 
-    pub fn serialized_capabilities(&mut self) -> Result<String, GeckError> {
-        let cap = self.options.as_ref().unwrap().to_capabilities().unwrap();
-        self.capabilities.as_mut().unwrap().extend(cap);
-        Ok(serde_json::to_string(&self.capabilities).unwrap())
+    #[test]
+    fn test_invalid_option() {
+        let mut builder = DriverBuilder::new(None);
+        assert!(builder.option("--unknown", ("value", "")).is_err());
+        assert!(builder.option("--arg", ("-private", "")).is_ok());
     }
 }
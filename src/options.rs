@@ -1,16 +1,11 @@
-use core::panic;
-use log::*;
-use std::borrow::Borrow;
-use std::{collections::HashMap, vec};
+use std::collections::{BTreeMap, HashMap};
 
-use crate::utils::error::GeckError;
-use crate::utils::types::DictType;
+use crate::schemas::session::Proxy;
+use crate::utils::error::{ErrorKind, GeckError};
 use crate::utils::types::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 pub mod capabilities {
-    use Dict;
-
     use super::*;
     // The idea of DesiredCapabilities is to provide const values to be used during options
     // TODO We only implement Firefox as create is for Firefox
@@ -24,9 +19,9 @@ pub mod capabilities {
             match *self {
                 DesiredCapabilities::FIREFOX => {
                     let mut cap = Capabilities::new();
-                    cap.insert("alwaysMatch", "browserName", "firefox");
-                    cap.insert("alwaysMatch", "acceptInsecureCerts", true);
-                    cap.insert("alwaysMatch", "moz:debuggerAddress", true);
+                    cap.always_match.browser_name = Some("firefox".to_owned());
+                    cap.always_match.accept_insecure_certs = Some(true);
+                    cap.always_match.moz_debugger_address = Some(true);
                     cap
                 }
             }
@@ -34,42 +29,436 @@ pub mod capabilities {
     }
 }
 
-#[derive(Serialize, Debug)]
+pub mod prefs {
+    use super::*;
+
+    /// A typed Firefox preference value
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum PrefValue {
+        Bool(bool),
+        Int(i32),
+        String(String),
+    }
+
+    impl From<bool> for PrefValue {
+        fn from(v: bool) -> Self {
+            PrefValue::Bool(v)
+        }
+    }
+
+    impl From<i32> for PrefValue {
+        fn from(v: i32) -> Self {
+            PrefValue::Int(v)
+        }
+    }
+
+    impl From<&str> for PrefValue {
+        fn from(v: &str) -> Self {
+            PrefValue::String(v.to_owned())
+        }
+    }
+
+    impl From<String> for PrefValue {
+        fn from(v: String) -> Self {
+            PrefValue::String(v)
+        }
+    }
+
+    impl From<PrefValue> for DictType {
+        fn from(v: PrefValue) -> Self {
+            match v {
+                PrefValue::Bool(v) => DictType::Bool(v),
+                PrefValue::Int(v) => DictType::Number(v),
+                PrefValue::String(v) => DictType::String(v),
+            }
+        }
+    }
+
+    /// Curated preference bundles, applied in order so later presets and prefs override earlier ones
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum PrefPreset {
+        /// Disable telemetry, health reports and studies
+        NoTelemetry,
+        /// Disable application, extension and search engine updates
+        NoUpdates,
+        /// Disable safe browsing lookups and list downloads
+        NoSafeBrowsing,
+        /// Skip welcome pages, default browser checks and session restore
+        FastStartup,
+        /// Block audible and inaudible autoplay
+        BlockAutoplay,
+        /// Keep fingerprinting resistance off so the browser looks like a regular install
+        NoResistFingerprinting,
+        /// Set `dom.webdriver.enabled` to false. Current Firefox ignores this pref:
+        /// `navigator.webdriver` stays true while Marionette controls the browser.
+        HideMarionette,
+        /// `NoUpdates`, `FastStartup` and `BlockAutoplay`
+        Performance,
+        /// `NoTelemetry` and `NoSafeBrowsing`
+        Privacy,
+        /// `NoResistFingerprinting` and `HideMarionette`, this does not hide automation
+        Stealth,
+    }
+
+    impl std::str::FromStr for PrefPreset {
+        type Err = GeckError;
+
+        /// Parses snake case preset names, e.g. `no_telemetry`
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s.trim().to_ascii_lowercase().replace('-', "_").as_str() {
+                "no_telemetry" => Ok(PrefPreset::NoTelemetry),
+                "no_updates" => Ok(PrefPreset::NoUpdates),
+                "no_safe_browsing" => Ok(PrefPreset::NoSafeBrowsing),
+                "fast_startup" => Ok(PrefPreset::FastStartup),
+                "block_autoplay" => Ok(PrefPreset::BlockAutoplay),
+                "no_resist_fingerprinting" => Ok(PrefPreset::NoResistFingerprinting),
+                "hide_marionette" => Ok(PrefPreset::HideMarionette),
+                "performance" => Ok(PrefPreset::Performance),
+                "privacy" => Ok(PrefPreset::Privacy),
+                "stealth" => Ok(PrefPreset::Stealth),
+                _ => Err(capabilities_error(&format!("Unknown pref preset {:?}", s))),
+            }
+        }
+    }
+
+    impl PrefPreset {
+        pub fn prefs(&self) -> Vec<(&'static str, PrefValue)> {
+            match self {
+                PrefPreset::NoTelemetry => vec![
+                    ("toolkit.telemetry.enabled", false.into()),
+                    ("toolkit.telemetry.unified", false.into()),
+                    ("toolkit.telemetry.archive.enabled", false.into()),
+                    ("datareporting.healthreport.uploadEnabled", false.into()),
+                    ("datareporting.policy.dataSubmissionEnabled", false.into()),
+                    (
+                        "browser.newtabpage.activity-stream.feeds.telemetry",
+                        false.into(),
+                    ),
+                    ("browser.ping-centre.telemetry", false.into()),
+                    ("app.shield.optoutstudies.enabled", false.into()),
+                    ("app.normandy.enabled", false.into()),
+                ],
+                PrefPreset::NoUpdates => vec![
+                    ("app.update.auto", false.into()),
+                    ("app.update.disabledForTesting", true.into()),
+                    ("extensions.update.enabled", false.into()),
+                    ("browser.search.update", false.into()),
+                ],
+                PrefPreset::NoSafeBrowsing => vec![
+                    ("browser.safebrowsing.malware.enabled", false.into()),
+                    ("browser.safebrowsing.phishing.enabled", false.into()),
+                    ("browser.safebrowsing.downloads.enabled", false.into()),
+                    ("browser.safebrowsing.blockedURIs.enabled", false.into()),
+                    ("browser.safebrowsing.provider.mozilla.updateURL", "".into()),
+                ],
+                PrefPreset::FastStartup => vec![
+                    ("browser.startup.page", 0.into()),
+                    ("browser.startup.homepage", "about:blank".into()),
+                    ("startup.homepage_welcome_url", "about:blank".into()),
+                    ("startup.homepage_welcome_url.additional", "".into()),
+                    ("browser.shell.checkDefaultBrowser", false.into()),
+                    ("browser.aboutwelcome.enabled", false.into()),
+                    ("browser.sessionstore.resume_from_crash", false.into()),
+                    ("browser.tabs.warnOnClose", false.into()),
+                ],
+                PrefPreset::BlockAutoplay => vec![
+                    ("media.autoplay.default", 5.into()),
+                    ("media.autoplay.blocking_policy", 2.into()),
+                ],
+                PrefPreset::NoResistFingerprinting => vec![
+                    ("privacy.resistFingerprinting", false.into()),
+                    ("privacy.resistFingerprinting.letterboxing", false.into()),
+                ],
+                PrefPreset::HideMarionette => vec![("dom.webdriver.enabled", false.into())],
+                PrefPreset::Performance => [
+                    PrefPreset::NoUpdates,
+                    PrefPreset::FastStartup,
+                    PrefPreset::BlockAutoplay,
+                ]
+                .iter()
+                .flat_map(|preset| preset.prefs())
+                .collect(),
+                PrefPreset::Privacy => [PrefPreset::NoTelemetry, PrefPreset::NoSafeBrowsing]
+                    .iter()
+                    .flat_map(|preset| preset.prefs())
+                    .collect(),
+                PrefPreset::Stealth => [
+                    PrefPreset::NoResistFingerprinting,
+                    PrefPreset::HideMarionette,
+                ]
+                .iter()
+                .flat_map(|preset| preset.prefs())
+                .collect(),
+            }
+        }
+    }
+}
+
+fn capabilities_error(msg: &str) -> GeckError {
+    GeckError::new(ErrorKind::Capabilities, None::<GeckError>, msg)
+}
+
+/// W3C page load strategies
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PageLoadStrategy {
+    None,
+    Eager,
+    Normal,
+}
+
+/// W3C user prompt handlers
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnhandledPromptBehavior {
+    #[serde(rename = "dismiss")]
+    Dismiss,
+    #[serde(rename = "accept")]
+    Accept,
+    #[serde(rename = "dismiss and notify")]
+    DismissAndNotify,
+    #[serde(rename = "accept and notify")]
+    AcceptAndNotify,
+    #[serde(rename = "ignore")]
+    Ignore,
+}
+
+/// Session timeouts in milliseconds
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Timeouts {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub implicit: Option<u64>,
+    #[serde(rename = "pageLoad", skip_serializing_if = "Option::is_none")]
+    pub page_load: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script: Option<u64>,
+}
+
+/// A set of W3C capabilities, used for `alwaysMatch` and each `firstMatch` entry.
+/// Unset capabilities are not serialized.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CapabilitySet {
+    #[serde(rename = "browserName", skip_serializing_if = "Option::is_none")]
+    pub browser_name: Option<String>,
+    #[serde(rename = "browserVersion", skip_serializing_if = "Option::is_none")]
+    pub browser_version: Option<String>,
+    #[serde(rename = "platformName", skip_serializing_if = "Option::is_none")]
+    pub platform_name: Option<String>,
+    #[serde(
+        rename = "acceptInsecureCerts",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub accept_insecure_certs: Option<bool>,
+    #[serde(rename = "pageLoadStrategy", skip_serializing_if = "Option::is_none")]
+    pub page_load_strategy: Option<PageLoadStrategy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<Proxy>,
+    #[serde(rename = "setWindowRect", skip_serializing_if = "Option::is_none")]
+    pub set_window_rect: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeouts: Option<Timeouts>,
+    #[serde(
+        rename = "strictFileInteractability",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub strict_file_interactability: Option<bool>,
+    #[serde(
+        rename = "unhandledPromptBehavior",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub unhandled_prompt_behavior: Option<UnhandledPromptBehavior>,
+    #[serde(rename = "webSocketUrl", skip_serializing_if = "Option::is_none")]
+    pub web_socket_url: Option<bool>,
+    #[serde(rename = "moz:firefoxOptions", skip_serializing_if = "Option::is_none")]
+    pub firefox_options: Option<DriverOptions>,
+    #[serde(
+        rename = "moz:debuggerAddress",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub moz_debugger_address: Option<bool>,
+    #[serde(
+        rename = "moz:accessibilityChecks",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub moz_accessibility_checks: Option<bool>,
+    #[serde(rename = "moz:webdriverClick", skip_serializing_if = "Option::is_none")]
+    pub moz_webdriver_click: Option<bool>,
+    /// Extension capabilities, their names must contain a `:`
+    #[serde(flatten)]
+    pub extensions: BTreeMap<String, serde_json::Value>,
+}
+
+impl CapabilitySet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set an extension capability, e.g. `moz:useNonSpecCompliantPointerOrigin`
+    pub fn extension<T: Serialize>(
+        &mut self,
+        name: &str,
+        value: T,
+    ) -> Result<&mut Self, GeckError> {
+        if !name.contains(':') {
+            return Err(capabilities_error(&format!(
+                "Extension capability {:?} must contain a ':'",
+                name
+            )));
+        }
+        let value = serde_json::to_value(value).map_err(|e| {
+            GeckError::new(
+                ErrorKind::Capabilities,
+                Some(e),
+                "Cannot serialize capability",
+            )
+        })?;
+        self.extensions.insert(name.to_owned(), value);
+        Ok(self)
+    }
+
+    /// Merge other capabilities over these, set values of other win
+    pub fn extend(&mut self, other: CapabilitySet) {
+        macro_rules! take {
+            ($($field: ident),*) => {
+                $(if other.$field.is_some() { self.$field = other.$field; })*
+            };
+        }
+        take!(
+            browser_name,
+            browser_version,
+            platform_name,
+            accept_insecure_certs,
+            page_load_strategy,
+            proxy,
+            set_window_rect,
+            timeouts,
+            strict_file_interactability,
+            unhandled_prompt_behavior,
+            web_socket_url,
+            moz_debugger_address,
+            moz_accessibility_checks,
+            moz_webdriver_click
+        );
+        match (self.firefox_options.as_mut(), other.firefox_options) {
+            (Some(options), Some(other)) => options.extend(other),
+            (None, Some(other)) => self.firefox_options = Some(other),
+            _ => (),
+        }
+        self.extensions.extend(other.extensions);
+    }
+
+    /// Names of the capabilities set in this set
+    pub fn keys(&self) -> Vec<String> {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(map)) => map.keys().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), GeckError> {
+        if let Some(proxy) = &self.proxy {
+            proxy.validate()?;
+        }
+        if let Some(name) = &self.browser_name {
+            if name != "firefox" {
+                return Err(capabilities_error(&format!(
+                    "browserName {:?} is not supported, only firefox is",
+                    name
+                )));
+            }
+        }
+        if let Some(name) = self.extensions.keys().find(|name| !name.contains(':')) {
+            return Err(capabilities_error(&format!(
+                "Unknown capability {:?}, extension capabilities must contain a ':'",
+                name
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// W3C capabilities sent with a new session request
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct Capabilities {
-    capabilities: Dict,
+    #[serde(rename = "alwaysMatch", default)]
+    pub always_match: CapabilitySet,
+    #[serde(rename = "firstMatch", default, skip_serializing_if = "Vec::is_empty")]
+    pub first_match: Vec<CapabilitySet>,
 }
 impl Capabilities {
     pub fn new() -> Self {
-        Self {
-            capabilities: Dict::new(),
-        }
+        Self::default()
     }
-    pub fn insert<T: Into<DictType>>(&mut self, match_type: &str, k: &str, v: T) {
-        let mut temp = Dict::new();
-        temp.insert(k, v);
-        self.capabilities.insert(match_type, temp)
+
+    /// Add an alternative, the remote end picks the first matching one
+    pub fn first_match(&mut self, set: CapabilitySet) -> &mut Self {
+        self.first_match.push(set);
+        self
     }
+
+    /// Merge other capabilities over these, `firstMatch` entries are appended
     pub fn extend(&mut self, cap: Self) {
-        self.capabilities.extend(cap.capabilities)
+        self.always_match.extend(cap.always_match);
+        self.first_match.extend(cap.first_match);
+    }
+
+    /// Validates every set and checks `firstMatch` entries don't redefine `alwaysMatch` capabilities
+    pub fn validate(&self) -> Result<(), GeckError> {
+        self.always_match.validate()?;
+        let always = self.always_match.keys();
+        for set in &self.first_match {
+            set.validate()?;
+            if let Some(key) = set.keys().into_iter().find(|key| always.contains(key)) {
+                return Err(capabilities_error(&format!(
+                    "Capability {:?} is set in both alwaysMatch and firstMatch",
+                    key
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Body of the new session request, `{"capabilities": {...}}`
+    pub fn to_session_request(&self) -> Result<String, GeckError> {
+        self.validate()?;
+        serde_json::to_string(&serde_json::json!({ "capabilities": self })).map_err(|e| {
+            GeckError::new(
+                ErrorKind::Capabilities,
+                Some(e),
+                "Cannot serialize capabilities",
+            )
+        })
     }
 }
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct DriverOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    binary: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     args: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     prefs: Option<Dict>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     log: Option<Dict>,
 }
 
+impl Default for DriverOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // TODO We don't support all the options right now, only firefox
 impl DriverOptions {
     /*
     We define Firefox options in this implementations
     */
-    pub const KEY: &'static str = "moz:firefoxOptions";
+    pub const KEY: &str = "moz:firefoxOptions";
 
     pub fn new() -> Self {
         Self {
+            binary: None,
             profile: None,
             args: Some(Vec::new()),
             prefs: Some(Dict::new()),
@@ -77,6 +466,15 @@ impl DriverOptions {
         }
     }
 
+    /// Path to the Firefox executable, see `package::discover_firefox`
+    pub fn binary(&mut self, v: &str) {
+        self.binary = Some(v.to_owned())
+    }
+
+    pub fn binary_path(&self) -> Option<&str> {
+        self.binary.as_deref()
+    }
+
     pub fn profile(&mut self, v: &str) {
         self.profile = Some(v.to_owned())
     }
@@ -86,7 +484,7 @@ impl DriverOptions {
     }
 
     pub fn arg(&mut self, v: &str) {
-        self.args.as_mut().unwrap().push(v.to_owned())
+        self.args.get_or_insert_with(Vec::new).push(v.to_owned())
     }
 
     pub fn prefs(&mut self, val: &HashMap<&str, &str>) {
@@ -94,7 +492,23 @@ impl DriverOptions {
     }
 
     pub fn pref(&mut self, val: (&str, &str)) {
-        self.prefs.as_mut().unwrap().insert(val.0, val.1)
+        self.prefs
+            .get_or_insert_with(Dict::new)
+            .insert(val.0, val.1)
+    }
+
+    /// Set a typed preference, e.g. `set_pref("dom.ipc.processCount", 8)`
+    pub fn set_pref<V: Into<prefs::PrefValue>>(&mut self, name: &str, value: V) {
+        self.prefs
+            .get_or_insert_with(Dict::new)
+            .insert(name, value.into())
+    }
+
+    /// Layer a preference preset, prefs set afterwards override it
+    pub fn preset(&mut self, preset: prefs::PrefPreset) {
+        for (name, value) in preset.prefs() {
+            self.set_pref(name, value)
+        }
     }
 
     pub fn logs(&mut self, val: &HashMap<&str, &str>) {
@@ -102,39 +516,62 @@ impl DriverOptions {
     }
 
     pub fn log(&mut self, val: (&str, &str)) {
-        self.log.as_mut().unwrap().insert(val.0, val.1)
+        self.log.get_or_insert_with(Dict::new).insert(val.0, val.1)
+    }
+
+    pub fn remove_arg(&mut self, v: &str) {
+        if let Some(args) = self.args.as_mut() {
+            args.retain(|arg| arg != v)
+        }
+    }
+
+    /// Merge other options over these, args are appended without duplicates
+    pub fn extend(&mut self, other: DriverOptions) {
+        if other.binary.is_some() {
+            self.binary = other.binary;
+        }
+        if other.profile.is_some() {
+            self.profile = other.profile;
+        }
+        let args = self.args.get_or_insert_with(Vec::new);
+        for arg in other.args.unwrap_or_default() {
+            if !args.contains(&arg) {
+                args.push(arg);
+            }
+        }
+        if let Some(prefs) = other.prefs {
+            self.prefs.get_or_insert_with(Dict::new).extend(prefs);
+        }
+        if let Some(log) = other.log {
+            self.log.get_or_insert_with(Dict::new).extend(log);
+        }
     }
 
-    pub fn insert(&mut self, option_type: &str, option: (&str, &str)) {
+    /// Insert an option by its type, see `option_types`
+    pub fn insert(&mut self, option_type: &str, option: (&str, &str)) -> Result<(), GeckError> {
         match option_type {
             "--arg" => self.arg(option.0),
+            "--binary" => self.binary(option.0),
             "--pref" => self.pref(option),
             "--log" => self.log(option),
-            _ => panic!("Non existing driver option"),
+            _ => {
+                return Err(capabilities_error(&format!(
+                    "Non existing driver option {:?}, expected one of {:?}",
+                    option_type,
+                    Self::option_types()
+                )))
+            }
         }
+        Ok(())
     }
 
     pub fn option_types() -> Vec<&'static str> {
-        vec!["--arg", "--pref", "--log"]
+        vec!["--arg", "--binary", "--pref", "--log"]
     }
 
     pub fn to_capabilities(&self) -> Result<Capabilities, GeckError> {
-        // TODO We do a lot of copies here
         let mut cap = Capabilities::new();
-        let mut option_dict = Dict::new();
-        if let Some(opt) = self.profile.borrow() {
-            option_dict.insert("profile", opt.clone());
-        }
-        if let Some(args) = self.args.borrow() {
-            option_dict.insert::<Vec<String>>("args", args.clone().into());
-        }
-        if let Some(logs) = self.log.borrow() {
-            option_dict.insert::<Dict>("log", logs.clone().into());
-        }
-        if let Some(prefs) = self.prefs.borrow() {
-            option_dict.insert::<Dict>("prefs", prefs.clone().into());
-        }
-        cap.insert::<Dict>("alwaysMatch", "moz:firefoxOptions", option_dict.into());
+        cap.always_match.firefox_options = Some(self.clone());
         Ok(cap)
     }
 }
@@ -142,10 +579,8 @@ impl DriverOptions {
 #[cfg(test)]
 mod tests {
 
-    use super::Capabilities;
     use super::*;
     use assert_json_diff::assert_json_include;
-    use serde_json;
 
     #[test]
     fn test_options() {
@@ -156,15 +591,162 @@ mod tests {
         options.prefs(&prefs);
     }
 
+    #[test]
+    fn test_typed_prefs() {
+        let mut options = DriverOptions::new();
+        options.preset(prefs::PrefPreset::Performance);
+        options.preset(prefs::PrefPreset::Stealth);
+        options.set_pref("media.autoplay.default", 0);
+        options.set_pref("dom.ipc.processCount", 8);
+        options.set_pref("browser.startup.homepage", "https://example.com");
+        options.pref(("intl.accept_languages", "de-DE"));
+        let actual: serde_json::Value =
+            serde_json::to_value(options.to_capabilities().unwrap()).unwrap();
+        let prefs = &actual["alwaysMatch"]["moz:firefoxOptions"]["prefs"];
+        assert_eq!(prefs["app.update.auto"], false);
+        assert_eq!(prefs["dom.webdriver.enabled"], false);
+        assert_eq!(prefs["media.autoplay.default"], 0);
+        assert_eq!(prefs["dom.ipc.processCount"], 8);
+        assert_eq!(prefs["browser.startup.homepage"], "https://example.com");
+        assert_eq!(prefs["intl.accept_languages"], "de-DE");
+        assert!(prefs.get("toolkit.telemetry.enabled").is_none());
+    }
+
+    #[test]
+    fn test_binary() {
+        let mut options = DriverOptions::new();
+        options
+            .insert("--binary", ("/usr/bin/firefox-esr", ""))
+            .unwrap();
+        assert!(options.insert("--profile", ("/tmp/profile", "")).is_err());
+        let actual: serde_json::Value =
+            serde_json::to_value(options.to_capabilities().unwrap()).unwrap();
+        assert_eq!(
+            actual["alwaysMatch"]["moz:firefoxOptions"]["binary"],
+            "/usr/bin/firefox-esr"
+        );
+    }
+
     #[test]
     fn test_serialize() {
         let capabilities = capabilities::DesiredCapabilities::FIREFOX.values();
-        let actual: serde_json::Value = serde_json::from_str(
-            r#"{"moz::debuggerAddress":true, "acceptInsecureCerts":true,"browserName":"firefox"}"#,
+        let expected: serde_json::Value = serde_json::from_str(
+            r#"{"alwaysMatch": {"moz:debuggerAddress":true, "acceptInsecureCerts":true,"browserName":"firefox"}}"#,
         )
         .unwrap();
-        let expected: serde_json::Value =
+        let actual: serde_json::Value =
             serde_json::from_str(&serde_json::to_string(&capabilities).unwrap()).unwrap();
-        //assert_json_include!(expected: expected , actual: actual)
+        assert_json_include!(actual: actual, expected: expected)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut capabilities = capabilities::DesiredCapabilities::FIREFOX.values();
+        capabilities.always_match.page_load_strategy = Some(PageLoadStrategy::Eager);
+        capabilities.always_match.unhandled_prompt_behavior =
+            Some(UnhandledPromptBehavior::DismissAndNotify);
+        capabilities.always_match.timeouts = Some(Timeouts {
+            implicit: Some(0),
+            page_load: Some(30000),
+            script: None,
+        });
+        capabilities.always_match.strict_file_interactability = Some(true);
+        capabilities.always_match.web_socket_url = Some(true);
+        capabilities.always_match.firefox_options = Some(DriverOptions::new());
+        capabilities
+            .always_match
+            .extension("moz:useNonSpecCompliantPointerOrigin", false)
+            .unwrap();
+        let mut first = CapabilitySet::new();
+        first.platform_name = Some("linux".to_owned());
+        capabilities
+            .first_match(first)
+            .first_match(CapabilitySet::new());
+
+        let serialized = serde_json::to_string(&capabilities).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(value["alwaysMatch"]["pageLoadStrategy"], "eager");
+        assert_eq!(
+            value["alwaysMatch"]["unhandledPromptBehavior"],
+            "dismiss and notify"
+        );
+        assert_eq!(
+            value["alwaysMatch"]["timeouts"],
+            serde_json::json!({"implicit": 0, "pageLoad": 30000})
+        );
+        assert_eq!(
+            value["alwaysMatch"]["moz:useNonSpecCompliantPointerOrigin"],
+            false
+        );
+        assert_eq!(
+            value["firstMatch"],
+            serde_json::json!([{"platformName": "linux"}, {}])
+        );
+
+        let parsed: Capabilities = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(parsed, capabilities);
+        assert!(parsed.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut capabilities = Capabilities::new();
+        capabilities.always_match.page_load_strategy = Some(PageLoadStrategy::Normal);
+        let mut first = CapabilitySet::new();
+        first.page_load_strategy = Some(PageLoadStrategy::None);
+        capabilities.first_match(first);
+        assert!(capabilities.validate().is_err());
+
+        let mut capabilities = Capabilities::new();
+        capabilities.always_match.browser_name = Some("chrome".to_owned());
+        assert!(capabilities.to_session_request().is_err());
+
+        let mut capabilities = Capabilities::new();
+        capabilities.always_match.proxy = Some(Proxy::manual());
+        assert!(capabilities.validate().is_err());
+        capabilities.always_match.proxy = Some(Proxy::manual().socks("127.0.0.1:1080", 5));
+        assert!(capabilities.validate().is_ok());
+
+        assert!(CapabilitySet::new().extension("unknown", true).is_err());
+        let parsed: Capabilities =
+            serde_json::from_str(r#"{"alwaysMatch": {"unknown": true}}"#).unwrap();
+        assert!(parsed.validate().is_err());
+        assert!(serde_json::from_str::<Capabilities>(
+            r#"{"alwaysMatch": {"pageLoadStrategy": "fast"}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_extend() {
+        let mut capabilities = capabilities::DesiredCapabilities::FIREFOX.values();
+        let mut other = Capabilities::new();
+        other.always_match.accept_insecure_certs = Some(false);
+        other.always_match.web_socket_url = Some(true);
+        capabilities.extend(other);
+        assert_eq!(
+            capabilities.always_match.browser_name.as_deref(),
+            Some("firefox")
+        );
+        assert_eq!(capabilities.always_match.accept_insecure_certs, Some(false));
+        assert_eq!(capabilities.always_match.web_socket_url, Some(true));
+        let request: serde_json::Value =
+            serde_json::from_str(&capabilities.to_session_request().unwrap()).unwrap();
+        assert_eq!(request["capabilities"]["alwaysMatch"]["webSocketUrl"], true);
+    }
+
+    #[test]
+    fn test_json_types() {
+        let value = serde_json::json!({
+            "prefs": {"layout.css.devPixelsPerPx": 1.5, "general.useragent.override": null},
+            "log": {"level": "trace"}
+        });
+        let options: DriverOptions = serde_json::from_value(value.clone()).unwrap();
+        let mut capabilities = Capabilities::new();
+        capabilities.always_match.firefox_options = Some(options);
+        let serialized = serde_json::to_value(&capabilities).unwrap();
+        assert_eq!(serialized["alwaysMatch"]["moz:firefoxOptions"], value);
+        let parsed: Capabilities = serde_json::from_value(serialized).unwrap();
+        assert_eq!(parsed, capabilities);
     }
 }
@@ -1,18 +1,1048 @@
+use flate2::read::GzDecoder;
+use log::*;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::fmt;
+use std::fs;
 use std::io;
-use std::fs::File;
-use tokio::process::Command;
-use log::info;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
+use crate::utils::error::{ErrorKind, GeckError};
+
+/// Geckodriver executable name looked up in PATH and the cache
+pub const EXECUTABLE: &str = "geckodriver";
+/// Environment variable pointing to a geckodriver executable
+pub const ENV_EXECUTABLE: &str = "GECKODRIVER";
+
+/// Minimum Firefox version required by each geckodriver release.
+/// Taken from https://firefox-source-docs.mozilla.org/testing/geckodriver/Support.html
+pub const COMPATIBILITY: &[((u32, u32, u32), u32)] = &[
+    ((0, 35, 0), 115),
+    ((0, 34, 0), 115),
+    ((0, 33, 0), 102),
+    ((0, 32, 0), 102),
+    ((0, 31, 0), 91),
+    ((0, 30, 0), 78),
+    ((0, 29, 0), 60),
+    ((0, 26, 0), 60),
+    ((0, 21, 0), 57),
+    ((0, 20, 1), 55),
+];
+
+/// A semantic version as reported by `geckodriver --version` or `firefox --version`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    pub fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Parses `0.34.0`, `128.0` or `115.3.1esr` like version strings
+    pub fn parse(s: &str) -> Result<Self, GeckError> {
+        let mut parts = s.trim().split('.').map(|p| {
+            p.chars()
+                .take_while(|c| c.is_ascii_digit())
+                .collect::<String>()
+                .parse::<u32>()
+        });
+        let mut next = || parts.next().unwrap_or(Ok(0));
+        match (next(), next(), next()) {
+            (Ok(major), Ok(minor), Ok(patch)) => Ok(Self::new(major, minor, patch)),
+            _ => Err(GeckError::new(
+                ErrorKind::Package,
+                None::<GeckError>,
+                &format!("Cannot parse version from {:?}", s),
+            )),
+        }
+    }
+
+    /// Finds the first version looking token in a `--version` output line,
+    /// e.g. `geckodriver 0.34.0 (c44f0d09630a 2024-01-02 15:36 +0000)`
+    pub fn from_output(output: &str) -> Result<Self, GeckError> {
+        output
+            .split_whitespace()
+            .find(|token| token.starts_with(|c: char| c.is_ascii_digit()) && token.contains('.'))
+            .map(Self::parse)
+            .unwrap_or_else(|| {
+                Err(GeckError::new(
+                    ErrorKind::Package,
+                    None::<GeckError>,
+                    &format!("No version found in {:?}", output.trim()),
+                ))
+            })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Returns the minimum Firefox major version supported by the given geckodriver
+pub fn min_firefox_version(geckodriver: &Version) -> Option<u32> {
+    COMPATIBILITY
+        .iter()
+        .find(|((major, minor, patch), _)| *geckodriver >= Version::new(*major, *minor, *patch))
+        .map(|(_, firefox)| *firefox)
+}
+
+/// Checks the Firefox/geckodriver pair against the compatibility matrix
+pub fn check_compatibility(geckodriver: &Version, firefox: &Version) -> Result<(), GeckError> {
+    match min_firefox_version(geckodriver) {
+        Some(min) if firefox.major >= min => Ok(()),
+        Some(min) => Err(GeckError::new(
+            ErrorKind::Package,
+            None::<GeckError>,
+            &format!(
+                "geckodriver {} requires Firefox {} or newer, found Firefox {}",
+                geckodriver, min, firefox
+            ),
+        )),
+        None => Err(GeckError::new(
+            ErrorKind::Package,
+            None::<GeckError>,
+            &format!("geckodriver {} is too old to be supported", geckodriver),
+        )),
+    }
+}
+
+/// Runs `<executable> --version` and returns the first line of its output
+pub fn version_output(path: &Path) -> Result<String, GeckError> {
+    let output = Command::new(path).arg("--version").output().map_err(|e| {
+        GeckError::new(
+            ErrorKind::Package,
+            Some(e),
+            &format!("Cannot execute {}", path.display()),
+        )
+    })?;
+    if !output.status.success() {
+        return Err(GeckError::new(
+            ErrorKind::Package,
+            None::<GeckError>,
+            &format!("{} --version exited with {}", path.display(), output.status),
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.lines().next().unwrap_or_default().to_owned())
+}
+
+/// Runs `<executable> --version` and parses the version out of its output
+pub fn executable_version(path: &Path) -> Result<Version, GeckError> {
+    Version::from_output(&version_output(path)?)
+}
+
+/// Markers rewritten in patched geckodriver copies by default. Only the name of the
+/// `dom.webdriver.enabled` pref is covered, so the profiles geckodriver writes do not set it.
+/// This does not hide Marionette: Firefox still reports `navigator.webdriver` while it is
+/// remote controlled. Replacements keep the marker length so offsets inside the binary
+/// stay intact.
+pub const DEFAULT_MARKERS: &[(&str, &str)] = &[("dom.webdriver.enabled", "dom.webdrivex.enabled")];
+
+/// Replaces every occurrence of the markers in place, returns the number of replacements
+pub fn patch_markers(bytes: &mut [u8], markers: &[(Vec<u8>, Vec<u8>)]) -> usize {
+    let mut count = 0;
+    for (from, to) in markers.iter().filter(|(from, _)| !from.is_empty()) {
+        let mut i = 0;
+        while i + from.len() <= bytes.len() {
+            if bytes[i..i + from.len()] == from[..] {
+                bytes[i..i + from.len()].copy_from_slice(to);
+                count += 1;
+                i += from.len();
+            } else {
+                i += 1;
+            }
+        }
+    }
+    count
+}
+
+/// Hex encoded sha256 digest of a marker set, tells patched copies apart
+pub fn markers_sha256(markers: &[(Vec<u8>, Vec<u8>)]) -> String {
+    let mut hasher = Sha256::new();
+    for (from, to) in markers {
+        for bytes in [from, to] {
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(bytes);
+        }
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Release asset name for the current platform, e.g. `geckodriver-v0.34.0-linux64.tar.gz`
+pub fn platform_asset(version: &Version) -> Result<String, GeckError> {
+    let platform = match (env::consts::OS, env::consts::ARCH) {
+        ("linux", "x86_64") => "linux64",
+        ("linux", "x86") => "linux32",
+        ("linux", "aarch64") => "linux-aarch64",
+        ("macos", "x86_64") => "macos",
+        ("macos", "aarch64") => "macos-aarch64",
+        (os, arch) => {
+            return Err(GeckError::new(
+                ErrorKind::Package,
+                None::<GeckError>,
+                &format!("No tar.gz geckodriver release for {} {}", os, arch),
+            ))
+        }
+    };
+    Ok(format!("geckodriver-v{}-{}.tar.gz", version, platform))
+}
+
+/// Hex encoded sha256 digest of a file
+pub fn sha256_file(path: &Path) -> Result<String, GeckError> {
+    let mut file = fs::File::open(path).map_err(|e| {
+        GeckError::new(
+            ErrorKind::Package,
+            Some(e),
+            &format!("Cannot open {}", path.display()),
+        )
+    })?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).map_err(|e| {
+        GeckError::new(
+            ErrorKind::Package,
+            Some(e),
+            &format!("Cannot read {}", path.display()),
+        )
+    })?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Compares a file against an expected sha256, accepts `sha256sum` formatted lines
+pub fn verify_sha256(path: &Path, expected: &str) -> Result<(), GeckError> {
+    let expected = expected
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let actual = sha256_file(path)?;
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(GeckError::new(
+            ErrorKind::Package,
+            None::<GeckError>,
+            &format!(
+                "Checksum mismatch for {}: expected {}, got {}",
+                path.display(),
+                expected,
+                actual
+            ),
+        ))
+    }
+}
+
+/// Default cache directory, `$XDG_CACHE_HOME/ungeckit` or `$HOME/.cache/ungeckit`
+pub fn default_cache_dir() -> PathBuf {
+    match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("ungeckit"),
+        _ => env::var_os("HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(env::temp_dir)
+            .join(".cache")
+            .join("ungeckit"),
+    }
+}
+
+/// Firefox release channels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Release,
+    Esr,
+    Beta,
+    DevEdition,
+    Nightly,
+}
+
+impl Channel {
+    /// Guesses the channel from the `--version` output and the binary path,
+    /// e.g. `Mozilla Firefox 128.0esr` or `/usr/lib/firefox-nightly/firefox`
+    pub fn detect(output: &str, path: &Path) -> Self {
+        let path = path.to_string_lossy().to_ascii_lowercase();
+        let version = output
+            .split_whitespace()
+            .find(|token| token.starts_with(|c: char| c.is_ascii_digit()))
+            .unwrap_or_default();
+        if version.ends_with("esr") || path.contains("esr") {
+            Channel::Esr
+        } else if version.contains('a') || path.contains("nightly") {
+            Channel::Nightly
+        } else if path.contains("developer") || path.contains("devedition") {
+            Channel::DevEdition
+        } else if version.contains('b') || path.contains("beta") {
+            Channel::Beta
+        } else {
+            Channel::Release
+        }
+    }
+}
+
+/// An installed Firefox build
+#[derive(Debug, Clone, PartialEq)]
+pub struct FirefoxBuild {
+    pub path: PathBuf,
+    pub version: Version,
+    pub channel: Channel,
+}
+
+impl FirefoxBuild {
+    /// Inspects a Firefox executable by running `--version`
+    pub fn from_path(path: &Path) -> Result<Self, GeckError> {
+        let output = version_output(path)?;
+        Ok(Self {
+            path: path.to_owned(),
+            version: Version::from_output(&output)?,
+            channel: Channel::detect(&output, path),
+        })
+    }
+}
+
+/// Executable names of the Firefox channels looked up in PATH
+pub const FIREFOX_EXECUTABLES: &[&str] = &[
+    "firefox",
+    "firefox-esr",
+    "firefox-beta",
+    "firefox-developer-edition",
+    "firefox-nightly",
+];
+
+/// Well known Firefox install locations on Linux
+pub fn firefox_candidates() -> Vec<PathBuf> {
+    let mut candidates: Vec<PathBuf> = FIREFOX_EXECUTABLES
+        .iter()
+        .filter_map(|name| PackageManager::find_in_path(name))
+        .collect();
+    for root in ["/usr/lib", "/usr/lib64", "/opt"] {
+        if let Ok(entries) = fs::read_dir(root) {
+            let mut dirs: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_name().to_string_lossy().starts_with("firefox"))
+                .map(|entry| entry.path().join("firefox"))
+                .collect();
+            dirs.sort();
+            candidates.extend(dirs);
+        }
+    }
+    candidates.push(PathBuf::from("/snap/bin/firefox"));
+    candidates.push(PathBuf::from(
+        "/var/lib/flatpak/exports/bin/org.mozilla.firefox",
+    ));
+    if let Some(home) = env::var_os("HOME") {
+        candidates
+            .push(PathBuf::from(home).join(".local/share/flatpak/exports/bin/org.mozilla.firefox"));
+    }
+    candidates
+}
+
+/// Finds the installed Firefox builds among the candidates, skipping duplicates
+pub fn discover_firefox_in(candidates: &[PathBuf]) -> Vec<FirefoxBuild> {
+    let mut seen = Vec::new();
+    let mut builds = Vec::new();
+    for path in candidates.iter().filter(|path| path.is_file()) {
+        let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.clone());
+        if seen.contains(&canonical) {
+            continue;
+        }
+        seen.push(canonical);
+        match FirefoxBuild::from_path(path) {
+            Ok(build) => {
+                debug!(
+                    "Found Firefox {} ({:?}) at {}",
+                    build.version,
+                    build.channel,
+                    path.display()
+                );
+                builds.push(build)
+            }
+            Err(e) => warn!("Skipping {}: {}", path.display(), e),
+        }
+    }
+    builds
+}
+
+/// Finds the installed Firefox builds on this machine
+pub fn discover_firefox() -> Vec<FirefoxBuild> {
+    discover_firefox_in(&firefox_candidates())
+}
+
+/// Picks the newest build matching the channel and minimum version
+pub fn select_firefox(
+    builds: &[FirefoxBuild],
+    channel: Option<Channel>,
+    min_version: Option<Version>,
+) -> Result<FirefoxBuild, GeckError> {
+    builds
+        .iter()
+        .filter(|build| channel.is_none_or(|channel| build.channel == channel))
+        .filter(|build| min_version.is_none_or(|min| build.version >= min))
+        .max_by_key(|build| build.version)
+        .cloned()
+        .ok_or_else(|| {
+            GeckError::new(
+                ErrorKind::Package,
+                None::<GeckError>,
+                &format!(
+                    "No Firefox build found for channel {:?} and minimum version {:?}, found {:?}",
+                    channel,
+                    min_version.map(|v| v.to_string()),
+                    builds
+                        .iter()
+                        .map(|build| format!("{} {}", build.path.display(), build.version))
+                        .collect::<Vec<_>>()
+                ),
+            )
+        })
+}
+
+/// Locates, verifies and caches geckodriver binaries.
+///
+/// The executable is resolved in this order: the explicit path, the `GECKODRIVER`
+/// environment variable, PATH and finally the newest version in the cache directory.
 pub struct PackageManager {
     path_to_executable: Option<String>,
+    cache_dir: PathBuf,
+    version: Option<Version>,
+    patch: bool,
+    markers: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Default for PackageManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PackageManager {
-    pub fn new() -> Self{
-        let mut path = None;
-        
-        Self{
-            path_to_executable: path, 
+    pub fn new() -> Self {
+        Self {
+            path_to_executable: None,
+            cache_dir: default_cache_dir(),
+            version: None,
+            patch: false,
+            markers: DEFAULT_MARKERS
+                .iter()
+                .map(|(from, to)| (from.as_bytes().to_vec(), to.as_bytes().to_vec()))
+                .collect(),
+        }
+    }
+
+    /// Use an explicit geckodriver executable
+    pub fn executable(&mut self, path: &str) -> &mut Self {
+        self.path_to_executable = Some(path.to_owned());
+        self
+    }
+
+    /// Override the cache directory
+    pub fn cache_dir(&mut self, path: &str) -> &mut Self {
+        self.cache_dir = PathBuf::from(path);
+        self
+    }
+
+    /// Resolve to a patched copy of geckodriver with the markers rewritten, see `DEFAULT_MARKERS`
+    pub fn patch(&mut self, enabled: bool) -> &mut Self {
+        self.patch = enabled;
+        self
+    }
+
+    /// Add a marker to rewrite while patching, the replacement must have the same length
+    pub fn marker(&mut self, from: &[u8], to: &[u8]) -> Result<&mut Self, GeckError> {
+        if from.is_empty() || from.len() != to.len() {
+            return Err(GeckError::new(
+                ErrorKind::Package,
+                None::<GeckError>,
+                "Marker and replacement must be non empty and have the same length",
+            ));
+        }
+        self.markers.push((from.to_vec(), to.to_vec()));
+        Ok(self)
+    }
+
+    /// Cached geckodriver binaries live in `<cache_dir>/geckodriver/<version>/geckodriver`
+    pub fn cache_path(&self) -> PathBuf {
+        self.cache_dir.join(EXECUTABLE)
+    }
+
+    /// Version of the resolved executable, only available after `resolve`
+    pub fn version(&self) -> Option<&Version> {
+        self.version.as_ref()
+    }
+
+    /// Finds a geckodriver executable and validates its version
+    pub fn resolve(&mut self) -> Result<String, GeckError> {
+        let (origin, path) = self.locate()?;
+        let version = executable_version(&path)?;
+        if min_firefox_version(&version).is_none() {
+            return Err(GeckError::new(
+                ErrorKind::Package,
+                None::<GeckError>,
+                &format!("geckodriver {} from {} is not supported", version, origin),
+            ));
+        }
+        info!(
+            "Using geckodriver {} from {} ({})",
+            version,
+            origin,
+            path.display()
+        );
+        self.version = Some(version);
+        let resolved = path.to_string_lossy().into_owned();
+        self.path_to_executable = Some(resolved.clone());
+        if self.patch {
+            let patched = self.patched_copy(&path, &version)?;
+            return Ok(patched.to_string_lossy().into_owned());
+        }
+        Ok(resolved)
+    }
+
+    /// Writes a patched copy into `<cache_dir>/geckodriver/<version>/patched/geckodriver`.
+    /// The sha256 of the source binary, of the markers and of the copy are recorded next to
+    /// it, the copy is reused while all of them match.
+    pub fn patched_copy(&self, path: &Path, version: &Version) -> Result<PathBuf, GeckError> {
+        let dir = self.cache_path().join(version.to_string()).join("patched");
+        let patched = dir.join(EXECUTABLE);
+        let checksum = dir.join(format!("{}.sha256", EXECUTABLE));
+        let key = format!(
+            "source {}\nmarkers {}\n",
+            sha256_file(path)?,
+            markers_sha256(&self.markers)
+        );
+        let record = fs::read_to_string(&checksum).unwrap_or_default();
+        if let Some(expected) = record
+            .strip_prefix(&key)
+            .and_then(|rest| rest.strip_prefix("patched "))
+        {
+            if verify_sha256(&patched, expected).is_ok() {
+                debug!("Reusing patched geckodriver {}", patched.display());
+                return Ok(patched);
+            }
+        }
+
+        let package_error = |e: io::Error| {
+            GeckError::new(
+                ErrorKind::Package,
+                Some(e),
+                &format!("Cannot patch {}", path.display()),
+            )
+        };
+        let mut bytes = fs::read(path).map_err(package_error)?;
+        let count = patch_markers(&mut bytes, &self.markers);
+        fs::create_dir_all(&dir).map_err(package_error)?;
+        fs::write(&patched, &bytes).map_err(package_error)?;
+        fs::set_permissions(&patched, fs::Permissions::from_mode(0o755)).map_err(package_error)?;
+        let record = format!("{}patched {}\n", key, sha256_file(&patched)?);
+        fs::write(&checksum, record).map_err(package_error)?;
+        info!("Patched {} markers into {}", count, patched.display());
+        Ok(patched)
+    }
+
+    /// Checks the resolved geckodriver against the given Firefox version
+    pub fn check_firefox(&self, firefox: &Version) -> Result<(), GeckError> {
+        match &self.version {
+            Some(version) => check_compatibility(version, firefox),
+            None => Err(GeckError::new(
+                ErrorKind::Package,
+                None::<GeckError>,
+                "geckodriver is not resolved yet",
+            )),
+        }
+    }
+
+    /// Installs geckodriver from a local `tar.gz` archive into the versioned cache.
+    /// The archive is verified first when a sha256 checksum is given.
+    pub fn install_archive(
+        &mut self,
+        archive: &str,
+        sha256: Option<&str>,
+    ) -> Result<PathBuf, GeckError> {
+        self.install(Path::new(archive), sha256, None)
+    }
+
+    /// Extracts geckodriver from the archive into the cache directory of the version it
+    /// reports, refusing a binary that is not the expected version
+    fn install(
+        &mut self,
+        archive: &Path,
+        sha256: Option<&str>,
+        expected: Option<&Version>,
+    ) -> Result<PathBuf, GeckError> {
+        if let Some(expected) = sha256 {
+            verify_sha256(archive, expected)?;
+        }
+        let package_error = |e: io::Error| {
+            GeckError::new(
+                ErrorKind::Package,
+                Some(e),
+                &format!("Cannot extract {}", archive.display()),
+            )
+        };
+
+        // Extract into a staging directory, the version is only known after running the binary
+        let staging = self
+            .cache_path()
+            .join(format!(".staging-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&staging);
+        fs::create_dir_all(&staging).map_err(package_error)?;
+        let staged = staging.join(EXECUTABLE);
+        let mut tarball = tar::Archive::new(GzDecoder::new(
+            fs::File::open(archive).map_err(package_error)?,
+        ));
+        let mut found = false;
+        for entry in tarball.entries().map_err(package_error)? {
+            let mut entry = entry.map_err(package_error)?;
+            if entry.path().map_err(package_error)?.file_name()
+                == Some(std::ffi::OsStr::new(EXECUTABLE))
+            {
+                entry.unpack(&staged).map_err(package_error)?;
+                found = true;
+                break;
+            }
+        }
+        if !found {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(GeckError::new(
+                ErrorKind::Package,
+                None::<GeckError>,
+                &format!("No {} found in {}", EXECUTABLE, archive.display()),
+            ));
+        }
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o755)).map_err(package_error)?;
+
+        let installed = executable_version(&staged).and_then(|version| {
+            if let Some(expected) = expected.filter(|expected| **expected != version) {
+                return Err(GeckError::new(
+                    ErrorKind::Package,
+                    None::<GeckError>,
+                    &format!(
+                        "{} contains geckodriver {}, expected {}",
+                        archive.display(),
+                        version,
+                        expected
+                    ),
+                ));
+            }
+            let dir = self.cache_path().join(version.to_string());
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).map_err(package_error)?;
+            let path = dir.join(EXECUTABLE);
+            fs::rename(&staged, &path).map_err(package_error)?;
+            info!("Installed geckodriver {} into {}", version, path.display());
+            Ok(path)
+        });
+        let _ = fs::remove_dir_all(&staging);
+        installed
+    }
+
+    /// Installs geckodriver from a mirror directory laid out like the release page,
+    /// `<mirror>/v<version>/geckodriver-v<version>-<platform>.tar.gz` with a `.sha256`
+    /// checksum file next to the archive. The extracted binary must report that version.
+    pub fn install_from_mirror(
+        &mut self,
+        mirror: &str,
+        version: &Version,
+    ) -> Result<PathBuf, GeckError> {
+        let asset = platform_asset(version)?;
+        let archive = Path::new(mirror).join(format!("v{}", version)).join(&asset);
+        let checksum_path = archive.with_file_name(format!("{}.sha256", asset));
+        let checksum = fs::read_to_string(&checksum_path).map_err(|e| {
+            GeckError::new(
+                ErrorKind::Package,
+                Some(e),
+                &format!("Cannot read checksum {}", checksum_path.display()),
+            )
+        })?;
+        self.install(&archive, Some(&checksum), Some(version))
+    }
+
+    fn locate(&self) -> Result<(&'static str, PathBuf), GeckError> {
+        if let Some(path) = &self.path_to_executable {
+            return Self::existing("explicit path", PathBuf::from(path));
+        }
+        if let Some(path) = env::var_os(ENV_EXECUTABLE).filter(|p| !p.is_empty()) {
+            return Self::existing(ENV_EXECUTABLE, PathBuf::from(path));
         }
+        if let Some(path) = Self::find_in_path(EXECUTABLE) {
+            return Ok(("PATH", path));
+        }
+        if let Some(path) = self.find_in_cache() {
+            return Ok(("cache", path));
+        }
+        Err(GeckError::new(
+            ErrorKind::Package,
+            None::<GeckError>,
+            &format!(
+                "Cannot find geckodriver: set {}, add it to PATH or install it into {}",
+                ENV_EXECUTABLE,
+                self.cache_path().display()
+            ),
+        ))
+    }
+
+    fn existing(origin: &'static str, path: PathBuf) -> Result<(&'static str, PathBuf), GeckError> {
+        if path.is_file() {
+            Ok((origin, path))
+        } else {
+            Err(GeckError::new(
+                ErrorKind::Package,
+                None::<GeckError>,
+                &format!(
+                    "geckodriver from {} does not exist at {}",
+                    origin,
+                    path.display()
+                ),
+            ))
+        }
+    }
+
+    /// Looks up an executable in PATH
+    pub fn find_in_path(name: &str) -> Option<PathBuf> {
+        env::var_os("PATH").and_then(|paths| {
+            env::split_paths(&paths)
+                .map(|dir| dir.join(name))
+                .find(|path| path.is_file())
+        })
+    }
+
+    /// Picks the newest versioned geckodriver from the cache
+    pub fn find_in_cache(&self) -> Option<PathBuf> {
+        std::fs::read_dir(self.cache_path())
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let version = Version::parse(&entry.file_name().to_string_lossy()).ok()?;
+                let path = entry.path().join(EXECUTABLE);
+                path.is_file().then_some((version, path))
+            })
+            .max_by_key(|(version, _)| *version)
+            .map(|(_, path)| path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    fn fake_script(version: &str) -> String {
+        format!(
+            "#!/bin/sh\necho \"geckodriver {} (c44f0d09630a 2024-01-02 15:36 +0000)\"\n",
+            version
+        )
+    }
+
+    fn fake_geckodriver(dir: &Path, version: &str) -> PathBuf {
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join(EXECUTABLE);
+        fs::write(&path, fake_script(version)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    fn fake_firefox(dir: &Path, name: &str, version: &str) -> PathBuf {
+        fs::create_dir_all(dir).unwrap();
+        let path = dir.join(name);
+        fs::write(
+            &path,
+            format!("#!/bin/sh\necho \"Mozilla Firefox {}\"\n", version),
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    fn fake_archive(path: &Path, version: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let script = fake_script(version);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(script.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            fs::File::create(path).unwrap(),
+            Compression::default(),
+        ));
+        builder
+            .append_data(&mut header, EXECUTABLE, script.as_bytes())
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ungeckit-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_version_parse() {
+        assert_eq!(Version::parse("0.34.0").unwrap(), Version::new(0, 34, 0));
+        assert_eq!(Version::parse("128.0").unwrap(), Version::new(128, 0, 0));
+        assert_eq!(
+            Version::parse("115.3.1esr").unwrap(),
+            Version::new(115, 3, 1)
+        );
+        assert_eq!(
+            Version::from_output("Mozilla Firefox 128.0.3").unwrap(),
+            Version::new(128, 0, 3)
+        );
+        assert!(Version::from_output("geckodriver").is_err());
+    }
+
+    #[test]
+    fn test_compatibility() {
+        let geckodriver = Version::new(0, 34, 0);
+        assert!(check_compatibility(&geckodriver, &Version::new(128, 0, 0)).is_ok());
+        assert!(check_compatibility(&geckodriver, &Version::new(102, 0, 0)).is_err());
+        assert_eq!(min_firefox_version(&Version::new(0, 32, 2)), Some(102));
+        assert_eq!(min_firefox_version(&Version::new(0, 19, 0)), None);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = temp_dir("resolve");
+        let path = fake_geckodriver(&dir, "0.34.0");
+        let mut manager = PackageManager::new();
+        manager.executable(path.to_str().unwrap());
+        assert_eq!(manager.resolve().unwrap(), path.to_str().unwrap());
+        assert_eq!(manager.version(), Some(&Version::new(0, 34, 0)));
+        assert!(manager.check_firefox(&Version::new(115, 0, 0)).is_ok());
+
+        manager.executable(dir.join("missing").to_str().unwrap());
+        assert!(manager.resolve().is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_find_in_cache() {
+        let dir = temp_dir("cache");
+        fake_geckodriver(&dir.join(EXECUTABLE).join("0.33.0"), "0.33.0");
+        let newest = fake_geckodriver(&dir.join(EXECUTABLE).join("0.35.0"), "0.35.0");
+        let mut manager = PackageManager::new();
+        manager.cache_dir(dir.to_str().unwrap());
+        assert_eq!(manager.find_in_cache(), Some(newest));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_install_archive() {
+        let dir = temp_dir("archive");
+        let archive = dir.join("geckodriver.tar.gz");
+        fake_archive(&archive, "0.34.0");
+        let mut manager = PackageManager::new();
+        manager.cache_dir(dir.join("cache").to_str().unwrap());
+
+        let bad = "0".repeat(64);
+        assert!(manager
+            .install_archive(archive.to_str().unwrap(), Some(&bad))
+            .is_err());
+
+        let checksum = sha256_file(&archive).unwrap();
+        let path = manager
+            .install_archive(archive.to_str().unwrap(), Some(&checksum))
+            .unwrap();
+        assert_eq!(path, manager.cache_path().join("0.34.0").join(EXECUTABLE));
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o111,
+            0o111
+        );
+        assert_eq!(manager.find_in_cache(), Some(path));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_install_from_mirror() {
+        let dir = temp_dir("mirror");
+        let version = Version::new(0, 35, 0);
+        let asset = platform_asset(&version).unwrap();
+        let archive = dir.join("mirror").join("v0.35.0").join(&asset);
+        fake_archive(&archive, "0.35.0");
+        fs::write(
+            archive.with_file_name(format!("{}.sha256", asset)),
+            format!("{}  {}\n", sha256_file(&archive).unwrap(), asset),
+        )
+        .unwrap();
+
+        let mut manager = PackageManager::new();
+        manager.cache_dir(dir.join("cache").to_str().unwrap());
+        let path = manager
+            .install_from_mirror(dir.join("mirror").to_str().unwrap(), &version)
+            .unwrap();
+        assert_eq!(executable_version(&path).unwrap(), version);
+        assert!(manager
+            .install_from_mirror(
+                dir.join("mirror").to_str().unwrap(),
+                &Version::new(0, 33, 0)
+            )
+            .is_err());
+
+        // A mislabelled archive is refused and leaves the cache untouched
+        let version = Version::new(0, 36, 0);
+        let asset = platform_asset(&version).unwrap();
+        let archive = dir.join("mirror").join("v0.36.0").join(&asset);
+        fake_archive(&archive, "0.34.0");
+        fs::write(
+            archive.with_file_name(format!("{}.sha256", asset)),
+            sha256_file(&archive).unwrap(),
+        )
+        .unwrap();
+        let error = manager
+            .install_from_mirror(dir.join("mirror").to_str().unwrap(), &version)
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("contains geckodriver 0.34.0, expected 0.36.0"));
+        assert!(!manager.cache_path().join("0.34.0").exists());
+        assert!(!manager.cache_path().join("0.36.0").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_patch_markers() {
+        let mut bytes = b"xx$marker$yy$marker$".to_vec();
+        let markers = vec![(b"$marker$".to_vec(), b"$patchd$".to_vec())];
+        assert_eq!(patch_markers(&mut bytes, &markers), 2);
+        assert_eq!(bytes, b"xx$patchd$yy$patchd$");
+    }
+
+    #[test]
+    fn test_patched_copy() {
+        let dir = temp_dir("patch");
+        let path = fake_geckodriver(&dir.join("bin"), "0.34.0");
+        let mut fixture = fs::read(&path).unwrap();
+        fixture.extend_from_slice(b"\n# dom.webdriver.enabled marionette-automation\n");
+        fs::write(&path, &fixture).unwrap();
+
+        let mut manager = PackageManager::new();
+        manager
+            .cache_dir(dir.join("cache").to_str().unwrap())
+            .executable(path.to_str().unwrap())
+            .patch(true)
+            .marker(b"marionette-automation", b"xxxxxxxxxx-xxxxxxxxxx")
+            .unwrap();
+        assert!(manager.marker(b"short", b"longer").is_err());
+
+        let patched = PathBuf::from(manager.resolve().unwrap());
+        assert_eq!(
+            patched,
+            manager
+                .cache_path()
+                .join("0.34.0")
+                .join("patched")
+                .join(EXECUTABLE)
+        );
+        let bytes = fs::read(&patched).unwrap();
+        assert_eq!(bytes.len(), fixture.len());
+        assert!(!bytes.windows(21).any(|w| w == b"dom.webdriver.enabled"));
+        assert!(!bytes.windows(21).any(|w| w == b"marionette-automation"));
+        assert_eq!(
+            executable_version(&patched).unwrap(),
+            Version::new(0, 34, 0)
+        );
+
+        let record_path = patched.with_file_name("geckodriver.sha256");
+        let record = fs::read_to_string(&record_path).unwrap();
+        assert_eq!(
+            record,
+            format!(
+                "source {}\nmarkers {}\npatched {}\n",
+                sha256_file(&path).unwrap(),
+                markers_sha256(&manager.markers),
+                sha256_file(&patched).unwrap()
+            )
+        );
+        manager.resolve().unwrap();
+        assert_eq!(fs::read_to_string(&record_path).unwrap(), record);
+
+        // Other markers or another source binary invalidate the copy
+        manager.marker(b"# dom", b"# DOM").unwrap();
+        manager.resolve().unwrap();
+        let bytes = fs::read(&patched).unwrap();
+        assert!(bytes.windows(5).any(|w| w == b"# DOM"));
+        fixture.extend_from_slice(b"# rebuilt\n");
+        fs::write(&path, &fixture).unwrap();
+        manager.resolve().unwrap();
+        assert_eq!(fs::read(&patched).unwrap().len(), fixture.len());
+        assert_ne!(fs::read_to_string(&record_path).unwrap(), record);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_channel_detect() {
+        let path = Path::new("/usr/bin/firefox");
+        assert_eq!(
+            Channel::detect("Mozilla Firefox 128.0", path),
+            Channel::Release
+        );
+        assert_eq!(
+            Channel::detect("Mozilla Firefox 115.3.1esr", path),
+            Channel::Esr
+        );
+        assert_eq!(
+            Channel::detect("Mozilla Firefox 131.0a1", path),
+            Channel::Nightly
+        );
+        assert_eq!(
+            Channel::detect("Mozilla Firefox 130.0b5", path),
+            Channel::Beta
+        );
+        assert_eq!(
+            Channel::detect(
+                "Mozilla Firefox 130.0b5",
+                Path::new("/opt/firefox-developer-edition/firefox")
+            ),
+            Channel::DevEdition
+        );
+    }
+
+    #[test]
+    fn test_discover_firefox() {
+        let dir = temp_dir("firefox");
+        let release = fake_firefox(&dir.join("release"), "firefox", "128.0.3");
+        let esr = fake_firefox(&dir.join("esr"), "firefox-esr", "115.14.0esr");
+        let nightly = fake_firefox(&dir.join("nightly"), "firefox", "131.0a1");
+        let builds = discover_firefox_in(&[
+            release.clone(),
+            release.clone(),
+            esr.clone(),
+            nightly,
+            dir.join("missing"),
+        ]);
+        assert_eq!(builds.len(), 3);
+
+        let newest = select_firefox(&builds, None, None).unwrap();
+        assert_eq!(newest.channel, Channel::Nightly);
+        let selected = select_firefox(&builds, Some(Channel::Esr), None).unwrap();
+        assert_eq!(selected.path, esr);
+        assert_eq!(selected.version, Version::new(115, 14, 0));
+        let selected = select_firefox(
+            &builds,
+            Some(Channel::Release),
+            Some(Version::new(120, 0, 0)),
+        )
+        .unwrap();
+        assert_eq!(selected.path, release);
+        assert!(
+            select_firefox(&builds, Some(Channel::Esr), Some(Version::new(120, 0, 0))).is_err()
+        );
+        fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,676 @@
+/*
+Politeness controls for navigations: per host request rates, tabs per host, jitter and
+robots.txt rules supplied from local files
+ */
+use log::*;
+use reqwest::Url;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::cdp::CDP;
+use crate::utils::error::{ErrorKind, GeckError};
+
+/// Limits of the navigations to a host
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimits {
+    pub requests_per_second: Option<f64>,
+    /// Tabs showing the host at the same time
+    pub max_tabs: Option<usize>,
+    /// Up to this much random delay is added after each navigation
+    pub jitter: Option<Duration>,
+}
+
+impl RateLimits {
+    /// Minimum delay between two navigations
+    pub fn interval(&self) -> Option<Duration> {
+        self.requests_per_second
+            .filter(|rps| *rps > 0.0)
+            .map(|rps| Duration::from_secs_f64(1.0 / rps))
+    }
+}
+
+/// A `Disallow` or `Allow` rule of a robots.txt group
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+impl Rule {
+    /// Matches the start of the path, `*` matches any characters and `$` ends the path
+    fn matches(&self, path: &str) -> bool {
+        match self.pattern.strip_suffix('$') {
+            Some(pattern) => glob(pattern, path),
+            None => glob(&format!("{}*", self.pattern), path),
+        }
+    }
+}
+
+/// Whether the whole text matches the pattern, `*` matching any characters
+fn glob(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((head, tail)) => text.strip_prefix(head).is_some_and(|rest| {
+            (0..=rest.len())
+                .filter(|i| rest.is_char_boundary(*i))
+                .any(|i| glob(tail, &rest[i..]))
+        }),
+    }
+}
+
+/// The robots.txt rules applying to one user agent
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Robots {
+    rules: Vec<Rule>,
+    crawl_delay: Option<Duration>,
+}
+
+impl Robots {
+    /// Keeps the group of the user agent, the `*` group when no group names it
+    pub fn parse(text: &str, user_agent: &str) -> Self {
+        let user_agent = user_agent.to_lowercase();
+        let mut groups: Vec<(Vec<String>, Robots)> = Vec::new();
+        let mut in_agents = false;
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let (key, value) = (key.trim().to_lowercase(), value.trim());
+            match key.as_str() {
+                "user-agent" => {
+                    if !in_agents {
+                        groups.push((Vec::new(), Robots::default()));
+                    }
+                    in_agents = true;
+                    if let Some((agents, _)) = groups.last_mut() {
+                        agents.push(value.to_lowercase());
+                    }
+                    continue;
+                }
+                "allow" | "disallow" => {
+                    if let Some((_, robots)) = groups.last_mut() {
+                        // An empty disallow allows everything
+                        if !value.is_empty() {
+                            robots.rules.push(Rule {
+                                allow: key == "allow",
+                                pattern: value.to_owned(),
+                            });
+                        }
+                    }
+                }
+                "crawl-delay" => {
+                    if let (Some((_, robots)), Ok(delay)) =
+                        (groups.last_mut(), value.parse::<f64>())
+                    {
+                        robots.crawl_delay = Some(Duration::from_secs_f64(delay.max(0.0)));
+                    }
+                }
+                _ => {}
+            }
+            in_agents = false;
+        }
+        let named = groups.iter().find(|(agents, _)| {
+            agents
+                .iter()
+                .any(|agent| agent != "*" && user_agent.contains(agent.as_str()))
+        });
+        let any = || {
+            groups
+                .iter()
+                .find(|(agents, _)| agents.iter().any(|a| a == "*"))
+        };
+        named
+            .or_else(any)
+            .map(|(_, robots)| robots.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn from_file(path: impl AsRef<Path>, user_agent: &str) -> Result<Self, GeckError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            GeckError::new(
+                ErrorKind::Politeness,
+                Some(e),
+                &format!("Cannot read robots.txt {}", path.display()),
+            )
+        })?;
+        Ok(Self::parse(&text, user_agent))
+    }
+
+    /// Whether the url, or a path with its query, may be visited. The longest matching rule
+    /// wins, `Allow` wins a tie.
+    pub fn allowed(&self, url: &str) -> bool {
+        let path = match Url::parse(url) {
+            Ok(url) => match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_owned(),
+            },
+            Err(_) => url.to_owned(),
+        };
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(&path))
+            .max_by_key(|rule| (rule.pattern.len(), rule.allow))
+            .is_none_or(|rule| rule.allow)
+    }
+
+    pub fn crawl_delay(&self) -> Option<Duration> {
+        self.crawl_delay
+    }
+}
+
+#[derive(Default)]
+struct Host {
+    tabs: usize,
+    /// Earliest start of the next navigation
+    next: Option<Instant>,
+}
+
+#[derive(Default)]
+struct State {
+    hosts: HashMap<String, Host>,
+    /// Host shown by each tab
+    tabs: HashMap<String, String>,
+    /// Url each tab was last let through to, its navigation request is not limited twice
+    admitted: HashMap<String, String>,
+}
+
+/// Paces navigations per host. Tabs are identified by a key, usually their browsing
+/// context id, a tab counts against the host it shows until it leaves or navigates away.
+/// Cloning the limiter shares its state.
+#[derive(Clone)]
+pub struct RateLimiter {
+    defaults: RateLimits,
+    hosts: HashMap<String, RateLimits>,
+    robots: HashMap<String, Robots>,
+    state: Arc<Mutex<State>>,
+    changed: Arc<Notify>,
+}
+
+impl RateLimiter {
+    /// The limits apply to every host without its own limits
+    pub fn new(defaults: RateLimits) -> Self {
+        Self {
+            defaults,
+            hosts: HashMap::new(),
+            robots: HashMap::new(),
+            state: Arc::new(Mutex::new(State::default())),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn defaults(&mut self, limits: RateLimits) -> &mut Self {
+        self.defaults = limits;
+        self
+    }
+
+    /// Limits of a host, e.g. `example.com`
+    pub fn host(&mut self, host: &str, limits: RateLimits) -> &mut Self {
+        self.hosts.insert(host.to_lowercase(), limits);
+        self
+    }
+
+    /// robots.txt rules of a host, their crawl delay applies when it is longer than the rate
+    pub fn robots(&mut self, host: &str, robots: Robots) -> &mut Self {
+        self.robots.insert(host.to_lowercase(), robots);
+        self
+    }
+
+    pub fn limits(&self, host: &str) -> &RateLimits {
+        self.hosts.get(host).unwrap_or(&self.defaults)
+    }
+
+    /// Whether robots.txt allows the url, urls of hosts without rules are allowed
+    pub fn allowed(&self, url: &str) -> bool {
+        match Self::host_of(url) {
+            Some(host) => self
+                .robots
+                .get(&host)
+                .is_none_or(|robots| robots.allowed(url)),
+            None => true,
+        }
+    }
+
+    fn host_of(url: &str) -> Option<String> {
+        Url::parse(url)
+            .ok()?
+            .host_str()
+            .map(|host| host.to_lowercase())
+    }
+
+    /// The url as Firefox reports it in network events, e.g. with a path and a lowercase host
+    fn normalize(url: &str) -> String {
+        Url::parse(url).map_or_else(|_| url.to_owned(), |url| url.to_string())
+    }
+
+    /// Random delay up to `jitter`
+    fn jitter(jitter: Option<Duration>) -> Duration {
+        match jitter {
+            Some(jitter) if !jitter.is_zero() => {
+                let random = RandomState::new().build_hasher().finish();
+                jitter.mul_f64((random % 10_000) as f64 / 10_000.0)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Waits until the tab may navigate to the url, then counts the tab on the host of the
+    /// url. Fails when robots.txt disallows the url. Urls without a host are not limited.
+    pub async fn enter(&self, tab: &str, url: &str) -> Result<(), GeckError> {
+        let Some(host) = Self::host_of(url) else {
+            return Ok(());
+        };
+        if !self.allowed(url) {
+            return Err(GeckError::new(
+                ErrorKind::Politeness,
+                None::<GeckError>,
+                &format!("{} is disallowed by robots.txt", url),
+            ));
+        }
+        let limits = self.limits(&host).clone();
+        let crawl_delay = self
+            .robots
+            .get(&host)
+            .and_then(|robots| robots.crawl_delay());
+        let interval = limits.interval().max(crawl_delay);
+        loop {
+            let changed = self.changed.notified();
+            let start = {
+                let mut state = self.state.lock().unwrap();
+                let moving = state.tabs.get(tab) != Some(&host);
+                let full = limits
+                    .max_tabs
+                    .is_some_and(|max| state.hosts.get(&host).is_some_and(|h| h.tabs >= max));
+                if moving && full {
+                    None
+                } else {
+                    if moving {
+                        if let Some(previous) = state.tabs.insert(tab.to_owned(), host.clone()) {
+                            if let Some(previous) = state.hosts.get_mut(&previous) {
+                                previous.tabs -= 1;
+                            }
+                        }
+                    }
+                    let entry = state.hosts.entry(host.clone()).or_default();
+                    if moving {
+                        entry.tabs += 1;
+                    }
+                    let start = entry
+                        .next
+                        .map_or(Instant::now(), |next| next.max(Instant::now()));
+                    if let Some(interval) = interval {
+                        entry.next = Some(start + interval + Self::jitter(limits.jitter));
+                    } else if limits.jitter.is_some() {
+                        entry.next = Some(start + Self::jitter(limits.jitter));
+                    }
+                    state.admitted.insert(tab.to_owned(), Self::normalize(url));
+                    Some(start)
+                }
+            };
+            match start {
+                Some(start) => {
+                    // The tab may have left a host others wait for
+                    self.changed.notify_waiters();
+                    if start > Instant::now() {
+                        debug!("Delaying {} in {} until its host allows it", url, tab);
+                    }
+                    tokio::time::sleep_until(start).await;
+                    return Ok(());
+                }
+                None => changed.await,
+            }
+        }
+    }
+
+    /// The tab no longer shows its host, e.g. it was closed
+    pub fn leave(&self, tab: &str) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.admitted.remove(tab);
+            if let Some(host) = state.tabs.remove(tab) {
+                if let Some(host) = state.hosts.get_mut(&host) {
+                    host.tabs -= 1;
+                }
+            }
+        }
+        self.changed.notify_waiters();
+    }
+
+    /// Number of tabs showing the host
+    pub fn tabs(&self, host: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .hosts
+            .get(host)
+            .map_or(0, |host| host.tabs)
+    }
+
+    /// Whether the navigation request of the tab was let through by `enter` already
+    fn take_admitted(&self, tab: &str, url: &str) -> bool {
+        let url = Self::normalize(url);
+        let mut state = self.state.lock().unwrap();
+        if state
+            .admitted
+            .get(tab)
+            .is_some_and(|admitted| *admitted == url)
+        {
+            state.admitted.remove(tab);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Holds the navigation requests of the given top level contexts, such as link clicks,
+    /// until their host allows them and fails the ones robots.txt disallows. Other requests
+    /// of these contexts pass through, the requests of other contexts are not intercepted.
+    pub async fn intercept(
+        &self,
+        bidi: Arc<CDP>,
+        contexts: &[String],
+    ) -> Result<Intercept, GeckError> {
+        let mut events = bidi.events();
+        let subscription = bidi
+            .subscribe(&["network.beforeRequestSent"], contexts)
+            .await?;
+        let params = serde_json::json!({ "phases": ["beforeRequestSent"], "contexts": contexts });
+        let resp = bidi
+            .send("network.addIntercept", &params.to_string())
+            .await?;
+        let id = resp["result"]["intercept"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned();
+        let (intercept, limiter) = (id.clone(), self.clone());
+        let task = tokio::spawn(async move {
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Rate limiter missed {} network events", missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let params = &event["params"];
+                let ours = params["intercepts"]
+                    .as_array()
+                    .is_some_and(|ids| ids.iter().any(|id| id == intercept.as_str()));
+                if event["method"] != "network.beforeRequestSent" || !ours {
+                    continue;
+                }
+                let request = params["request"]["request"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_owned();
+                let url = params["request"]["url"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_owned();
+                let tab = params["context"].as_str().unwrap_or_default().to_owned();
+                let navigation = !params["navigation"].is_null()
+                    && params["redirectCount"].as_u64().unwrap_or(0) == 0;
+                let (limiter, bidi) = (limiter.clone(), bidi.clone());
+                tokio::spawn(async move {
+                    let admitted = !navigation || limiter.take_admitted(&tab, &url);
+                    let outcome = if admitted {
+                        Ok(())
+                    } else {
+                        limiter.enter(&tab, &url).await
+                    };
+                    let params = serde_json::json!({ "request": request });
+                    let sent = match outcome {
+                        Ok(()) => {
+                            bidi.send("network.continueRequest", &params.to_string())
+                                .await
+                        }
+                        Err(e) => {
+                            info!("Blocking navigation of {}: {}", tab, e);
+                            bidi.send("network.failRequest", &params.to_string()).await
+                        }
+                    };
+                    if let Err(e) = sent {
+                        warn!("Cannot resume request {}: {}", request, e);
+                    }
+                });
+            }
+        });
+        Ok(Intercept {
+            id,
+            subscription,
+            task,
+        })
+    }
+}
+
+/// The network intercept of a rate limiter and the task answering its requests
+pub struct Intercept {
+    id: String,
+    subscription: Option<String>,
+    task: JoinHandle<()>,
+}
+
+impl Intercept {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Removes the intercept from the browser, then stops answering its requests. The
+    /// requests held back at that point are still resumed.
+    pub async fn remove(self, bidi: &CDP) -> Result<(), GeckError> {
+        let removed = bidi.remove_intercept(&self.id).await;
+        if let Some(subscription) = &self.subscription {
+            if let Err(e) = bidi.unsubscribe(subscription).await {
+                warn!("Cannot end the subscription {}: {}", subscription, e);
+            }
+        }
+        self.task.abort();
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::testing::StandIn;
+    use serde_json::json;
+
+    const ROBOTS: &str = "
+# comments are ignored
+User-agent: ungeckit
+User-agent: other
+Disallow: /private
+Allow: /private/public
+Disallow: /*.pdf$
+Crawl-delay: 0.05
+
+User-agent: *
+Disallow: /
+";
+
+    #[test]
+    fn test_robots() {
+        let robots = Robots::parse(ROBOTS, "Mozilla/5.0 ungeckit/0.1");
+        assert!(robots.allowed("https://example.com/"));
+        assert!(!robots.allowed("https://example.com/private/data"));
+        assert!(robots.allowed("https://example.com/private/public/data"));
+        assert!(!robots.allowed("/docs/file.pdf"));
+        assert!(robots.allowed("/docs/file.pdf?page=2"));
+        assert_eq!(robots.crawl_delay(), Some(Duration::from_millis(50)));
+
+        let robots = Robots::parse(ROBOTS, "Firefox");
+        assert!(!robots.allowed("https://example.com/"));
+        assert!(Robots::parse("", "Firefox").allowed("/anything"));
+        assert!(Robots::parse("User-agent: *\nDisallow:\n", "Firefox").allowed("/anything"));
+    }
+
+    #[test]
+    fn test_patterns() {
+        let rule = |pattern: &str| Rule {
+            allow: false,
+            pattern: pattern.to_owned(),
+        };
+        assert!(rule("/a*c").matches("/abc"));
+        assert!(rule("/a*c").matches("/abcd"));
+        assert!(!rule("/a*c$").matches("/abcd"));
+        assert!(rule("/a*c$").matches("/abcbc"));
+        assert!(rule("/a$").matches("/a"));
+        assert!(!rule("/a$").matches("/ab"));
+        assert!(rule("/*").matches("/anything"));
+    }
+
+    #[tokio::test]
+    async fn test_rate() {
+        let mut limiter = RateLimiter::new(RateLimits {
+            requests_per_second: Some(20.0),
+            ..Default::default()
+        });
+        limiter.host("fast.test", RateLimits::default());
+        let start = Instant::now();
+        for tab in ["a", "b", "c"] {
+            limiter.enter(tab, "https://slow.test/").await.unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        let start = Instant::now();
+        for tab in ["a", "b", "c"] {
+            limiter.enter(tab, "https://fast.test/").await.unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+        assert_eq!(
+            (limiter.tabs("fast.test"), limiter.tabs("slow.test")),
+            (3, 0)
+        );
+        limiter.enter("blank", "about:blank").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tabs_and_robots() {
+        let mut limiter = RateLimiter::new(RateLimits {
+            max_tabs: Some(1),
+            jitter: Some(Duration::from_millis(5)),
+            ..Default::default()
+        });
+        limiter.robots("example.com", Robots::parse(ROBOTS, "ungeckit"));
+        let error = limiter
+            .enter("a", "https://example.com/private/x")
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Politeness);
+
+        limiter.enter("a", "https://example.com/").await.unwrap();
+        // The same tab navigates within the host freely
+        limiter.enter("a", "https://example.com/2").await.unwrap();
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.enter("b", "https://example.com/").await }
+        });
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert!(!waiting.is_finished());
+        limiter.enter("a", "https://other.test/").await.unwrap();
+        waiting.await.unwrap().unwrap();
+        assert_eq!(limiter.tabs("example.com"), 1);
+        limiter.leave("b");
+        assert_eq!(limiter.tabs("example.com"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_admitted_urls_are_normalized() {
+        let limiter = RateLimiter::new(RateLimits::default());
+        // Firefox reports the url with a path and a lowercase host
+        limiter.enter("a", "https://Example.com").await.unwrap();
+        assert!(limiter.take_admitted("a", "https://example.com/"));
+        assert!(!limiter.take_admitted("a", "https://example.com/"));
+        limiter.enter("b", "https://example.com/a b").await.unwrap();
+        assert!(limiter.take_admitted("b", "https://example.com/a%20b"));
+    }
+
+    #[tokio::test]
+    async fn test_driver_navigations() {
+        let browser = StandIn::start("rate-limit").await;
+        let driver = browser.driver().await.unwrap();
+        let mut limiter = RateLimiter::new(RateLimits {
+            requests_per_second: Some(20.0),
+            ..Default::default()
+        });
+        limiter.robots("example.com", Robots::parse(ROBOTS, "ungeckit"));
+        driver
+            .set_rate_limiter(Some(limiter.clone()))
+            .await
+            .unwrap();
+        // Windows are intercepted once they navigate under the limiter
+        assert!(browser.browser().intercepts.is_empty());
+
+        let start = Instant::now();
+        driver.get("https://example.com/1").await.unwrap();
+        assert_eq!(
+            browser.browser().intercepts,
+            vec![json!({ "phases": ["beforeRequestSent"], "contexts": ["default"] })]
+        );
+        driver.get("https://example.com/2").await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        let error = driver.get("https://example.com/private").await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Politeness);
+        assert_eq!(browser.browser().navigations.len(), 2);
+
+        // The navigation request of a `get` is let through, others are limited by the interception
+        let request = |id: &str, url: &str| {
+            json!({
+                "context": "default", "navigation": "nav", "redirectCount": 0, "intercepts": ["intercept-1"],
+                "request": { "request": id, "url": url },
+            })
+        };
+        browser.emit(
+            "network.beforeRequestSent",
+            request("r1", "https://example.com/2"),
+        );
+        browser.emit(
+            "network.beforeRequestSent",
+            request("r2", "https://example.com/private/link"),
+        );
+        browser.emit(
+            "network.beforeRequestSent",
+            request("r3", "https://other.test/"),
+        );
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while browser.browser().resumed.len() < 3 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        let mut resumed = browser.browser().resumed.clone();
+        resumed.sort_by(|a, b| a.1.cmp(&b.1));
+        let resumed: Vec<(&str, &str)> = resumed
+            .iter()
+            .map(|(a, b)| (a.as_str(), b.as_str()))
+            .collect();
+        assert_eq!(
+            resumed,
+            vec![
+                ("continueRequest", "r1"),
+                ("failRequest", "r2"),
+                ("continueRequest", "r3")
+            ]
+        );
+        assert_eq!(limiter.tabs("other.test"), 1);
+
+        // Replacing the limiter removes the intercept and its subscription
+        driver.set_rate_limiter(None).await.unwrap();
+        let state = browser.browser();
+        assert_eq!(state.removed_intercepts, vec!["intercept-1"]);
+        assert!(state
+            .unsubscriptions
+            .contains(&json!({ "subscriptions": ["sub-1"] })));
+    }
+}
@@ -1 +1,5 @@
+pub mod navigation;
+pub mod proxy;
+pub mod script;
 pub mod session;
+pub mod value;
@@ -0,0 +1,24 @@
+use serde::{Deserialize, Serialize};
+
+/// How far a page loads before a navigation returns, the BiDi `browsingContext.ReadinessState`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadinessState {
+    /// Returns once the navigation started
+    None,
+    /// The document is parsed, `DOMContentLoaded`
+    Interactive,
+    /// The document and its resources are loaded, `load`
+    #[default]
+    Complete,
+}
+
+/// A finished navigation, the BiDi `browsingContext.NavigateResult`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Navigation {
+    /// Id of the navigation in the BiDi events, none for same document navigations and
+    /// for the history commands of classic WebDriver
+    pub navigation: Option<String>,
+    /// The url after redirects
+    pub url: String,
+}
@@ -0,0 +1,195 @@
+use serde::de::{self, Deserializer};
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::value::RemoteValue;
+use crate::utils::error::{ErrorKind, GeckError, WebDriverError, WebDriverErrorCode};
+
+/// Key of W3C element references
+pub const ELEMENT_KEY: &str = "element-6066-11e4-a832-944a3d4ab4f2";
+
+/// A reference to a DOM element, passed to and returned from scripts.
+/// Firefox uses the same id for classic element references and BiDi shared ids.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Element {
+    pub id: String,
+}
+
+impl Serialize for Element {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(Some(1))?;
+        map.serialize_entry(ELEMENT_KEY, &self.id)?;
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Element {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let mut map = Map::deserialize(deserializer)?;
+        match map.remove(ELEMENT_KEY) {
+            Some(Value::String(id)) => Ok(Element { id }),
+            _ => Err(de::Error::custom(format!(
+                "not an element reference, no {:?}",
+                ELEMENT_KEY
+            ))),
+        }
+    }
+}
+
+/// Where a BiDi script runs, the `script.Target`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Target {
+    /// A realm from `script.getRealms`, e.g. of a worker
+    Realm { realm: String },
+    /// The window realm of a browsing context, or its named sandbox. Sandboxes are isolated
+    /// worlds sharing the DOM, page scripts can neither see their globals nor tamper with
+    /// the prototypes they use.
+    Context {
+        context: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sandbox: Option<String>,
+    },
+}
+
+impl Target {
+    pub fn context(context: &str, sandbox: Option<&str>) -> Self {
+        Target::Context {
+            context: context.to_owned(),
+            sandbox: sandbox.map(|s| s.to_owned()),
+        }
+    }
+
+    pub fn realm(realm: &str) -> Self {
+        Target::Realm {
+            realm: realm.to_owned(),
+        }
+    }
+}
+
+/// Kinds of realms, the BiDi `script.RealmType`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RealmType {
+    Window,
+    DedicatedWorker,
+    SharedWorker,
+    ServiceWorker,
+    Worker,
+    PaintWorklet,
+    AudioWorklet,
+    Worklet,
+}
+
+/// A realm of the browser, the BiDi `script.RealmInfo`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RealmInfo {
+    pub realm: String,
+    pub origin: String,
+    #[serde(rename = "type")]
+    pub realm_type: RealmType,
+    /// Browsing context of window realms
+    #[serde(default)]
+    pub context: Option<String>,
+    /// Name of the sandbox of sandboxed window realms
+    #[serde(default)]
+    pub sandbox: Option<String>,
+}
+
+/// The arguments of a script: sequences and tuples are spread, `()` passes none and any
+/// other value is the single argument
+pub fn script_args(args: impl Serialize) -> Result<Vec<Value>, GeckError> {
+    Ok(match serde_json::to_value(args)? {
+        Value::Array(args) => args,
+        Value::Null => Vec::new(),
+        arg => vec![arg],
+    })
+}
+
+/// The `script.EvaluateResult` of `script.evaluate` and `script.callFunction`, a thrown
+/// exception becomes a `javascript error`
+pub fn evaluate_result(result: &Value) -> Result<RemoteValue, GeckError> {
+    match result["type"].as_str() {
+        Some("success") => Ok(serde_json::from_value(result["result"].clone())?),
+        Some("exception") => {
+            let details = &result["exceptionDetails"];
+            let frames = details["stackTrace"]["callFrames"]
+                .as_array()
+                .map_or(&[][..], |f| f.as_slice());
+            Err(GeckError::webdriver(WebDriverError {
+                error: WebDriverErrorCode::JavascriptError,
+                message: details["text"].as_str().unwrap_or_default().to_owned(),
+                stacktrace: frames
+                    .iter()
+                    .map(|f| {
+                        let name = f["functionName"].as_str().unwrap_or_default();
+                        let url = f["url"].as_str().unwrap_or_default();
+                        format!("{}@{}:{}:{}", name, url, f["lineNumber"], f["columnNumber"])
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+                data: Some(details.clone()),
+            }))
+        }
+        _ => Err(GeckError::new(
+            ErrorKind::Driver,
+            None::<GeckError>,
+            &format!("Unexpected script result {:?}", result),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_values() {
+        let element = Element {
+            id: "e-1".to_owned(),
+        };
+        assert_eq!(
+            serde_json::to_value(&element).unwrap(),
+            json!({ ELEMENT_KEY: "e-1" })
+        );
+        let args = script_args((1, "a\"b", &element, json!({ "k": [true, null] }))).unwrap();
+        assert_eq!(script_args(()).unwrap(), Vec::<Value>::new());
+        assert_eq!(script_args("one").unwrap(), vec![json!("one")]);
+
+        assert_eq!(args[2], json!({ ELEMENT_KEY: "e-1" }));
+
+        assert_eq!(
+            serde_json::to_value(Target::context("c-1", Some("extract"))).unwrap(),
+            json!({"context": "c-1", "sandbox": "extract"})
+        );
+        assert_eq!(
+            serde_json::to_value(Target::realm("r-1")).unwrap(),
+            json!({"realm": "r-1"})
+        );
+        let realm: RealmInfo = serde_json::from_value(json!({
+            "realm": "r-2", "origin": "null", "type": "dedicated-worker", "owners": ["r-1"]}))
+        .unwrap();
+        assert_eq!(
+            (realm.realm_type, realm.context),
+            (RealmType::DedicatedWorker, None)
+        );
+
+        let thrown = json!({"type": "exception", "realm": "r", "exceptionDetails": {
+            "text": "Error: boom", "columnNumber": 1, "lineNumber": 0,
+            "exception": {"type": "error"}, "stackTrace": {"callFrames": []}}});
+        let error = evaluate_result(&thrown).unwrap_err();
+        assert!(error.is(&WebDriverErrorCode::JavascriptError));
+        assert_eq!(
+            error.to_string(),
+            "WebDriver Error: javascript error: Error: boom"
+        );
+    }
+}
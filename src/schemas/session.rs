@@ -1,8 +1,8 @@
 use bytes::Bytes;
-use log::*;
 use serde::{self, de, Deserialize, Serialize};
 
-use crate::utils::error::GeckError;
+pub use super::proxy::Proxy;
+use crate::utils::error::{ErrorKind, GeckError};
 
 pub struct SchemaParser {}
 
@@ -17,17 +17,19 @@ impl<T> TryParse<T> for SchemaParser {
     where
         T: de::DeserializeOwned,
     {
-        let b = body_bytes.to_vec();
-        let body_text = String::from_utf8_lossy(&b);
-        let d: T = serde_json::from_str(&body_text).unwrap();
-        Ok(d)
+        serde_json::from_slice(&body_bytes).map_err(|e| {
+            GeckError::new(
+                ErrorKind::Driver,
+                Some(e),
+                &format!(
+                    "Cannot parse response {}",
+                    String::from_utf8_lossy(&body_bytes)
+                ),
+            )
+        })
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Proxy {
-    // TODO implement
-}
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Timeouts {
     pub implicit: i64,
@@ -37,7 +39,7 @@ pub struct Timeouts {
 }
 
 pub fn none() -> String {
-    return "".to_owned();
+    "".to_owned()
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -84,7 +86,8 @@ pub struct Capabilities {
     pub unhandled_prompt_behavior: String,
     #[serde(alias = "userAgent")]
     pub user_agent: String,
-    #[serde(alias = "webSocketUrl")]
+    /// Empty when the session was created without `webSocketUrl`
+    #[serde(alias = "webSocketUrl", default = "none")]
     pub web_socket_url: String,
 }
 #[derive(Serialize, Deserialize, Debug)]
@@ -102,3 +105,16 @@ pub struct SessionResponse {
 pub struct Response<T> {
     pub value: Option<T>,
 }
+
+impl<T> Response<T> {
+    /// The value, an error when the remote end answered `null`
+    pub fn into_value(self) -> Result<T, GeckError> {
+        self.value.ok_or_else(|| {
+            GeckError::new(
+                ErrorKind::Driver,
+                None::<GeckError>,
+                "Response has no value",
+            )
+        })
+    }
+}
@@ -0,0 +1,640 @@
+/*
+BiDi script values: `RemoteValue` is what scripts return, `LocalValue` what they are called
+with. A `RemoteValue` is also a serde deserializer, so results map onto user types directly.
+ */
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{self, DeserializeOwned, Deserializer, IntoDeserializer, Visitor};
+use serde::ser::{SerializeMap, Serializer};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+use super::script::ELEMENT_KEY;
+use crate::utils::error::GeckError;
+
+/// Key of W3C window references
+pub const WINDOW_KEY: &str = "window-fcc6-11e5-b4f8-330a88ab9d7f";
+
+/// Integers up to this are exact in JavaScript numbers
+const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_992.0;
+
+/// Parses the `number` value of BiDi, non finite numbers and `-0` are strings
+fn number<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Value::deserialize(deserializer)? {
+        Value::Number(n) => n
+            .as_f64()
+            .ok_or_else(|| de::Error::custom("number out of range")),
+        Value::String(s) => match s.as_str() {
+            "NaN" => Ok(f64::NAN),
+            "-0" => Ok(-0.0),
+            "Infinity" => Ok(f64::INFINITY),
+            "-Infinity" => Ok(f64::NEG_INFINITY),
+            _ => Err(de::Error::custom(format!("unknown number {:?}", s))),
+        },
+        other => Err(de::Error::custom(format!("not a number {}", other))),
+    }
+}
+
+/// Key of an object or map entry, objects have string keys, maps any value
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum PropertyKey {
+    String(String),
+    Value(RemoteValue),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RegExpValue {
+    pub pattern: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flags: Option<String>,
+}
+
+/// Properties of a serialized DOM node, the BiDi `script.NodeProperties`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeProperties {
+    pub node_type: u32,
+    pub child_node_count: u32,
+    #[serde(default)]
+    pub attributes: Option<HashMap<String, String>>,
+    #[serde(default)]
+    pub children: Option<Vec<RemoteValue>>,
+    #[serde(default)]
+    pub local_name: Option<String>,
+    #[serde(default)]
+    pub namespace_uri: Option<String>,
+    #[serde(default)]
+    pub node_value: Option<String>,
+    #[serde(default)]
+    pub shadow_root: Option<Box<RemoteValue>>,
+}
+
+/// A value returned by a script, the BiDi `script.RemoteValue`.
+/// Objects keep a `handle` when the script asked to own them.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RemoteValue {
+    Undefined,
+    Null,
+    String {
+        value: String,
+    },
+    Number {
+        #[serde(deserialize_with = "number")]
+        value: f64,
+    },
+    Boolean {
+        value: bool,
+    },
+    /// Decimal digits of the big integer
+    BigInt {
+        value: String,
+    },
+    Symbol {
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    Array {
+        #[serde(default)]
+        value: Option<Vec<RemoteValue>>,
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    Object {
+        #[serde(default)]
+        value: Option<Vec<(PropertyKey, RemoteValue)>>,
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    Function {
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    RegExp {
+        value: RegExpValue,
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    /// ISO 8601 date
+    Date {
+        value: String,
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    Map {
+        #[serde(default)]
+        value: Option<Vec<(PropertyKey, RemoteValue)>>,
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    Set {
+        #[serde(default)]
+        value: Option<Vec<RemoteValue>>,
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    WeakMap {
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    WeakSet {
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    Generator {
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    Error {
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    Proxy {
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    Promise {
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    TypedArray {
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    ArrayBuffer {
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    NodeList {
+        #[serde(default)]
+        value: Option<Vec<RemoteValue>>,
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    HtmlCollection {
+        #[serde(default)]
+        value: Option<Vec<RemoteValue>>,
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    /// A DOM node, its shared id is also its element reference
+    Node {
+        #[serde(rename = "sharedId", default)]
+        shared_id: Option<String>,
+        #[serde(default)]
+        value: Option<NodeProperties>,
+        #[serde(default)]
+        handle: Option<String>,
+    },
+    /// A window proxy, `context` is its browsing context and window handle
+    Window {
+        value: WindowProxyProperties,
+        #[serde(default)]
+        handle: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WindowProxyProperties {
+    pub context: String,
+}
+
+impl RemoteValue {
+    /// Deserializes the value into a Rust type, see the `Deserializer` implementation
+    pub fn into_typed<T: DeserializeOwned>(self) -> Result<T, GeckError> {
+        Ok(T::deserialize(self)?)
+    }
+
+    /// Handle of the object in its realm, set when the script was called with
+    /// `resultOwnership: root`
+    pub fn handle(&self) -> Option<&str> {
+        match self {
+            RemoteValue::Symbol { handle }
+            | RemoteValue::Array { handle, .. }
+            | RemoteValue::Object { handle, .. }
+            | RemoteValue::Function { handle }
+            | RemoteValue::RegExp { handle, .. }
+            | RemoteValue::Date { handle, .. }
+            | RemoteValue::Map { handle, .. }
+            | RemoteValue::Set { handle, .. }
+            | RemoteValue::WeakMap { handle }
+            | RemoteValue::WeakSet { handle }
+            | RemoteValue::Generator { handle }
+            | RemoteValue::Error { handle }
+            | RemoteValue::Proxy { handle }
+            | RemoteValue::Promise { handle }
+            | RemoteValue::TypedArray { handle }
+            | RemoteValue::ArrayBuffer { handle }
+            | RemoteValue::NodeList { handle, .. }
+            | RemoteValue::HtmlCollection { handle, .. }
+            | RemoteValue::Node { handle, .. }
+            | RemoteValue::Window { handle, .. } => handle.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Key of the entry when it is used as an object key
+    fn key(key: PropertyKey) -> String {
+        match key {
+            PropertyKey::String(key) => key,
+            PropertyKey::Value(value) => match value {
+                RemoteValue::String { value } | RemoteValue::BigInt { value } => value,
+                value => Value::deserialize(value)
+                    .map(|v| v.to_string())
+                    .unwrap_or_default(),
+            },
+        }
+    }
+
+    /// A reference as a one entry map, `{ELEMENT_KEY: id}` or `{WINDOW_KEY: context}`
+    fn reference<'de, V: Visitor<'de>>(
+        key: &'static str,
+        id: String,
+        visitor: V,
+    ) -> Result<V::Value, serde_json::Error> {
+        visitor.visit_map(MapDeserializer::new(std::iter::once((key, id))))
+    }
+}
+
+/// Maps script values onto Rust values: primitives onto the matching types, arrays, sets and
+/// node lists onto sequences, objects and maps onto structs and maps, nodes onto `Element`s,
+/// windows onto `{WINDOW_KEY: context}`, dates onto strings and regexps onto `/pattern/flags`.
+/// Values without data, such as functions or promises, are unit.
+impl<'de> Deserializer<'de> for RemoteValue {
+    type Error = serde_json::Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, serde_json::Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            RemoteValue::String { value } | RemoteValue::Date { value, .. } => {
+                visitor.visit_string(value)
+            }
+            RemoteValue::Number { value } => {
+                if value.fract() == 0.0 && value.abs() < MAX_SAFE_INTEGER {
+                    visitor.visit_i64(value as i64)
+                } else {
+                    visitor.visit_f64(value)
+                }
+            }
+            RemoteValue::Boolean { value } => visitor.visit_bool(value),
+            RemoteValue::BigInt { value } => match (value.parse::<i64>(), value.parse::<u64>()) {
+                (Ok(n), _) => visitor.visit_i64(n),
+                (_, Ok(n)) => visitor.visit_u64(n),
+                _ => visitor.visit_string(value),
+            },
+            RemoteValue::Array { value, .. }
+            | RemoteValue::Set { value, .. }
+            | RemoteValue::NodeList { value, .. }
+            | RemoteValue::HtmlCollection { value, .. } => {
+                visitor.visit_seq(SeqDeserializer::new(value.unwrap_or_default().into_iter()))
+            }
+            RemoteValue::Object { value, .. } | RemoteValue::Map { value, .. } => {
+                let entries = value
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(k, v)| (Self::key(k), v));
+                visitor.visit_map(MapDeserializer::new(entries))
+            }
+            RemoteValue::RegExp { value, .. } => visitor.visit_string(format!(
+                "/{}/{}",
+                value.pattern,
+                value.flags.unwrap_or_default()
+            )),
+            RemoteValue::Node {
+                shared_id: Some(id),
+                ..
+            } => Self::reference(ELEMENT_KEY, id, visitor),
+            RemoteValue::Window { value, .. } => {
+                Self::reference(WINDOW_KEY, value.context, visitor)
+            }
+            _ => visitor.visit_unit(),
+        }
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, serde_json::Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            RemoteValue::Undefined | RemoteValue::Null => visitor.visit_none(),
+            value => visitor.visit_some(value),
+        }
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, serde_json::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, serde_json::Error>
+    where
+        V: Visitor<'de>,
+    {
+        // Unit variants are strings, the others single entry objects, as in JSON
+        Value::deserialize(self)?.deserialize_enum(name, variants, visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, serde_json::Error> for RemoteValue {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+/// An argument of a script, the BiDi `script.LocalValue`
+#[derive(Debug, Clone, PartialEq)]
+pub enum LocalValue {
+    Undefined,
+    Null,
+    String(String),
+    Number(f64),
+    Boolean(bool),
+    /// Decimal digits of the big integer
+    BigInt(String),
+    Array(Vec<LocalValue>),
+    /// ISO 8601 date
+    Date(String),
+    Map(Vec<(LocalValue, LocalValue)>),
+    Object(Vec<(String, LocalValue)>),
+    RegExp(RegExpValue),
+    Set(Vec<LocalValue>),
+    /// A DOM node by its shared id, e.g. an `Element` id
+    SharedReference(String),
+    /// An object owned by a realm, by its handle
+    RemoteReference(String),
+    /// A function posting `script.message` events on the channel, see `ChannelValue`
+    Channel(ChannelValue),
+}
+
+/// Properties of a `script.ChannelValue`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChannelValue {
+    pub channel: String,
+    #[serde(
+        rename = "serializationOptions",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub serialization_options: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ownership: Option<String>,
+}
+
+impl LocalValue {
+    /// The local value of serializable data, element references become shared references
+    pub fn from_serialize(value: impl Serialize) -> Result<Self, GeckError> {
+        Ok(Self::from(serde_json::to_value(value)?))
+    }
+}
+
+impl From<Value> for LocalValue {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => LocalValue::Null,
+            Value::Bool(b) => LocalValue::Boolean(b),
+            // Integers JavaScript numbers cannot hold exactly are big integers
+            Value::Number(n) => match n.as_f64() {
+                Some(f) if n.is_f64() || f.abs() < MAX_SAFE_INTEGER => LocalValue::Number(f),
+                _ => LocalValue::BigInt(n.to_string()),
+            },
+            Value::String(s) => LocalValue::String(s),
+            Value::Array(items) => {
+                LocalValue::Array(items.into_iter().map(LocalValue::from).collect())
+            }
+            Value::Object(mut map) => match map.remove(ELEMENT_KEY) {
+                Some(Value::String(id)) if map.is_empty() => LocalValue::SharedReference(id),
+                Some(id) => {
+                    map.insert(ELEMENT_KEY.to_owned(), id);
+                    Self::object(map)
+                }
+                None => Self::object(map),
+            },
+        }
+    }
+}
+
+impl LocalValue {
+    fn object(map: Map<String, Value>) -> Self {
+        LocalValue::Object(
+            map.into_iter()
+                .map(|(k, v)| (k, LocalValue::from(v)))
+                .collect(),
+        )
+    }
+}
+
+impl Serialize for LocalValue {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+        let kind = match self {
+            LocalValue::Undefined => "undefined",
+            LocalValue::Null => "null",
+            LocalValue::String(_) => "string",
+            LocalValue::Number(_) => "number",
+            LocalValue::Boolean(_) => "boolean",
+            LocalValue::BigInt(_) => "bigint",
+            LocalValue::Array(_) => "array",
+            LocalValue::Date(_) => "date",
+            LocalValue::Map(_) => "map",
+            LocalValue::Object(_) => "object",
+            LocalValue::RegExp(_) => "regexp",
+            LocalValue::Set(_) => "set",
+            LocalValue::Channel(_) => "channel",
+            LocalValue::SharedReference(id) => {
+                map.serialize_entry("sharedId", id)?;
+                return map.end();
+            }
+            LocalValue::RemoteReference(handle) => {
+                map.serialize_entry("handle", handle)?;
+                return map.end();
+            }
+        };
+        map.serialize_entry("type", kind)?;
+        match self {
+            LocalValue::String(value) | LocalValue::BigInt(value) | LocalValue::Date(value) => {
+                map.serialize_entry("value", value)?
+            }
+            LocalValue::Number(value) => match value {
+                v if v.is_nan() => map.serialize_entry("value", "NaN")?,
+                v if v.is_infinite() => {
+                    map.serialize_entry("value", if *v > 0.0 { "Infinity" } else { "-Infinity" })?
+                }
+                v if *v == 0.0 && v.is_sign_negative() => map.serialize_entry("value", "-0")?,
+                v => map.serialize_entry("value", v)?,
+            },
+            LocalValue::Boolean(value) => map.serialize_entry("value", value)?,
+            LocalValue::Array(items) | LocalValue::Set(items) => {
+                map.serialize_entry("value", items)?
+            }
+            LocalValue::Map(entries) => map.serialize_entry("value", entries)?,
+            LocalValue::Object(entries) => map.serialize_entry("value", entries)?,
+            LocalValue::RegExp(value) => map.serialize_entry("value", value)?,
+            LocalValue::Channel(value) => map.serialize_entry("value", value)?,
+            _ => {}
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schemas::script::Element;
+    use serde_json::json;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    #[serde(rename_all = "camelCase")]
+    struct Product {
+        title: String,
+        price: f64,
+        stock: u32,
+        sku: Option<String>,
+        tags: Vec<String>,
+        link: Element,
+        kind: Kind,
+        on_sale: bool,
+    }
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Kind {
+        Book,
+    }
+
+    #[test]
+    fn test_remote_value() {
+        let remote: RemoteValue = serde_json::from_value(json!({"type": "object", "value": [
+            ["title", {"type": "string", "value": "Dune"}],
+            ["price", {"type": "number", "value": 9.5}],
+            ["stock", {"type": "number", "value": 3}],
+            ["sku", {"type": "undefined"}],
+            ["tags", {"type": "set", "value": [{"type": "string", "value": "scifi"}]}],
+            ["link", {"type": "node", "sharedId": "e-1", "value": {"nodeType": 1, "childNodeCount": 0, "localName": "a"}}],
+            ["kind", {"type": "string", "value": "book"}],
+            ["onSale", {"type": "boolean", "value": false}],
+            ["ignored", {"type": "function", "handle": "h-1"}],
+        ]}))
+        .unwrap();
+        let product: Product = remote.clone().into_typed().unwrap();
+        assert_eq!(
+            product,
+            Product {
+                title: "Dune".to_owned(),
+                price: 9.5,
+                stock: 3,
+                sku: None,
+                tags: vec!["scifi".to_owned()],
+                link: Element {
+                    id: "e-1".to_owned()
+                },
+                kind: Kind::Book,
+                on_sale: false,
+            }
+        );
+
+        let value: Value = remote.into_typed().unwrap();
+        assert_eq!(value["link"], json!({ ELEMENT_KEY: "e-1" }));
+        assert_eq!(value["ignored"], Value::Null);
+
+        let specials: RemoteValue = serde_json::from_value(json!({"type": "array", "value": [
+            {"type": "number", "value": "-Infinity"},
+            {"type": "bigint", "value": "18446744073709551615"},
+            {"type": "regexp", "value": {"pattern": "a+", "flags": "g"}},
+            {"type": "date", "value": "2024-08-20T12:00:00.000Z"},
+            {"type": "map", "value": [[{"type": "number", "value": 1}, {"type": "null"}]]},
+            {"type": "window", "value": {"context": "c-1"}, "handle": "h-2"},
+        ]}))
+        .unwrap();
+        let (infinity, big, regexp, date, map, window): (
+            f64,
+            u64,
+            String,
+            String,
+            HashMap<String, Option<u8>>,
+            Value,
+        ) = specials.into_typed().unwrap();
+        assert_eq!(infinity, f64::NEG_INFINITY);
+        assert_eq!(big, u64::MAX);
+        assert_eq!(
+            (regexp.as_str(), date.as_str()),
+            ("/a+/g", "2024-08-20T12:00:00.000Z")
+        );
+        assert_eq!(map, HashMap::from([("1".to_owned(), None)]));
+        assert_eq!(window, json!({ WINDOW_KEY: "c-1" }));
+    }
+
+    #[test]
+    fn test_local_value() {
+        let element = Element {
+            id: "e-1".to_owned(),
+        };
+        let local = LocalValue::from_serialize((
+            1,
+            1.5,
+            "a\"b",
+            &element,
+            json!({"k": [true, null]}),
+            u64::MAX,
+        ))
+        .unwrap();
+        assert_eq!(
+            serde_json::to_value(&local).unwrap(),
+            json!({"type": "array", "value": [
+                {"type": "number", "value": 1.0},
+                {"type": "number", "value": 1.5},
+                {"type": "string", "value": "a\"b"},
+                {"sharedId": "e-1"},
+                {"type": "object", "value": [["k", {"type": "array", "value": [
+                    {"type": "boolean", "value": true}, {"type": "null"}]}]]},
+                {"type": "bigint", "value": "18446744073709551615"},
+            ]})
+        );
+        let specials = LocalValue::Array(vec![
+            LocalValue::Number(f64::NAN),
+            LocalValue::Number(-0.0),
+            LocalValue::Undefined,
+            LocalValue::RemoteReference("h-1".to_owned()),
+            LocalValue::Map(vec![(
+                LocalValue::Number(1.0),
+                LocalValue::Date("2024-08-20".to_owned()),
+            )]),
+        ]);
+        assert_eq!(
+            serde_json::to_value(&specials).unwrap()["value"],
+            json!([
+                {"type": "number", "value": "NaN"},
+                {"type": "number", "value": "-0"},
+                {"type": "undefined"},
+                {"handle": "h-1"},
+                {"type": "map", "value": [[{"type": "number", "value": 1.0}, {"type": "date", "value": "2024-08-20"}]]},
+            ])
+        );
+    }
+}
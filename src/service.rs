@@ -1,42 +1,47 @@
 use log::*;
 use std::io::{self};
+use std::path::Path;
 use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::net::unix::pipe::Receiver;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
 use tokio::process::Command;
 use tokio::runtime::{Handle, Runtime};
 use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 
-use crate::utils::error::GeckError;
+use crate::package::{self, FirefoxBuild, PackageManager};
+use crate::utils::error::{ErrorKind, GeckError};
+use crate::utils::logging::{LogLine, LogSink, LogStream, TracingSink};
+use crate::utils::webdriver_commands::Driver;
+
+static NEXT_DRIVER_ID: AtomicUsize = AtomicUsize::new(0);
 
 pub struct Context {
     pub handle: Handle,
     pub runtime: Option<Runtime>,
 }
 impl Context {
-    pub fn new() -> Arc<Mutex<Self>> {
-        let (handle, runtime) = Self::get_current_runtime();
-        Arc::new(Mutex::new(Self {
-            handle: handle,
-            runtime: runtime,
-        }))
+    pub fn new() -> Result<Arc<Mutex<Self>>, GeckError> {
+        let (handle, runtime) = Self::get_current_runtime()?;
+        Ok(Arc::new(Mutex::new(Self { handle, runtime })))
     }
 
-    pub fn get_current_runtime() -> (Handle, Option<Runtime>) {
+    pub fn get_current_runtime() -> Result<(Handle, Option<Runtime>), GeckError> {
         match Handle::try_current() {
             Ok(handle) => {
                 debug!("Getting current runtime");
-                (handle, None)
+                Ok((handle, None))
             }
             Err(_) => {
                 debug!("Creating a runtime as there is no runtime...");
                 let rt = tokio::runtime::Builder::new_multi_thread()
                     .enable_all()
                     .build()
-                    .unwrap();
-                (rt.handle().clone(), Some(rt))
+                    .map_err(|e| {
+                        GeckError::new(ErrorKind::Context, Some(e), "Cannot create a runtime")
+                    })?;
+                Ok((rt.handle().clone(), Some(rt)))
             }
         }
     }
@@ -49,142 +54,234 @@ impl Context {
         }
     }
 }
+/// How a driver starts its geckodriver service
+#[derive(Clone)]
+pub struct ServiceConfig {
+    /// Explicit geckodriver executable, resolved by `PackageManager` when not set
+    pub driver_path: Option<String>,
+    pub port: u16,
+    /// Receives parsed geckodriver logs
+    pub log_sink: Arc<dyn LogSink>,
+    /// Firefox executable geckodriver is checked against, `firefox` from PATH when not set
+    pub firefox: Option<String>,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        Self {
+            driver_path: None,
+            port: Driver::PORT.parse().unwrap_or(4444),
+            log_sink: Arc::new(TracingSink),
+            firefox: None,
+        }
+    }
+}
+
+impl ServiceConfig {
+    /// Resolves geckodriver and checks it against the Firefox it will start
+    pub fn resolve_driver_path(&self) -> Result<String, GeckError> {
+        let mut manager = PackageManager::new();
+        if let Some(path) = &self.driver_path {
+            manager.executable(path);
+        }
+        let driver_path = manager.resolve()?;
+        let firefox = match &self.firefox {
+            Some(path) => Some(FirefoxBuild::from_path(Path::new(path))?),
+            // Geckodriver runs the `firefox` of PATH when no binary is given
+            None => match PackageManager::find_in_path(package::FIREFOX_EXECUTABLES[0]) {
+                Some(path) => FirefoxBuild::from_path(&path)
+                    .map_err(|e| warn!("Cannot check the Firefox of PATH: {}", e))
+                    .ok(),
+                None => None,
+            },
+        };
+        match firefox {
+            Some(firefox) => manager.check_firefox(&firefox.version)?,
+            None => warn!("No Firefox found, geckodriver compatibility is not checked"),
+        }
+        Ok(driver_path)
+    }
+
+    /// Geckodriver command line arguments
+    pub fn args(&self) -> Vec<String> {
+        vec![
+            Driver::ARGS_PORT.to_owned(),
+            self.port.to_string(),
+            Driver::ARGS_VERBOSITY.to_owned(),
+        ]
+    }
+
+    pub fn driver_url(&self) -> String {
+        format!("{}:{}", Driver::HOST, self.port)
+    }
+}
+
 pub struct Service {
     driver_path: String,
     context: Arc<Mutex<Context>>,
-    channel: (mpsc::Sender<String>, mpsc::Receiver<String>), // TODO: See if logs can be handled via this silently
+    /// Id of the driver, attached to every log line
+    driver_id: usize,
+    /// Current session id, attached to every log line once a session is created
+    session_id: Arc<Mutex<Option<String>>>,
+    /// Receives parsed geckodriver logs
+    sink: Arc<dyn LogSink>,
+    /// Carries the readiness line to `session_is_up`, the sender moves to the readers
+    /// so the channel closes when geckodriver exits
+    channel: (Option<mpsc::Sender<String>>, mpsc::Receiver<String>),
     geckodriver_service: Option<JoinHandle<Result<(), io::Error>>>,
     stdout_service: Option<JoinHandle<Result<(), io::Error>>>,
     stderr_service: Option<JoinHandle<Result<(), io::Error>>>,
 }
 impl Service {
-    pub fn new(context: &Arc<Mutex<Context>>, driver_path: &String) -> Self {
+    pub fn new(context: &Arc<Mutex<Context>>, driver_path: &str) -> Self {
         Self {
-            driver_path: driver_path.clone(),
+            driver_path: driver_path.to_owned(),
             context: context.clone(),
-            channel: mpsc::channel(32),
+            driver_id: NEXT_DRIVER_ID.fetch_add(1, Ordering::Relaxed),
+            session_id: Arc::new(Mutex::new(None)),
+            sink: Arc::new(TracingSink),
+            channel: {
+                let (sender, receiver) = mpsc::channel(32);
+                (Some(sender), receiver)
+            },
             geckodriver_service: None,
             stdout_service: None,
             stderr_service: None,
         }
     }
 
-    /// Starts the async service to be used with the async context
-    pub async fn start_async(
-        &mut self,
-        args: Vec<&'static str>,
-    ) -> std::result::Result<(), GeckError> {
-        let d_path = self.driver_path.clone();
-        let c_args = args.clone();
-        let mut command = Command::new(d_path);
+    /// Route geckodriver logs to a custom sink, must be set before starting the service
+    pub fn sink(&mut self, sink: Arc<dyn LogSink>) -> &mut Self {
+        self.sink = sink;
+        self
+    }
+
+    pub fn driver_id(&self) -> usize {
+        self.driver_id
+    }
+
+    /// Attach the session id to the following log lines
+    pub fn set_session(&self, session_id: Option<&str>) {
+        *self.session_id.lock().unwrap() = session_id.map(|s| s.to_owned());
+    }
+
+    /// Spawns a task parsing the lines of a geckodriver output stream into the sink.
+    /// The readiness line is forwarded to the channel.
+    fn spawn_reader<R>(
+        &self,
+        stream: R,
+        kind: LogStream,
+        sender: mpsc::Sender<String>,
+    ) -> JoinHandle<Result<(), io::Error>>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let sink = self.sink.clone();
+        let driver_id = self.driver_id;
+        let session_id = self.session_id.clone();
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(stream).lines();
+            while let Some(raw) = reader.next_line().await? {
+                let session = session_id.lock().unwrap().clone();
+                let line = LogLine::parse(driver_id, session, kind, &raw);
+                if line.component.as_deref() == Some("geckodriver")
+                    && line.message.starts_with("Listening on")
+                {
+                    // Nobody waits for readiness after the first line
+                    let _ = sender.try_send(line.message.clone());
+                }
+                sink.log(&line);
+            }
+            Ok(())
+        })
+    }
+
+    /// Spawns the geckodriver process and its log readers, must run inside the runtime
+    fn spawn(&mut self, args: Vec<String>) -> Result<(), GeckError> {
+        let service_error = |msg: &str| GeckError::new(ErrorKind::Service, None::<GeckError>, msg);
+        let sender = self
+            .channel
+            .0
+            .take()
+            .ok_or_else(|| service_error("Service is already started"))?;
+        let mut command = Command::new(self.driver_path.clone());
         let mut output = command
-            .args(c_args)
+            .args(args)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .kill_on_drop(true)
             .spawn()
-            .expect("Failed to spawn process geckodriver!");
-
-        let stdout = output.stdout.take().expect("Failed to take stdout!");
-        let stderr = output.stderr.take().expect("Failed to take stderr!");
-
-        // A task to to spawn to retrieve command output
-        let send_channel = self.channel.0.clone();
-        self.stdout_service = Some(tokio::spawn(async move {
-            let mut reader = BufReader::new(stdout).lines();
-            while let Some(line) = reader.next_line().await? {
-                if line.contains("Listening on") {
-                    send_channel.send(line.clone()).await.unwrap();
-                }
-                debug!("{}", line)
-            }
-            Ok(())
-        }));
+            .map_err(|e| {
+                GeckError::new(
+                    ErrorKind::Service,
+                    Some(e),
+                    &format!("Failed to spawn geckodriver {}", self.driver_path),
+                )
+            })?;
 
-        // A task to to spawn to retrieve command output
-        self.stderr_service = Some(tokio::spawn(async move {
-            let mut reader = BufReader::new(stderr).lines();
-            while let Some(line) = reader.next_line().await? {
-                error!("{}", line)
-            }
-            Ok(())
-        }));
+        let stdout = output
+            .stdout
+            .take()
+            .ok_or_else(|| service_error("Failed to take stdout!"))?;
+        let stderr = output
+            .stderr
+            .take()
+            .ok_or_else(|| service_error("Failed to take stderr!"))?;
+
+        // Tasks to retrieve command output
+        self.stdout_service = Some(self.spawn_reader(stdout, LogStream::Stdout, sender.clone()));
+        self.stderr_service = Some(self.spawn_reader(stderr, LogStream::Stderr, sender));
 
         // A task to to spawn to run the command
+        let driver_id = self.driver_id;
         self.geckodriver_service = Some(tokio::spawn(async move {
-            let status = output.wait().await.expect("Failed to run geckodriver!");
+            let status = output.wait().await?;
             if status.success() {
                 Ok(())
             } else {
-                // Since this is the main task we panic in case task failures!
-                panic!("Driver service failed with status {}", status)
+                error!("Driver service {} failed with status {}", driver_id, status);
+                Err(io::Error::other(format!(
+                    "Driver service failed with status {}",
+                    status
+                )))
             }
         }));
-        debug!("Spawned driver service...");
+        debug!("Spawned driver service {}...", self.driver_id);
         Ok(())
     }
 
-    /// Starts the services on the background. Designed to be used on blocking functions.
-    pub fn start(&mut self, args: Vec<&'static str>) -> std::result::Result<(), GeckError> {
-        let d_path = self.driver_path.clone();
-        let c_args = args.clone();
-        self.context.lock().unwrap().handle.block_on(async {
-            let mut command = Command::new(d_path);
-            let mut output = command
-                .args(c_args)
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .kill_on_drop(true)
-                .spawn()
-                .expect("Failed to spawn process geckodriver!");
-
-            let stdout = output.stdout.take().expect("Failed to take stdout!");
-            let stderr = output.stderr.take().expect("Failed to take stderr!");
-
-            // A task to to spawn to retrieve command output
-            let sender = self.channel.0.clone();
-            self.stdout_service = Some(tokio::spawn(async move {
-                let mut reader = BufReader::new(stdout).lines();
-                while let Some(line) = reader.next_line().await? {
-                    debug!("{}", line);
-                    if line.contains("INFO") || line.contains("TRACE") {
-                        sender.send(line.clone()).await.unwrap();
-                    }
-                }
-                Ok(())
-            }));
+    /// Starts the async service to be used with the async context
+    pub async fn start_async(&mut self, args: Vec<String>) -> std::result::Result<(), GeckError> {
+        self.spawn(args)
+    }
 
-            // A task to to spawn to retrieve command output
-            self.stderr_service = Some(tokio::spawn(async move {
-                let mut reader = BufReader::new(stderr).lines();
-                while let Some(line) = reader.next_line().await? {
-                    error!("{}", line)
-                }
-                Ok(())
-            }));
-
-            // A task to to spawn to run the command
-            self.geckodriver_service = Some(tokio::spawn(async move {
-                let status = output.wait().await.expect("Failed to run geckodriver!");
-                if status.success() {
-                    Ok(())
-                } else {
-                    // Since this is the main task we panic in case task failures!
-                    panic!("Driver service failed with status {}", status)
-                }
-            }));
-        });
-        debug!("Spawned driver service...");
-        Ok(())
+    /// Starts the services on the background. Designed to be used on blocking functions.
+    pub fn start(&mut self, args: Vec<String>) -> std::result::Result<(), GeckError> {
+        let context = self.context.clone();
+        let handle = context.lock().unwrap().handle.clone();
+        let _guard = handle.enter();
+        self.spawn(args)
     }
 
+    /// Blocks until geckodriver listens, fails when it exits before
     pub fn session_is_up(&mut self) -> Result<bool, GeckError> {
-        Ok(self
-            .channel
-            .1
-            .blocking_recv()
-            .unwrap()
-            .contains("Listening on"))
+        Self::is_listening(self.channel.1.blocking_recv())
+    }
+
+    /// Waits until geckodriver listens, fails when it exits before
+    pub async fn session_is_up_async(&mut self) -> Result<bool, GeckError> {
+        Self::is_listening(self.channel.1.recv().await)
+    }
+
+    fn is_listening(line: Option<String>) -> Result<bool, GeckError> {
+        match line {
+            Some(line) => Ok(line.contains("Listening on")),
+            None => Err(GeckError::new(
+                ErrorKind::Service,
+                None::<GeckError>,
+                "Geckodriver exited before listening",
+            )),
+        }
     }
 }
 
@@ -195,3 +292,77 @@ impl Drop for Service {
         Context::abort_if_exists(&self.stderr_service);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::logging::{LogLevel, MemorySink};
+    use crate::utils::testing;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_logs_to_sink() {
+        let dir = std::env::temp_dir().join(format!("ungeckit-service-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("geckodriver");
+        fs::write(
+            &path,
+            "#!/bin/sh\nprintf '1724157311434\\tgeckodriver\\tINFO\\tListening on 127.0.0.1:4444\\n'\necho 'crash' >&2\nsleep 1\n",
+        )
+        .unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let sink = MemorySink::new();
+        let context = Context::new().unwrap();
+        let mut service = Service::new(&context, path.to_str().unwrap());
+        service.sink(Arc::new(sink.clone()));
+        service.start(Vec::new()).unwrap();
+        assert!(service.session_is_up().unwrap());
+
+        // stderr is read by another task
+        for _ in 0..50 {
+            if sink.lines().len() == 2 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        let lines = sink.lines();
+        assert_eq!(lines.len(), 2);
+        let ready = lines
+            .iter()
+            .find(|l| l.stream == LogStream::Stdout)
+            .unwrap();
+        assert_eq!(ready.level, LogLevel::Info);
+        assert_eq!(ready.driver_id, service.driver_id());
+        let crash = lines
+            .iter()
+            .find(|l| l.stream == LogStream::Stderr)
+            .unwrap();
+        assert_eq!(crash.message, "crash");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_start_failures() {
+        let context = Context::new().unwrap();
+        let mut service = Service::new(&context, "/nonexistent/geckodriver");
+        assert!(service.start(Vec::new()).is_err());
+
+        let mut service = Service::new(&context, "/bin/true");
+        service.sink(Arc::new(MemorySink::new()));
+        service.start(Vec::new()).unwrap();
+        assert!(service.session_is_up().is_err());
+    }
+
+    #[test]
+    fn test_resolve_checks_firefox() {
+        let mut config = testing::fake_geckodriver("service-firefox");
+        assert!(config.resolve_driver_path().is_ok());
+        config.firefox = Some(testing::fake_firefox("service-firefox", "102.0"));
+        let error = config.resolve_driver_path().unwrap_err();
+        assert!(error.to_string().contains("requires Firefox 115 or newer"));
+        config.firefox = Some("/nonexistent/firefox".to_owned());
+        assert!(config.resolve_driver_path().is_err());
+    }
+}
@@ -0,0 +1,449 @@
+/*
+A handle on one tab of a driver, commands sent through it act on that tab only
+ */
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::binding::{Binding, BindingCall};
+use crate::cdp::CDP;
+use crate::driver_async::WebDriver;
+use crate::events::{EventKind, EventStream};
+use crate::schemas::navigation::{Navigation, ReadinessState};
+use crate::schemas::script::RealmInfo;
+use crate::utils::error::GeckError;
+
+/// A top level browsing context of a driver. Tabs are cheap to clone and share the driver.
+#[derive(Clone)]
+pub struct Tab {
+    driver: WebDriver,
+    context: String,
+}
+
+impl Tab {
+    /// Wraps an existing browsing context, e.g. one created with `CDP::create_tab`
+    pub fn new(driver: WebDriver, context: &str) -> Self {
+        Self {
+            driver,
+            context: context.to_owned(),
+        }
+    }
+
+    /// Opens a new tab, in the default user context when none is given
+    pub async fn open(driver: &WebDriver, user_context: Option<&str>) -> Result<Self, GeckError> {
+        let context = driver.bidi().await?.create_tab(user_context).await?;
+        Ok(Self::new(driver.clone(), &context))
+    }
+
+    pub fn driver(&self) -> &WebDriver {
+        &self.driver
+    }
+
+    /// Id of the browsing context, also the window handle of the tab
+    pub fn context(&self) -> &str {
+        &self.context
+    }
+
+    /// The BiDi connection of the driver
+    pub async fn bidi(&self) -> Result<Arc<CDP>, GeckError> {
+        self.driver.bidi().await
+    }
+
+    /// Opens the url in this tab and returns the page source, see `WebDriver::get_in`
+    pub async fn get(&self, url: &str) -> Result<String, GeckError> {
+        self.driver.get_in(&self.context, url).await
+    }
+
+    /// Navigates this tab over BiDi, tabs navigate concurrently
+    pub async fn navigate(&self, url: &str, wait: ReadinessState) -> Result<Navigation, GeckError> {
+        self.driver.navigate_in(&self.context, url, wait).await
+    }
+
+    pub async fn reload(&self, wait: ReadinessState) -> Result<Navigation, GeckError> {
+        self.driver.reload_in(&self.context, wait).await
+    }
+
+    pub async fn back(&self) -> Result<Navigation, GeckError> {
+        self.driver
+            .in_window(&self.context, || self.driver.back())
+            .await
+    }
+
+    pub async fn forward(&self) -> Result<Navigation, GeckError> {
+        self.driver
+            .in_window(&self.context, || self.driver.forward())
+            .await
+    }
+
+    pub async fn current_url(&self) -> Result<String, GeckError> {
+        self.driver
+            .in_window(&self.context, || self.driver.current_url())
+            .await
+    }
+
+    pub async fn title(&self) -> Result<String, GeckError> {
+        self.driver
+            .in_window(&self.context, || self.driver.title())
+            .await
+    }
+
+    pub async fn page_source(&self) -> Result<String, GeckError> {
+        self.driver
+            .in_window(&self.context, || self.driver.page_source())
+            .await
+    }
+
+    /// Execute a W3C script in this tab
+    pub async fn execute_script(&self, script: &str, args: &str) -> Result<String, GeckError> {
+        self.driver
+            .execute_script_in(&self.context, script, args)
+            .await
+    }
+
+    /// Typed `execute` in this tab, see `WebDriver::execute`
+    pub async fn execute<T: DeserializeOwned>(
+        &self,
+        script: &str,
+        args: impl Serialize,
+    ) -> Result<T, GeckError> {
+        let args = serde_json::to_value(args)?;
+        self.driver
+            .in_window(&self.context, || self.driver.execute(script, args))
+            .await
+    }
+
+    pub async fn execute_async<T: DeserializeOwned>(
+        &self,
+        script: &str,
+        args: impl Serialize,
+    ) -> Result<T, GeckError> {
+        let args = serde_json::to_value(args)?;
+        self.driver
+            .in_window(&self.context, || self.driver.execute_async(script, args))
+            .await
+    }
+
+    /// Calls a function over BiDi in this tab, in a `sandbox` realm when named, see
+    /// `WebDriver::call_function`
+    pub async fn call_function<T: DeserializeOwned>(
+        &self,
+        function: &str,
+        args: impl Serialize,
+        sandbox: Option<&str>,
+    ) -> Result<T, GeckError> {
+        self.driver
+            .call_function(&self.context, function, args, sandbox)
+            .await
+    }
+
+    /// Evaluates an expression over BiDi in this tab, in a `sandbox` realm when named
+    pub async fn evaluate<T: DeserializeOwned>(
+        &self,
+        expression: &str,
+        sandbox: Option<&str>,
+    ) -> Result<T, GeckError> {
+        self.driver
+            .evaluate(&self.context, expression, sandbox)
+            .await
+    }
+
+    /// The window realm of this tab and its sandboxes
+    pub async fn realms(&self) -> Result<Vec<RealmInfo>, GeckError> {
+        self.driver.realms(Some(&self.context)).await
+    }
+
+    /// Defines the global function `name` in the next documents of this tab, see
+    /// `WebDriver::expose_function`
+    pub async fn expose_function<F, Fut, R>(
+        &self,
+        name: &str,
+        callback: F,
+    ) -> Result<Binding, GeckError>
+    where
+        F: Fn(BindingCall) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R, GeckError>> + Send + 'static,
+        R: Serialize,
+    {
+        let contexts = [self.context.clone()];
+        Binding::install(self.bidi().await?, name, Some(&contexts), None, callback).await
+    }
+
+    /// Typed events of this tab and of its frames
+    pub async fn events(&self, kinds: &[EventKind]) -> Result<EventStream, GeckError> {
+        EventStream::open(
+            self.bidi().await?,
+            kinds,
+            Some(std::slice::from_ref(&self.context)),
+        )
+        .await
+    }
+
+    /// Waits until no request of this tab was in flight for `idle`, see
+    /// `WebDriver::network_idle`
+    pub async fn network_idle(&self, idle: Duration) -> Result<(), GeckError> {
+        self.driver.network_idle(Some(&self.context), idle).await
+    }
+
+    /// A named isolated world of this tab. It shares the DOM with the page but none of its
+    /// globals, so extraction code there is invisible to page scripts.
+    pub fn sandbox(&self, name: &str) -> Sandbox {
+        Sandbox {
+            tab: self.clone(),
+            name: name.to_owned(),
+        }
+    }
+}
+
+/// A sandbox realm of a tab, created by the browser on first use
+#[derive(Clone)]
+pub struct Sandbox {
+    tab: Tab,
+    name: String,
+}
+
+impl Sandbox {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn tab(&self) -> &Tab {
+        &self.tab
+    }
+
+    pub async fn evaluate<T: DeserializeOwned>(&self, expression: &str) -> Result<T, GeckError> {
+        self.tab.evaluate(expression, Some(&self.name)).await
+    }
+
+    pub async fn call_function<T: DeserializeOwned>(
+        &self,
+        function: &str,
+        args: impl Serialize,
+    ) -> Result<T, GeckError> {
+        self.tab
+            .call_function(function, args, Some(&self.name))
+            .await
+    }
+
+    /// `Tab::expose_function` in this sandbox, e.g. for the preload scripts running there
+    pub async fn expose_function<F, Fut, R>(
+        &self,
+        name: &str,
+        callback: F,
+    ) -> Result<Binding, GeckError>
+    where
+        F: Fn(BindingCall) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<R, GeckError>> + Send + 'static,
+        R: Serialize,
+    {
+        let contexts = [self.tab.context.clone()];
+        Binding::install(
+            self.tab.bidi().await?,
+            name,
+            Some(&contexts),
+            Some(&self.name),
+            callback,
+        )
+        .await
+    }
+
+    /// The realm info of the sandbox, none until a script ran in it
+    pub async fn realm(&self) -> Result<Option<RealmInfo>, GeckError> {
+        let realms = self.tab.realms().await?;
+        Ok(realms
+            .into_iter()
+            .find(|r| r.sandbox.as_deref() == Some(self.name.as_str())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schemas::script::{Element, Target};
+    use crate::utils::error::WebDriverErrorCode;
+    use crate::utils::testing::StandIn;
+    use serde_json::Value;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn test_tabs() {
+        let browser = StandIn::start("tabs").await;
+        let driver = browser.driver().await.unwrap();
+        let first = Tab::open(&driver, Some("uc-a")).await.unwrap();
+        let second = Tab::new(driver.clone(), "tab-uc-b");
+        assert_eq!(first.context(), "tab-uc-a");
+
+        let (a, b) = tokio::join!(first.get("https://a.test/"), second.get("https://b.test/"));
+        assert_eq!(a.unwrap(), "<html>https://a.test/</html>");
+        assert_eq!(b.unwrap(), "<html>https://b.test/</html>");
+        let state = browser.browser();
+        assert_eq!(state.urls["tab-uc-a"], "https://a.test/");
+        assert_eq!(state.urls["tab-uc-b"], "https://b.test/");
+    }
+
+    #[tokio::test]
+    async fn test_navigation() {
+        let browser = StandIn::start("navigation").await;
+        browser.browser().redirects.insert(
+            "http://a.test/".to_owned(),
+            "https://a.test/home".to_owned(),
+        );
+        let driver = browser.driver().await.unwrap();
+        let tab = Tab::new(driver.clone(), "tab-uc-a");
+        assert_eq!(
+            serde_json::to_string(&ReadinessState::Interactive).unwrap(),
+            r#""interactive""#
+        );
+
+        let first = tab
+            .navigate("http://a.test/", ReadinessState::Complete)
+            .await
+            .unwrap();
+        assert_eq!(first.url, "https://a.test/home");
+        assert!(first.navigation.is_some());
+        tab.navigate("https://a.test/2", ReadinessState::None)
+            .await
+            .unwrap();
+        assert_eq!(tab.title().await.unwrap(), "Title of https://a.test/2");
+
+        let back = tab.back().await.unwrap();
+        assert_eq!(
+            back,
+            Navigation {
+                navigation: None,
+                url: "https://a.test/home".to_owned()
+            }
+        );
+        assert_eq!(
+            tab.page_source().await.unwrap(),
+            "<html>https://a.test/home</html>"
+        );
+        assert_eq!(tab.forward().await.unwrap().url, "https://a.test/2");
+        assert_eq!(
+            tab.reload(ReadinessState::Complete).await.unwrap().url,
+            "https://a.test/2"
+        );
+        assert_eq!(tab.current_url().await.unwrap(), "https://a.test/2");
+        // The tab stays the current window of classic commands until another one is switched to
+        assert_eq!(driver.current_url().await.unwrap(), "https://a.test/2");
+        driver.switch_to_window("default").await.unwrap();
+        assert_eq!(driver.current_url().await.unwrap(), "");
+    }
+
+    #[tokio::test]
+    async fn test_window_lock() {
+        let browser = StandIn::start("window-lock").await;
+        let driver = browser.driver().await.unwrap();
+        let (a, b) = (
+            Tab::new(driver.clone(), "tab-uc-a"),
+            Tab::new(driver.clone(), "tab-uc-b"),
+        );
+        a.get("https://a.test/").await.unwrap();
+        b.get("https://b.test/").await.unwrap();
+
+        // Commands of the current window wait for the commands running in another one
+        let (held, current) = tokio::join!(
+            driver.in_window("tab-uc-b", || async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(Instant::now())
+            }),
+            async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                let url = driver.current_url().await;
+                (url, Instant::now())
+            }
+        );
+        assert!(held.unwrap() <= current.1);
+        assert_eq!(current.0.unwrap(), "https://b.test/");
+
+        // Nested in the commands of a window, another tab leaves that window current
+        let url = driver
+            .in_window("tab-uc-a", || async {
+                assert_eq!(b.title().await?, "Title of https://b.test/");
+                driver.current_url().await
+            })
+            .await
+            .unwrap();
+        assert_eq!(url, "https://a.test/");
+    }
+
+    #[tokio::test]
+    async fn test_scripts() {
+        let browser = StandIn::start("scripts").await;
+        let driver = browser.driver().await.unwrap();
+        let tab = Tab::new(driver.clone(), "tab-uc-a");
+        let element = Element {
+            id: "e-1".to_owned(),
+        };
+
+        // The stand-in scripts return their arguments
+        let quoted = r#"return arguments[0] + "\"quoted\"";"#;
+        let (text, number, found): (String, u32, Element) =
+            tab.execute(quoted, ("a\"b", 2, &element)).await.unwrap();
+        assert_eq!((text.as_str(), number, &found), ("a\"b", 2, &element));
+        assert_eq!(browser.browser().scripts[0], quoted);
+        let none: Vec<String> = tab.execute_async("arguments[0]()", ()).await.unwrap();
+        assert!(none.is_empty());
+        assert_eq!(tab.execute_script("return 1", "[1]").await.unwrap(), "[1]");
+        let error = driver
+            .execute::<()>("throw new Error()", ())
+            .await
+            .unwrap_err();
+        assert!(error.is(&WebDriverErrorCode::JavascriptError));
+
+        let (found, label): (Element, String) = tab
+            .call_function(
+                "(el, label) => [el, label]",
+                (&element, "x"),
+                Some("extract"),
+            )
+            .await
+            .unwrap();
+        assert_eq!((found, label.as_str()), (element, "x"));
+        let error = tab
+            .call_function::<()>("() => { throw new Error() }", (), None)
+            .await
+            .unwrap_err();
+        assert!(error.is(&WebDriverErrorCode::JavascriptError));
+    }
+
+    #[tokio::test]
+    async fn test_sandbox() {
+        let browser = StandIn::start("sandbox").await;
+        let driver = browser.driver().await.unwrap();
+        let tab = Tab::new(driver.clone(), "tab-uc-a");
+        let sandbox = tab.sandbox("extract");
+        assert_eq!(sandbox.realm().await.unwrap(), None);
+
+        // The stand-in evaluates expressions to their source and realm
+        let (expression, realm): (String, String) =
+            sandbox.evaluate("document.title").await.unwrap();
+        assert_eq!(
+            (expression.as_str(), realm.as_str()),
+            ("document.title", "tab-uc-a/extract")
+        );
+        let (_, realm): (String, String) = tab.evaluate("1", None).await.unwrap();
+        assert_eq!(realm, "tab-uc-a");
+        let (label,): (String,) = sandbox
+            .call_function("label => [label]", "x")
+            .await
+            .unwrap();
+        assert_eq!(label, "x");
+
+        let realm = sandbox.realm().await.unwrap().unwrap();
+        assert_eq!(realm.context.as_deref(), Some("tab-uc-a"));
+        let (_, on): (String, String) = driver
+            .evaluate_on(&Target::realm(&realm.realm), "2")
+            .await
+            .unwrap();
+        assert_eq!(on, realm.realm);
+        Tab::new(driver.clone(), "tab-uc-b")
+            .sandbox("extract")
+            .evaluate::<Value>("3")
+            .await
+            .unwrap();
+        assert_eq!(tab.realms().await.unwrap().len(), 1);
+        assert_eq!(driver.realms(None).await.unwrap().len(), 2);
+    }
+}
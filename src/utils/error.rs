@@ -1,5 +1,7 @@
+use serde::Deserialize;
 use std::error::Error;
 use std::fmt;
+use tokio_tungstenite::tungstenite;
 
 pub type Result<T> = std::result::Result<T, GeckError>;
 pub type BoxError = Box<dyn Error + Send + Sync>;
@@ -7,6 +9,7 @@ pub type BoxError = Box<dyn Error + Send + Sync>;
 pub struct Inner {
     kind: ErrorKind,
     source: Option<BoxError>,
+    webdriver: Option<WebDriverError>,
 }
 
 pub struct GeckError {
@@ -21,18 +24,73 @@ impl GeckError {
     {
         Self {
             inner: Box::new(Inner {
-                kind: kind,
+                kind,
                 source: source.map(Into::into),
+                webdriver: None,
             }),
             details: msg.to_owned(),
         }
     }
+
+    /// An error reported by geckodriver or the remote end in the W3C error format
+    pub fn webdriver(error: WebDriverError) -> Self {
+        Self {
+            details: format!("{}: {}", error.error, error.message),
+            inner: Box::new(Inner {
+                kind: ErrorKind::WebDriver,
+                source: None,
+                webdriver: Some(error),
+            }),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.inner.kind
+    }
+
+    /// The W3C error code, only set for `ErrorKind::WebDriver` errors
+    pub fn code(&self) -> Option<&WebDriverErrorCode> {
+        self.inner.webdriver.as_ref().map(|e| &e.error)
+    }
+
+    /// The full W3C error with its message, stacktrace and data
+    pub fn webdriver_error(&self) -> Option<&WebDriverError> {
+        self.inner.webdriver.as_ref()
+    }
+
+    pub fn is(&self, code: &WebDriverErrorCode) -> bool {
+        self.code() == Some(code)
+    }
+
+    /// Whether retrying the same command may succeed, see `WebDriverErrorCode::is_retryable`
+    pub fn is_retryable(&self) -> bool {
+        self.code().is_some_and(|code| code.is_retryable())
+    }
+
+    /// Whether the connection to geckodriver or the BiDi connection was lost
+    pub fn is_disconnected(&self) -> bool {
+        let Some(source) = self.inner.source.as_deref() else {
+            return false;
+        };
+        if let Some(e) = source.downcast_ref::<reqwest::Error>() {
+            return e.is_connect() || e.is_timeout() || e.is_request() || e.is_body();
+        }
+        matches!(
+            source.downcast_ref::<tungstenite::Error>(),
+            Some(
+                tungstenite::Error::ConnectionClosed
+                    | tungstenite::Error::AlreadyClosed
+                    | tungstenite::Error::Io(_)
+            )
+        )
+    }
 }
 
 impl fmt::Debug for GeckError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("rust_geck::GeckError")
             .field("kind", &self.inner.kind)
+            .field("code", &self.code())
             .finish()
     }
 }
@@ -44,6 +102,13 @@ impl fmt::Display for GeckError {
             ErrorKind::Service => write!(f, "Service Error: {}", self.details),
             ErrorKind::Context => write!(f, "Context Error: {}", self.details),
             ErrorKind::Gecko => write!(f, "Gecko Error: {}", self.details),
+            ErrorKind::Package => write!(f, "Package Error: {}", self.details),
+            ErrorKind::Capabilities => write!(f, "Capabilities Error: {}", self.details),
+            ErrorKind::Proxy => write!(f, "Proxy Error: {}", self.details),
+            ErrorKind::Config => write!(f, "Config Error: {}", self.details),
+            ErrorKind::Politeness => write!(f, "Politeness Error: {}", self.details),
+            ErrorKind::Pool => write!(f, "Pool Error: {}", self.details),
+            ErrorKind::WebDriver => write!(f, "WebDriver Error: {}", self.details),
             ErrorKind::Other => write!(f, "Error: {}", self.details),
         }
     }
@@ -64,39 +129,311 @@ impl From<reqwest::Error> for GeckError {
     }
 }
 
-impl From<tokio_tungstenite::tungstenite::error::Error> for GeckError {
-    fn from(err: tokio_tungstenite::tungstenite::error::Error) -> GeckError {
+impl From<std::io::Error> for GeckError {
+    fn from(err: std::io::Error) -> GeckError {
+        GeckError::new(ErrorKind::Other, Some(err), "IO Failed")
+    }
+}
+
+impl From<serde_json::Error> for GeckError {
+    fn from(err: serde_json::Error) -> GeckError {
+        GeckError::new(ErrorKind::Driver, Some(err), "Unexpected JSON")
+    }
+}
+
+impl From<tungstenite::Error> for GeckError {
+    fn from(err: tungstenite::Error) -> GeckError {
         GeckError::new(ErrorKind::Driver, Some(err), "Service Failed")
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ErrorKind {
     Driver,
     Service,
     Context,
     Gecko,
+    Package,
+    Capabilities,
+    Proxy,
+    Config,
+    /// A navigation was refused by robots.txt or the rate limits
+    Politeness,
+    /// The browser pool is closed or had no browser in time
+    Pool,
+    /// The remote end answered with a W3C error, see `GeckError::code`
+    WebDriver,
     Other,
 }
 
+/// W3C WebDriver and BiDi error codes
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum WebDriverErrorCode {
+    DetachedShadowRoot,
+    ElementClickIntercepted,
+    ElementNotInteractable,
+    InsecureCertificate,
+    InvalidArgument,
+    InvalidCookieDomain,
+    InvalidElementState,
+    InvalidSelector,
+    InvalidSessionId,
+    JavascriptError,
+    MoveTargetOutOfBounds,
+    NoSuchAlert,
+    NoSuchCookie,
+    NoSuchElement,
+    NoSuchFrame,
+    NoSuchHandle,
+    NoSuchIntercept,
+    NoSuchNode,
+    NoSuchRequest,
+    NoSuchScript,
+    NoSuchShadowRoot,
+    NoSuchUserContext,
+    NoSuchWindow,
+    ScriptTimeout,
+    SessionNotCreated,
+    StaleElementReference,
+    Timeout,
+    UnableToCaptureScreen,
+    UnableToCloseBrowser,
+    UnableToSetCookie,
+    UnexpectedAlertOpen,
+    UnknownCommand,
+    UnknownError,
+    UnknownMethod,
+    UnsupportedOperation,
+    /// A code this crate doesn't know about yet
+    Other(String),
+}
+
+impl WebDriverErrorCode {
+    const CODES: [(&'static str, WebDriverErrorCode); 35] = [
+        (
+            "detached shadow root",
+            WebDriverErrorCode::DetachedShadowRoot,
+        ),
+        (
+            "element click intercepted",
+            WebDriverErrorCode::ElementClickIntercepted,
+        ),
+        (
+            "element not interactable",
+            WebDriverErrorCode::ElementNotInteractable,
+        ),
+        (
+            "insecure certificate",
+            WebDriverErrorCode::InsecureCertificate,
+        ),
+        ("invalid argument", WebDriverErrorCode::InvalidArgument),
+        (
+            "invalid cookie domain",
+            WebDriverErrorCode::InvalidCookieDomain,
+        ),
+        (
+            "invalid element state",
+            WebDriverErrorCode::InvalidElementState,
+        ),
+        ("invalid selector", WebDriverErrorCode::InvalidSelector),
+        ("invalid session id", WebDriverErrorCode::InvalidSessionId),
+        ("javascript error", WebDriverErrorCode::JavascriptError),
+        (
+            "move target out of bounds",
+            WebDriverErrorCode::MoveTargetOutOfBounds,
+        ),
+        ("no such alert", WebDriverErrorCode::NoSuchAlert),
+        ("no such cookie", WebDriverErrorCode::NoSuchCookie),
+        ("no such element", WebDriverErrorCode::NoSuchElement),
+        ("no such frame", WebDriverErrorCode::NoSuchFrame),
+        ("no such handle", WebDriverErrorCode::NoSuchHandle),
+        ("no such intercept", WebDriverErrorCode::NoSuchIntercept),
+        ("no such node", WebDriverErrorCode::NoSuchNode),
+        ("no such request", WebDriverErrorCode::NoSuchRequest),
+        ("no such script", WebDriverErrorCode::NoSuchScript),
+        ("no such shadow root", WebDriverErrorCode::NoSuchShadowRoot),
+        (
+            "no such user context",
+            WebDriverErrorCode::NoSuchUserContext,
+        ),
+        ("no such window", WebDriverErrorCode::NoSuchWindow),
+        ("script timeout", WebDriverErrorCode::ScriptTimeout),
+        ("session not created", WebDriverErrorCode::SessionNotCreated),
+        (
+            "stale element reference",
+            WebDriverErrorCode::StaleElementReference,
+        ),
+        ("timeout", WebDriverErrorCode::Timeout),
+        (
+            "unable to capture screen",
+            WebDriverErrorCode::UnableToCaptureScreen,
+        ),
+        (
+            "unable to close browser",
+            WebDriverErrorCode::UnableToCloseBrowser,
+        ),
+        (
+            "unable to set cookie",
+            WebDriverErrorCode::UnableToSetCookie,
+        ),
+        (
+            "unexpected alert open",
+            WebDriverErrorCode::UnexpectedAlertOpen,
+        ),
+        ("unknown command", WebDriverErrorCode::UnknownCommand),
+        ("unknown error", WebDriverErrorCode::UnknownError),
+        ("unknown method", WebDriverErrorCode::UnknownMethod),
+        (
+            "unsupported operation",
+            WebDriverErrorCode::UnsupportedOperation,
+        ),
+    ];
+
+    /// Parses the `error` field of a W3C error, unknown codes are kept in `Other`
+    pub fn parse(s: &str) -> Self {
+        Self::CODES
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|(_, code)| code.clone())
+            .unwrap_or_else(|| WebDriverErrorCode::Other(s.to_owned()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            WebDriverErrorCode::Other(s) => s,
+            code => Self::CODES
+                .iter()
+                .find(|(_, known)| known == code)
+                .map(|(name, _)| *name)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Errors caused by the page still changing, worth retrying or waiting on
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            WebDriverErrorCode::NoSuchElement
+                | WebDriverErrorCode::StaleElementReference
+                | WebDriverErrorCode::ElementNotInteractable
+                | WebDriverErrorCode::ElementClickIntercepted
+                | WebDriverErrorCode::NoSuchFrame
+                | WebDriverErrorCode::NoSuchAlert
+                | WebDriverErrorCode::Timeout
+                | WebDriverErrorCode::ScriptTimeout
+        )
+    }
+}
+
+impl fmt::Display for WebDriverErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for WebDriverErrorCode {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer).map(|s| WebDriverErrorCode::parse(&s))
+    }
+}
+
+/// A W3C error, the `value` of a failed HTTP response or a BiDi error message
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct WebDriverError {
+    pub error: WebDriverErrorCode,
+    #[serde(default)]
+    pub message: String,
+    #[serde(default)]
+    pub stacktrace: String,
+    #[serde(default)]
+    pub data: Option<serde_json::Value>,
+}
+
+impl WebDriverError {
+    /// Parses an HTTP error body `{"value": {"error": ...}}` or a bare error object
+    pub fn from_body(body: &[u8]) -> Option<Self> {
+        let value: serde_json::Value = serde_json::from_slice(body).ok()?;
+        Self::from_value(&value)
+    }
+
+    pub fn from_value(value: &serde_json::Value) -> Option<Self> {
+        let error = match value.get("value") {
+            Some(inner) if inner.get("error").is_some() => inner,
+            _ => value,
+        };
+        serde_json::from_value(error.clone()).ok()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    // TODO write a proper test
-    // a test function that returns our error result
-    fn raises_my_error(yes: bool) -> std::result::Result<(), GeckError> {
-        if yes {
-            let source = GeckError::new(ErrorKind::Other, None::<GeckError>, "This");
-            Err(GeckError::new(ErrorKind::Other, Some(source), "This"))
-        } else {
-            Ok(())
-        }
+
+    #[test]
+    fn test_webdriver_error() {
+        let body = br##"{"value": {"error": "no such element", "message": "Unable to locate element: #missing",
+            "stacktrace": "RemoteError@chrome://remote/content/shared/RemoteError.sys.mjs:8:8", "data": {"selector": "#missing"}}}"##;
+        let error = GeckError::webdriver(WebDriverError::from_body(body).unwrap());
+        assert_eq!(error.kind(), ErrorKind::WebDriver);
+        assert_eq!(error.code(), Some(&WebDriverErrorCode::NoSuchElement));
+        assert!(error.is(&WebDriverErrorCode::NoSuchElement));
+        assert!(error.is_retryable());
+        assert_eq!(
+            error.webdriver_error().unwrap().data.as_ref().unwrap()["selector"],
+            "#missing"
+        );
+        assert_eq!(
+            error.to_string(),
+            "WebDriver Error: no such element: Unable to locate element: #missing"
+        );
+
+        let bidi = serde_json::json!({"type": "error", "id": 1, "error": "no such user context", "message": "x"});
+        let error = WebDriverError::from_value(&bidi).unwrap();
+        assert_eq!(error.error, WebDriverErrorCode::NoSuchUserContext);
+        assert!(WebDriverError::from_body(b"<html>Bad Gateway</html>").is_none());
     }
-    fn raises_my_error_2(yes: bool) -> std::result::Result<(), GeckError> {
-        if yes {
-            Err(GeckError::new(ErrorKind::Other, Some("Test"), "This"))
-        } else {
-            Ok(())
+
+    #[test]
+    fn test_codes() {
+        for (name, code) in WebDriverErrorCode::CODES.iter() {
+            assert_eq!(&WebDriverErrorCode::parse(name), code);
+            assert_eq!(code.as_str(), *name);
         }
+        let other = WebDriverErrorCode::parse("no such web extension");
+        assert_eq!(
+            other,
+            WebDriverErrorCode::Other("no such web extension".to_owned())
+        );
+        assert_eq!(other.to_string(), "no such web extension");
+        assert!(!WebDriverErrorCode::SessionNotCreated.is_retryable());
+
+        let error = GeckError::new(ErrorKind::Service, None::<GeckError>, "down");
+        assert_eq!(error.kind(), ErrorKind::Service);
+        assert_eq!(error.code(), None);
+        assert!(!error.is_retryable());
+        assert!(!error.is_disconnected());
+
+        let closed = GeckError::new(
+            ErrorKind::Driver,
+            Some(tungstenite::Error::ConnectionClosed),
+            "closed",
+        );
+        assert!(closed.is_disconnected());
+        let json = GeckError::from(serde_json::from_str::<u32>("x").unwrap_err());
+        assert!(!json.is_disconnected());
+    }
+
+    #[tokio::test]
+    async fn test_disconnected() {
+        let error = GeckError::from(
+            reqwest::get("http://127.0.0.1:1/session")
+                .await
+                .unwrap_err(),
+        );
+        assert_eq!(error.kind(), ErrorKind::Driver);
+        assert!(error.is_disconnected());
     }
 }
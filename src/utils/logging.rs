@@ -0,0 +1,240 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Geckodriver and Marionette log levels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLevel {
+    Fatal,
+    Error,
+    Warn,
+    Info,
+    Config,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "FATAL" => Some(LogLevel::Fatal),
+            "ERROR" => Some(LogLevel::Error),
+            "WARN" => Some(LogLevel::Warn),
+            "INFO" => Some(LogLevel::Info),
+            "CONFIG" => Some(LogLevel::Config),
+            "DEBUG" => Some(LogLevel::Debug),
+            "TRACE" => Some(LogLevel::Trace),
+            _ => None,
+        }
+    }
+
+    /// Level name as accepted by `moz:firefoxOptions.log.level`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Fatal => "fatal",
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Config => "config",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+/// The stream a log line was read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+/// A single geckodriver log line.
+///
+/// Geckodriver and Marionette lines look like `1724157311434\tgeckodriver\tINFO\tListening on 127.0.0.1:4444`,
+/// anything else (Firefox console output, crash reports) is kept as a message without a component.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogLine {
+    pub driver_id: usize,
+    pub session_id: Option<String>,
+    pub stream: LogStream,
+    pub timestamp: Option<u64>,
+    pub component: Option<String>,
+    pub level: LogLevel,
+    pub message: String,
+}
+
+impl LogLine {
+    /// Parses a raw line, lines without a level get `Debug` on stdout and `Warn` on stderr
+    pub fn parse(
+        driver_id: usize,
+        session_id: Option<String>,
+        stream: LogStream,
+        raw: &str,
+    ) -> Self {
+        let mut fields = raw.trim_end().splitn(4, '\t');
+        let parsed = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(timestamp), Some(component), Some(level), Some(message)) => {
+                match (
+                    timestamp.trim().parse::<u64>(),
+                    LogLevel::parse(level.trim()),
+                ) {
+                    (Ok(timestamp), Some(level)) => Some((timestamp, component, level, message)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        match parsed {
+            Some((timestamp, component, level, message)) => Self {
+                driver_id,
+                session_id,
+                stream,
+                timestamp: Some(timestamp),
+                component: Some(component.trim().to_owned()),
+                level,
+                message: message.to_owned(),
+            },
+            None => Self {
+                driver_id,
+                session_id,
+                stream,
+                timestamp: None,
+                component: None,
+                level: match stream {
+                    LogStream::Stdout => LogLevel::Debug,
+                    LogStream::Stderr => LogLevel::Warn,
+                },
+                message: raw.trim_end().to_owned(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for LogLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.component {
+            Some(component) => write!(f, "{} {:?} {}", component, self.level, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Receives every geckodriver log line of a service
+pub trait LogSink: Send + Sync {
+    fn log(&self, line: &LogLine);
+}
+
+/// Emits log lines as `tracing` events inside a `geckodriver` span carrying the driver and session ids
+#[derive(Debug, Default, Clone)]
+pub struct TracingSink;
+
+impl LogSink for TracingSink {
+    fn log(&self, line: &LogLine) {
+        let span = tracing::debug_span!(
+            "geckodriver",
+            driver_id = line.driver_id,
+            session_id = line.session_id.as_deref().unwrap_or_default()
+        );
+        let _entered = span.enter();
+        let component = line.component.as_deref().unwrap_or("firefox");
+        let timestamp = line.timestamp.unwrap_or_default();
+        let stream = match line.stream {
+            LogStream::Stdout => "stdout",
+            LogStream::Stderr => "stderr",
+        };
+        match line.level {
+            LogLevel::Fatal | LogLevel::Error => {
+                tracing::error!(component, timestamp, stream, "{}", line.message)
+            }
+            LogLevel::Warn => tracing::warn!(component, timestamp, stream, "{}", line.message),
+            LogLevel::Info | LogLevel::Config => {
+                tracing::info!(component, timestamp, stream, "{}", line.message)
+            }
+            LogLevel::Debug => tracing::debug!(component, timestamp, stream, "{}", line.message),
+            LogLevel::Trace => tracing::trace!(component, timestamp, stream, "{}", line.message),
+        }
+    }
+}
+
+/// Keeps log lines in memory so they can be inspected per test instead of interleaved
+#[derive(Debug, Default, Clone)]
+pub struct MemorySink {
+    lines: Arc<Mutex<Vec<LogLine>>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A snapshot of the captured lines
+    pub fn lines(&self) -> Vec<LogLine> {
+        self.lines.lock().unwrap().clone()
+    }
+
+    pub fn clear(&self) {
+        self.lines.lock().unwrap().clear()
+    }
+}
+
+impl LogSink for MemorySink {
+    fn log(&self, line: &LogLine) {
+        self.lines.lock().unwrap().push(line.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let line = LogLine::parse(
+            1,
+            None,
+            LogStream::Stdout,
+            "1724157311434\tgeckodriver\tINFO\tListening on 127.0.0.1:4444\n",
+        );
+        assert_eq!(line.timestamp, Some(1724157311434));
+        assert_eq!(line.component.as_deref(), Some("geckodriver"));
+        assert_eq!(line.level, LogLevel::Info);
+        assert_eq!(line.message, "Listening on 127.0.0.1:4444");
+
+        let line = LogLine::parse(
+            1,
+            Some("abc".to_owned()),
+            LogStream::Stdout,
+            "1724157312000\tMarionette\tTRACE\t0 -> [0,1,\"WebDriver:NewSession\",{}]",
+        );
+        assert_eq!(line.component.as_deref(), Some("Marionette"));
+        assert_eq!(line.level, LogLevel::Trace);
+        assert_eq!(line.session_id.as_deref(), Some("abc"));
+
+        let line = LogLine::parse(
+            1,
+            None,
+            LogStream::Stderr,
+            "JavaScript error: foo.js, line 1",
+        );
+        assert_eq!(line.component, None);
+        assert_eq!(line.level, LogLevel::Warn);
+        assert_eq!(line.message, "JavaScript error: foo.js, line 1");
+    }
+
+    #[test]
+    fn test_memory_sink() {
+        let sink = MemorySink::new();
+        let shared: Arc<dyn LogSink> = Arc::new(sink.clone());
+        shared.log(&LogLine::parse(
+            2,
+            None,
+            LogStream::Stdout,
+            "1\tgeckodriver\tWARN\tslow",
+        ));
+        TracingSink.log(&LogLine::parse(2, None, LogStream::Stdout, "plain line"));
+        assert_eq!(sink.lines().len(), 1);
+        assert_eq!(sink.lines()[0].level, LogLevel::Warn);
+        sink.clear();
+        assert!(sink.lines().is_empty());
+    }
+}
@@ -1,4 +1,7 @@
 pub mod error;
+pub mod logging;
 pub mod net;
+#[cfg(test)]
+pub(crate) mod testing;
 pub mod types;
 pub mod webdriver_commands;
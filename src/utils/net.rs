@@ -1,37 +1,61 @@
 use bytes::Bytes;
 use reqwest::{self, header, Method, Request, RequestBuilder, Url};
 
-use crate::utils::error::{ErrorKind, GeckError};
+use crate::utils::error::{ErrorKind, GeckError, WebDriverError};
 pub mod http {
     use super::*;
 
-    pub async fn request<'a>(
+    pub async fn request(
         client: &reqwest::Client,
         verb: &str,
         url: &str,
         data: String,
     ) -> Result<Bytes, GeckError> {
-        let method = Method::from_bytes(verb.as_bytes()).unwrap();
+        let method = Method::from_bytes(verb.as_bytes()).map_err(|e| {
+            GeckError::new(
+                ErrorKind::Driver,
+                Some(e),
+                &format!("Invalid method {}", verb),
+            )
+        })?;
+        let url = Url::parse(url).map_err(|e| {
+            GeckError::new(
+                ErrorKind::Driver,
+                Some(e),
+                &format!("Cannot parse url {}", url),
+            )
+        })?;
         let mut headers = header::HeaderMap::new();
-        headers.insert("Accept", "application/json".parse().unwrap());
+        headers.insert(
+            "Accept",
+            header::HeaderValue::from_static("application/json"),
+        );
         headers.insert(
             "Content-Type",
-            "application/json;charset=UTF-8".parse().unwrap(),
+            header::HeaderValue::from_static("application/json;charset=UTF-8"),
         );
-        let request = Request::new(method, Url::parse(&url).expect("Cannot parse url"));
+        let request = Request::new(method, url);
         let response = RequestBuilder::from_parts(client.clone(), request)
             .headers(headers)
             .body(data)
             .send()
             .await?;
-        if response.status() == 200 {
-            Ok(response.bytes().await?)
-        } else {
-            Err(GeckError::new(
+        let status = response.status();
+        if status == 200 {
+            return Ok(response.bytes().await?);
+        }
+        let body = response.bytes().await?;
+        match WebDriverError::from_body(&body) {
+            Some(error) => Err(GeckError::webdriver(error)),
+            None => Err(GeckError::new(
                 ErrorKind::Service,
                 None::<GeckError>,
-                &format!("Response status is {}", response.status()),
-            ))
+                &format!(
+                    "Response status is {}: {}",
+                    status,
+                    String::from_utf8_lossy(&body)
+                ),
+            )),
         }
     }
 
@@ -41,69 +65,78 @@ pub mod ws {
     use tokio::net::TcpStream;
     use log::*;
     use tokio_tungstenite::{self, MaybeTlsStream, WebSocketStream};
-    use std::sync::{Arc, Mutex};
-    use crate::service::Context;
-    use crate::utils::error::GeckError;
+    use crate::utils::error::{ErrorKind, GeckError};
+    use futures_util::stream::{SplitSink, SplitStream};
     use futures_util::{SinkExt, StreamExt};
-    use tokio::sync::mpsc;
-    use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
+    use tokio_tungstenite::{connect_async, tungstenite, tungstenite::protocol::Message};
+
+    pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
     // TODO Move CDP in here!!!
     pub struct WebSocketClient {
-        pub context: Arc<Mutex<Context>>,
         ws_url: String,
-        ws_stream: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>,
-        rx: mpsc::UnboundedReceiver<Message>,
-        tx: mpsc::UnboundedSender<Message>,
+        ws_stream: Option<WsStream>,
     }
 
     impl WebSocketClient {
-        pub fn new(context: Arc<Mutex<Context>>, ws_url: &str) -> Self {
-            let ch = mpsc::unbounded_channel();
+        pub fn new(ws_url: &str) -> Self {
             Self {
-                context: context,
                 ws_url: ws_url.to_owned(),
                 ws_stream: None,
-                tx: ch.0,
-                rx: ch.1,
             }
         }
 
-        pub async fn connect_async(&mut self) {
-            let (tx, rx) = (&mut self.tx, &self.rx) ;
-            let (ws_stream, _) = connect_async(self.ws_url.clone()).await.expect("Cannot create connection");
+        pub async fn connect(&mut self) -> Result<(), GeckError> {
+            let (ws_stream, _) = connect_async(self.ws_url.clone()).await?;
             self.ws_stream = Some(ws_stream);
             debug!("Successfully connected to the websocket stream");
+            Ok(())
         }
 
-        pub fn connect(&mut self) {
-            let ctx = self.context.clone();
-            ctx.lock().unwrap().handle.block_on(async move {
-                let (ws_stream, _) = connect_async(self.ws_url.clone()).await.expect("Cannot create connection");
-                self.ws_stream = Some(ws_stream);
-            });
-            debug!("Successfully connected to the websocket stream");
+        fn not_connected() -> GeckError {
+            GeckError::new(
+                ErrorKind::Driver,
+                None::<GeckError>,
+                "WebSocketClient is not connected",
+            )
         }
 
-        pub fn send(&mut self, msg: &str) -> Result<Message, GeckError> {
-            let ctx = self.context.clone();
-            let sc = self.ws_stream.as_mut().unwrap();
-            let parsed_msg = ctx.lock().unwrap().handle.block_on(async move {
-                match sc.send(msg.into()).await {
-                    Ok(data) => {log::debug!("Message is sent successfully {:?}", data)}
-                    Err(e) => log::error!("{:?}", e)
-                }
-                // Handle Remote Agent errors
-                if let Some(message) = sc.next().await {
-                    let data = message.unwrap();
-                    log::debug!("Response message is: {:?}", data);
-                    return Some(data);
-                } 
-                None
-            });
-            assert!(parsed_msg != None, "WebSocketClient couldn't retrieve the response message");
-            Ok(parsed_msg.unwrap())
+        fn stream(&mut self) -> Result<&mut WsStream, GeckError> {
+            self.ws_stream.as_mut().ok_or_else(Self::not_connected)
         }
 
+        /// Splits the connection into its writing and reading halves, so that messages
+        /// can be sent while another task reads
+        pub fn split(
+            self,
+        ) -> Result<(SplitSink<WsStream, Message>, SplitStream<WsStream>), GeckError> {
+            self.ws_stream
+                .map(|s| s.split())
+                .ok_or_else(Self::not_connected)
+        }
+
+        /// Sends a message and returns the next message received
+        pub async fn send(&mut self, msg: &str) -> Result<Message, GeckError> {
+            self.stream()?.send(msg.into()).await?;
+            log::debug!("Message is sent successfully");
+            self.receive().await
+        }
+
+        /// The next message, responses and events alike
+        pub async fn receive(&mut self) -> Result<Message, GeckError> {
+            match self.stream()?.next().await {
+                Some(message) => {
+                    let data = message?;
+                    log::debug!("Response message is: {:?}", data);
+                    Ok(data)
+                }
+                None => Err(GeckError::new(
+                    ErrorKind::Driver,
+                    Some(tungstenite::Error::ConnectionClosed),
+                    "WebSocketClient couldn't retrieve the response message",
+                )),
+            }
+        }
     }
 
-}
\ No newline at end of file
+}
@@ -0,0 +1,562 @@
+/*
+Stand-ins for geckodriver and Firefox shared by the tests
+ */
+use futures_util::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+
+use crate::browser_pool::Launcher;
+use crate::driver_async::WebDriver;
+use crate::service::ServiceConfig;
+use crate::utils::error::GeckError;
+use crate::utils::logging::MemorySink;
+
+/// A geckodriver announcing it listens, then idling until it is killed
+pub(crate) fn fake_geckodriver(name: &str) -> ServiceConfig {
+    let dir = std::env::temp_dir().join(format!("ungeckit-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("geckodriver");
+    fs::write(
+        &path,
+        "#!/bin/sh\n[ \"$1\" = --version ] && echo 'geckodriver 0.35.0' && exit 0\nprintf '1724157311434\\tgeckodriver\\tINFO\\tListening on 127.0.0.1:1\\n'\nsleep 5\n",
+    )
+    .unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    ServiceConfig {
+        driver_path: Some(path.to_string_lossy().into_owned()),
+        port: 1,
+        log_sink: Arc::new(MemorySink::new()),
+        firefox: Some(fake_firefox(name, "128.0")),
+    }
+}
+
+/// A Firefox only answering `--version`, returns its path
+pub(crate) fn fake_firefox(name: &str, version: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("ungeckit-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(format!("firefox-{}", version));
+    fs::write(
+        &path,
+        format!("#!/bin/sh\necho 'Mozilla Firefox {}'\n", version),
+    )
+    .unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+/// What the stand-in browser was asked to do
+#[derive(Debug, Default)]
+pub(crate) struct Browser {
+    pub sessions: usize,
+    pub quits: usize,
+    pub user_contexts: usize,
+    pub removed: Vec<String>,
+    /// The window classic commands act on
+    pub current: Option<String>,
+    /// Url opened in each window
+    pub urls: HashMap<String, String>,
+    pub navigations: Vec<String>,
+    /// Pages visited before and after the current one, per window
+    pub history: HashMap<String, (Vec<String>, Vec<String>)>,
+    /// Parameters of the `session.subscribe` commands, subscription `sub-<n>` is the n-th
+    pub subscriptions: Vec<Value>,
+    /// Parameters of the `session.unsubscribe` commands
+    pub unsubscriptions: Vec<Value>,
+    /// Tabs opened with `browsingContext.create`, closed with their user context
+    pub tabs: Vec<String>,
+    /// Frames of the tabs, by their parent
+    pub frames: Vec<(String, String)>,
+    /// Windows whose document is still loading
+    pub loading: Vec<String>,
+    /// Parameters of the preload scripts by id
+    pub preload_scripts: Vec<(String, Value)>,
+    /// Parameters of the `script.callFunction` commands
+    pub calls: Vec<Value>,
+    /// Sandboxes scripts ran in, per browsing context
+    pub sandboxes: Vec<(String, String)>,
+    /// Scripts executed with classic commands
+    pub scripts: Vec<String>,
+    /// Navigations to these urls end at the target
+    pub redirects: HashMap<String, String>,
+    /// Navigations to these urls time out as many times
+    pub timeouts: HashMap<String, u32>,
+    /// Parameters of the `network.addIntercept` commands, intercept `intercept-<n>` is the n-th
+    pub intercepts: Vec<Value>,
+    pub removed_intercepts: Vec<String>,
+    /// Intercepted requests resumed, with `continueRequest` or `failRequest`
+    pub resumed: Vec<(String, String)>,
+    /// Parameters of the `network.continueWithAuth` commands
+    pub authenticated: Vec<Value>,
+}
+
+type Failure = (u16, &'static str, String);
+
+impl Browser {
+    /// Id of the realm of a `script.Target`, sandboxes are created on first use
+    fn realm(&mut self, target: &Value) -> String {
+        let context = target["context"].as_str().unwrap_or_default().to_owned();
+        match (target["realm"].as_str(), target["sandbox"].as_str()) {
+            (Some(realm), _) => realm.to_owned(),
+            (None, Some(sandbox)) => {
+                let realm = format!("{}/{}", context, sandbox);
+                let sandbox = (context, sandbox.to_owned());
+                if !self.sandboxes.contains(&sandbox) {
+                    self.sandboxes.push(sandbox);
+                }
+                realm
+            }
+            (None, None) => context,
+        }
+    }
+
+    /// Opens the url in the window, returns the url after redirects
+    fn open(&mut self, window: &str, url: &str) -> Result<String, Failure> {
+        self.navigations.push(url.to_owned());
+        if let Some(left) = self.timeouts.get_mut(url).filter(|left| **left > 0) {
+            *left -= 1;
+            return Err((500, "timeout", format!("Timed out loading {}", url)));
+        }
+        let url = self
+            .redirects
+            .get(url)
+            .cloned()
+            .unwrap_or_else(|| url.to_owned());
+        if let Some(previous) = self.urls.insert(window.to_owned(), url.clone()) {
+            let (back, forward) = self.history.entry(window.to_owned()).or_default();
+            back.push(previous);
+            forward.clear();
+        }
+        Ok(url)
+    }
+
+    /// Moves through the history of the window, `back` or forward
+    fn traverse(&mut self, window: &str, back: bool) {
+        let (before, after) = self.history.entry(window.to_owned()).or_default();
+        let (from, to) = if back {
+            (before, after)
+        } else {
+            (after, before)
+        };
+        if let Some(url) = from.pop() {
+            if let Some(current) = self.urls.insert(window.to_owned(), url) {
+                to.push(current);
+            }
+        }
+    }
+
+    fn bidi(&mut self, method: &str, params: &Value) -> Result<Value, Failure> {
+        match method {
+            "browser.createUserContext" => {
+                self.user_contexts += 1;
+                Ok(json!({ "userContext": format!("uc-{}", self.user_contexts) }))
+            }
+            "browser.removeUserContext" => {
+                let user_context = params["userContext"].as_str().unwrap_or_default();
+                let tab = format!("tab-{}", user_context);
+                self.tabs.retain(|t| *t != tab);
+                self.removed.push(user_context.to_owned());
+                Ok(json!({}))
+            }
+            "network.addIntercept" => {
+                self.intercepts.push(params.clone());
+                Ok(json!({ "intercept": format!("intercept-{}", self.intercepts.len()) }))
+            }
+            "network.removeIntercept" => {
+                let intercept = params["intercept"].as_str().unwrap_or_default();
+                self.removed_intercepts.push(intercept.to_owned());
+                Ok(json!({}))
+            }
+            "network.continueRequest" | "network.failRequest" => {
+                let request = params["request"].as_str().unwrap_or_default().to_owned();
+                self.resumed
+                    .push((method.trim_start_matches("network.").to_owned(), request));
+                Ok(json!({}))
+            }
+            "network.continueWithAuth" => {
+                self.authenticated.push(params.clone());
+                Ok(json!({}))
+            }
+            // The default window and the open tabs, without a root
+            "browsingContext.getTree" if params["root"].is_null() => {
+                let contexts: Vec<Value> = std::iter::once("default")
+                    .chain(self.tabs.iter().map(|tab| tab.as_str()))
+                    .map(|context| json!({ "context": context, "children": [], "url": "about:blank" }))
+                    .collect();
+                Ok(json!({ "contexts": contexts }))
+            }
+            // Tabs of user contexts are named `tab-<user context>`
+            "browsingContext.getTree" => {
+                let context = params["root"].as_str().unwrap_or_default();
+                let user_context = match context.strip_prefix("tab-uc-") {
+                    Some(n) => format!("uc-{}", n),
+                    None => "default".to_owned(),
+                };
+                let children: Vec<Value> = self
+                    .frames
+                    .iter()
+                    .filter(|(parent, _)| parent == context)
+                    .map(|(parent, frame)| json!({ "context": frame, "parent": parent, "url": "about:blank" }))
+                    .collect();
+                Ok(json!({ "contexts": [
+                    { "context": context, "userContext": user_context, "children": children, "url": "about:blank" },
+                ] }))
+            }
+            "browsingContext.navigate" => {
+                let context = params["context"].as_str().unwrap_or_default().to_owned();
+                let url = self.open(&context, params["url"].as_str().unwrap_or_default())?;
+                Ok(json!({ "navigation": format!("nav-{}", self.navigations.len()), "url": url }))
+            }
+            "browsingContext.reload" => {
+                let context = params["context"].as_str().unwrap_or_default();
+                let url = self.urls.get(context).cloned().unwrap_or_default();
+                self.navigations.push(url.clone());
+                Ok(json!({ "navigation": format!("nav-{}", self.navigations.len()), "url": url }))
+            }
+            // The page source is its url, other expressions evaluate to their source and the
+            // realm they ran in
+            "script.evaluate" if params["expression"] == "document.documentElement.outerHTML" => {
+                let context = params["target"]["context"].as_str().unwrap_or_default();
+                let url = self.urls.get(context).cloned().unwrap_or_default();
+                Ok(json!({ "type": "success", "realm": context,
+                    "result": { "type": "string", "value": format!("<html>{}</html>", url) } }))
+            }
+            "script.evaluate" if params["expression"] == "document.readyState" => {
+                let context = params["target"]["context"].as_str().unwrap_or_default();
+                let state = match self.loading.iter().any(|c| c == context) {
+                    true => "loading",
+                    false => "complete",
+                };
+                Ok(json!({ "type": "success", "realm": context,
+                    "result": { "type": "string", "value": state } }))
+            }
+            "script.evaluate" => {
+                let realm = self.realm(&params["target"]);
+                let expression = params["expression"].as_str().unwrap_or_default();
+                Ok(
+                    json!({ "type": "success", "realm": realm, "result": { "type": "array", "value": [
+                    { "type": "string", "value": expression }, { "type": "string", "value": realm },
+                ] } }),
+                )
+            }
+            "script.getRealms" => {
+                let context = params["context"].as_str();
+                let mut realms = Vec::new();
+                for (owner, sandbox) in &self.sandboxes {
+                    if context.is_none_or(|c| c == owner) {
+                        realms.push(json!({ "realm": format!("{}/{}", owner, sandbox), "origin": "https://a.test",
+                            "type": "window", "context": owner, "sandbox": sandbox }));
+                    }
+                }
+                Ok(json!({ "realms": realms }))
+            }
+            "session.subscribe" => {
+                self.subscriptions.push(params.clone());
+                Ok(json!({ "subscription": format!("sub-{}", self.subscriptions.len()) }))
+            }
+            "session.unsubscribe" => {
+                self.unsubscriptions.push(params.clone());
+                Ok(json!({}))
+            }
+            "script.addPreloadScript" => {
+                let script = format!("preload-{}", self.preload_scripts.len() + 1);
+                self.preload_scripts.push((script.clone(), params.clone()));
+                Ok(json!({ "script": script }))
+            }
+            "script.removePreloadScript" => {
+                self.preload_scripts
+                    .retain(|(script, _)| params["script"] != script.as_str());
+                Ok(json!({}))
+            }
+            // Functions return their arguments, or throw when they say so
+            "script.callFunction" => {
+                self.calls.push(params.clone());
+                self.realm(&params["target"]);
+                if params["functionDeclaration"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .contains("throw")
+                {
+                    return Ok(json!({ "type": "exception", "realm": "realm-1",
+                        "exceptionDetails": { "text": "Error: thrown", "stackTrace": { "callFrames": [] } } }));
+                }
+                let args = params["arguments"].as_array().cloned().unwrap_or_default();
+                let args: Vec<Value> = args
+                    .into_iter()
+                    .map(|arg| match arg["sharedId"].as_str() {
+                        Some(id) => json!({ "type": "node", "sharedId": id }),
+                        None => arg,
+                    })
+                    .collect();
+                Ok(
+                    json!({ "type": "success", "realm": "realm-1", "result": { "type": "array", "value": args } }),
+                )
+            }
+            "browsingContext.create" => {
+                let tab = format!(
+                    "tab-{}",
+                    params["userContext"].as_str().unwrap_or("default")
+                );
+                self.tabs.push(tab.clone());
+                Ok(json!({ "context": tab }))
+            }
+            _ => Ok(json!({})),
+        }
+    }
+
+    fn http(
+        &mut self,
+        verb: &str,
+        path: &str,
+        body: &Value,
+        ws_url: &str,
+    ) -> Result<Value, Failure> {
+        let window = self.current.clone().unwrap_or_else(|| "default".to_owned());
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (verb, &segments[..]) {
+            ("POST", ["session"]) => {
+                self.sessions += 1;
+                Ok(session(&format!("session-{}", self.sessions), ws_url))
+            }
+            ("DELETE", ["session", _]) => {
+                self.quits += 1;
+                self.current = None;
+                Ok(Value::Null)
+            }
+            ("GET", ["session", _, "window"]) => Ok(json!(window)),
+            ("POST", ["session", _, "window"]) => {
+                self.current = body["handle"].as_str().map(|h| h.to_owned());
+                Ok(Value::Null)
+            }
+            ("POST", ["session", _, "url"]) => {
+                self.open(&window, body["url"].as_str().unwrap_or_default())?;
+                Ok(Value::Null)
+            }
+            ("GET", ["session", _, "url"]) => {
+                Ok(json!(self.urls.get(&window).cloned().unwrap_or_default()))
+            }
+            ("POST", ["session", _, direction @ ("back" | "forward")]) => {
+                self.traverse(&window, *direction == "back");
+                Ok(Value::Null)
+            }
+            ("GET", ["session", _, "title"]) => Ok(json!(format!(
+                "Title of {}",
+                self.urls.get(&window).cloned().unwrap_or_default()
+            ))),
+            ("GET", ["session", _, "source"]) => Ok(json!(format!(
+                "<html>{}</html>",
+                self.urls.get(&window).cloned().unwrap_or_default()
+            ))),
+            // Scripts return their arguments, or throw when they say so
+            ("POST", ["session", _, "execute", _]) => {
+                let script = body["script"].as_str().unwrap_or_default().to_owned();
+                self.scripts.push(script.clone());
+                match script.contains("throw") {
+                    true => Err((500, "javascript error", script)),
+                    false => Ok(body["args"].clone()),
+                }
+            }
+            _ => Err((404, "unknown command", format!("{} {}", verb, path))),
+        }
+    }
+}
+
+/// A new session of the stand-in browser, its BiDi connection is `ws_url`
+fn session(session_id: &str, ws_url: &str) -> Value {
+    json!({"sessionId": session_id, "capabilities": {
+        "acceptInsecureCerts": true, "browserName": "firefox", "browserVersion": "128.0",
+        "moz:accessibilityChecks": false, "moz:buildID": "1", "moz:geckodriverVersion": "0.35.0",
+        "moz:headless": true, "moz:platformVersion": "6.0", "moz:processID": 1,
+        "moz:profile": "/tmp/profile", "moz:shutdownTimeout": 60000, "moz:webdriverClick": true,
+        "moz:windowless": false, "pageLoadStrategy": "normal", "platformName": "linux",
+        "proxy": {}, "setWindowRect": true, "strictFileInteractability": false,
+        "timeouts": {"implicit": 0, "pageLoad": 300000, "script": 30000},
+        "unhandledPromptBehavior": "dismiss and notify", "userAgent": "Firefox",
+        "webSocketUrl": ws_url,
+    }})
+}
+
+/// A stand-in geckodriver http endpoint and BiDi websocket, sharing one browser state
+pub(crate) struct StandIn {
+    browser: Arc<Mutex<Browser>>,
+    events: broadcast::Sender<Value>,
+    pub http_url: String,
+    pub geckodriver: ServiceConfig,
+}
+
+impl StandIn {
+    pub async fn start(name: &str) -> Self {
+        let browser = Arc::new(Mutex::new(Browser::default()));
+        let (events, _) = broadcast::channel(64);
+        let ws_url = Self::serve_bidi(browser.clone(), events.clone()).await;
+        let http_url = Self::serve_http(browser.clone(), ws_url).await;
+        Self {
+            browser,
+            events,
+            http_url,
+            geckodriver: fake_geckodriver(name),
+        }
+    }
+
+    pub fn browser(&self) -> MutexGuard<'_, Browser> {
+        self.browser.lock().unwrap()
+    }
+
+    /// Sends a BiDi event on every connection
+    pub fn emit(&self, method: &str, params: Value) {
+        let _ = self
+            .events
+            .send(json!({ "type": "event", "method": method, "params": params }));
+    }
+
+    /// A driver with a session on the stand-in browser
+    pub async fn driver(&self) -> Result<WebDriver, GeckError> {
+        let driver = WebDriver::new(
+            Some(self.http_url.clone()),
+            "{}".to_owned(),
+            reqwest::Client::new(),
+            self.geckodriver.clone(),
+        )
+        .await?;
+        driver.new_session().await?;
+        Ok(driver)
+    }
+
+    /// Starts drivers on the stand-in browser, all of them share its state
+    pub fn launcher(&self) -> Launcher {
+        let http_url = self.http_url.clone();
+        let geckodriver = self.geckodriver.clone();
+        Arc::new(move || {
+            let http_url = http_url.clone();
+            let geckodriver = geckodriver.clone();
+            Box::pin(async move {
+                let driver = WebDriver::new(
+                    Some(http_url),
+                    "{}".to_owned(),
+                    reqwest::Client::new(),
+                    geckodriver,
+                )
+                .await?;
+                driver.new_session().await?;
+                Ok(driver)
+            })
+        })
+    }
+
+    async fn serve_bidi(browser: Arc<Mutex<Browser>>, events: broadcast::Sender<Value>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let browser = browser.clone();
+                let mut events = events.subscribe();
+                tokio::spawn(async move {
+                    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    loop {
+                        let message = tokio::select! {
+                            Some(Ok(message)) = ws.next() => message,
+                            Ok(event) = events.recv() => {
+                                if ws.send(event.to_string().into()).await.is_err() {
+                                    break;
+                                }
+                                continue;
+                            }
+                            else => break,
+                        };
+                        let Ok(command) =
+                            serde_json::from_str::<Value>(message.to_text().unwrap_or_default())
+                        else {
+                            continue;
+                        };
+                        let method = command["method"].as_str().unwrap_or_default();
+                        let result = browser.lock().unwrap().bidi(method, &command["params"]);
+                        let response = match result {
+                            Ok(result) => {
+                                json!({ "type": "success", "id": command["id"], "result": result })
+                            }
+                            Err((_, error, message)) => json!({
+                                "type": "error", "id": command["id"], "error": error, "message": message,
+                            }),
+                        };
+                        if ws.send(response.to_string().into()).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    async fn serve_http(browser: Arc<Mutex<Browser>>, ws_url: String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let browser = browser.clone();
+                let ws_url = ws_url.clone();
+                tokio::spawn(async move {
+                    let Some((verb, path, body)) = read_request(&mut socket).await else {
+                        return;
+                    };
+                    let result = browser.lock().unwrap().http(&verb, &path, &body, &ws_url);
+                    let (status, value) = match result {
+                        Ok(value) => (200, value),
+                        Err((status, error, message)) => (
+                            status,
+                            json!({ "error": error, "message": message, "stacktrace": "" }),
+                        ),
+                    };
+                    let body = json!({ "value": value }).to_string();
+                    let response = format!(
+                        "HTTP/1.1 {} Stand-in\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        url
+    }
+}
+
+/// Reads the headers, then the body announced by content-length
+async fn read_request(socket: &mut tokio::net::TcpStream) -> Option<(String, String, Value)> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = socket.read(&mut buf).await.ok()?;
+        request.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&request).into_owned();
+        if let Some(end) = text.find("\r\n\r\n") {
+            let length = text[..end]
+                .lines()
+                .find_map(|l| {
+                    l.to_lowercase()
+                        .strip_prefix("content-length:")
+                        .map(|v| v.trim().to_owned())
+                })
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or(0);
+            if n == 0 || request.len() >= end + 4 + length {
+                let mut line = text.split_whitespace();
+                let verb = line.next()?.to_owned();
+                let path = line.next()?.to_owned();
+                let body = serde_json::from_slice(&request[end + 4..]).unwrap_or(Value::Null);
+                return Some((verb, path, body));
+            }
+        }
+        if n == 0 {
+            return None;
+        }
+    }
+}
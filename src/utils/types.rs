@@ -1,129 +1,132 @@
 use serde::ser::{SerializeMap, SerializeSeq};
 use serde::{de, Deserialize, Serialize};
-use serde_json::Number;
 
-use std::borrow::BorrowMut;
 use std::collections::HashMap;
 use std::convert::From;
-use std::ops::{Deref, Index, IndexMut};
+use std::ops::{Index, IndexMut};
 
-fn type_of<T>(_: &T) -> Option<&str> {
-    Some(std::any::type_name::<T>())
-}
+use crate::utils::error::{ErrorKind, GeckError};
 
 type BoxedValue = Box<DictType>;
 type BoxedVec = Vec<BoxedValue>;
 type BoxedStringMap = HashMap<String, BoxedValue>;
 
-#[derive(Debug, Deserialize, Clone, PartialEq)]
+static NULL: DictType = DictType::Null;
+
+/// A JSON compatible value, convertible from and into `serde_json::Value`
+#[derive(Debug, Clone, PartialEq)]
 pub enum DictType {
     Number(i32),
+    /// Integers not fitting in `Number`
+    Long(i64),
+    Float(f64),
     String(String),
     Bool(bool),
     Vec(BoxedVec),
     Map(BoxedStringMap),
+    Null,
 }
 
 impl DictType {
-    /// A method to insert in vec in maps in the context recursively
-    fn insert<T>(dict: &mut HashMap<String, BoxedValue>, key: &str, new_value: T)
+    /// A method to insert in vec in maps in the context recursively.
+    /// Maps are merged, vecs are appended and anything else is replaced.
+    fn insert<T>(dict: &mut BoxedStringMap, key: &str, new_value: T)
     where
         T: Into<DictType>,
     {
         match dict.get_mut(key) {
-            Some(val) => {
-                // If the value exists, we recursively insert considering type
-                match val.borrow_mut() {
-                    DictType::Bool(_) | DictType::Number(_) | DictType::String(_) => {
-                        *val = Box::new(new_value.into())
-                    }
-                    DictType::Vec(t) => t.push(Box::new(new_value.into())),
-                    DictType::Map(t) => {
-                        if let DictType::Map(newv) = new_value.into() {
-                            for key in newv.keys() {
-                                let inner_h = *(*(newv.get(key).unwrap())).clone(); // TODO: This doesn't sound like a very good idea
-                                DictType::insert::<DictType>(t, &key, inner_h);
-                            }
-                        } else {
-                            panic!("The value you are appending alreadys holds map type, you cannot append another type");
-                        }
+            Some(val) => match (val.as_mut(), new_value.into()) {
+                (DictType::Map(t), DictType::Map(newv)) => {
+                    for (key, inner) in newv {
+                        DictType::insert(t, &key, *inner);
                     }
                 }
-            }
+                (DictType::Vec(t), DictType::Vec(newv)) => t.extend(newv),
+                (DictType::Vec(t), newv) => t.push(Box::new(newv)),
+                (_, newv) => **val = newv,
+            },
             // If the value, does not exist just insert into it.
-            _ => {
+            None => {
                 dict.insert(key.to_owned(), Box::new(new_value.into()));
             }
         }
     }
 
-    fn len(&self) -> usize {
+    /// Number of entries of a map or vec, `None` for primitive types
+    pub fn len(&self) -> Option<usize> {
         match self {
-            Self::Map(v) => v.len(),
-            Self::Vec(v) => v.len(),
-            _ => panic!("We don't implement this on primitive types"),
+            Self::Map(v) => Some(v.len()),
+            Self::Vec(v) => Some(v.len()),
+            _ => None,
         }
     }
 
-    fn extend(&mut self, other: DictType) {
-        match self {
-            Self::Map(v) => {
-                let unwrapped_other = other.take_map();
-                for key in unwrapped_other.keys() {
-                    let inner_h = *(*(unwrapped_other.get(key).unwrap())).clone(); // TODO: This doesn't sound like a very good idea
-                    DictType::insert::<DictType>(v, &key, inner_h); // TODO Clone a good idea?
+    /// True for empty maps and vecs only
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Self::Null)
+    }
+
+    /// Merges maps and appends vecs, other types are replaced by `other`
+    pub fn extend(&mut self, other: DictType) {
+        match (self, other) {
+            (Self::Map(v), Self::Map(other)) => {
+                for (key, inner) in other {
+                    DictType::insert(v, &key, *inner);
                 }
             }
-            Self::Vec(v) => v.extend(other.take_vec().into_iter()),
-            _ => panic!("We don't implement this on primitive types"),
+            (Self::Vec(v), Self::Vec(other)) => v.extend(other),
+            (this, other) => *this = other,
         }
     }
 
-    fn take_vec(self) -> BoxedVec {
+    pub fn get(&self, key: &str) -> Option<&DictType> {
         match self {
-            Self::Vec(v) => v,
-            _ => panic!("We don't implement this on primitive types"),
+            Self::Map(v) => v.get(key).map(|v| v.as_ref()),
+            _ => None,
         }
     }
 
-    fn take_map(self) -> BoxedStringMap {
+    /// The map, turning any other type into an empty map first
+    fn as_map_mut(&mut self) -> &mut BoxedStringMap {
+        if !matches!(self, Self::Map(_)) {
+            *self = Self::Map(HashMap::new());
+        }
         match self {
             Self::Map(v) => v,
-            _ => panic!("We don't implement this on primitive types"),
+            _ => unreachable!(),
         }
     }
 
-    fn take_vec_mut(&mut self) -> &mut BoxedVec {
-        match self {
-            Self::Vec(v) => v,
-            _ => panic!("We don't implement this on primitive types"),
+    /// The vec, turning any other type into an empty vec first
+    fn as_vec_mut(&mut self) -> &mut BoxedVec {
+        if !matches!(self, Self::Vec(_)) {
+            *self = Self::Vec(Vec::new());
         }
-    }
-
-    fn take_map_mut(&mut self) -> &mut BoxedStringMap {
         match self {
-            Self::Map(v) => v,
-            _ => panic!("We don't implement this on primitive types"),
+            Self::Vec(v) => v,
+            _ => unreachable!(),
         }
     }
 }
 
+/// Missing keys and non map values index to `Null`, like `serde_json::Value`
 impl Index<&'_ str> for DictType {
     type Output = DictType;
     fn index(&self, s: &str) -> &DictType {
-        match self {
-            Self::Map(v) => v.get(s).unwrap(),
-            _ => panic!("You cannot index this type with string"),
-        }
+        self.get(s).unwrap_or(&NULL)
     }
 }
 
+/// Non map values become maps and missing keys are inserted as `Null`
 impl IndexMut<&'_ str> for DictType {
     fn index_mut(&mut self, s: &str) -> &mut Self::Output {
-        match self {
-            Self::Map(v) => v.get_mut(s).unwrap(),
-            _ => panic!("You cannot index this type with string"),
-        }
+        self.as_map_mut()
+            .entry(s.to_owned())
+            .or_insert_with(|| Box::new(DictType::Null))
     }
 }
 
@@ -131,18 +134,20 @@ impl Index<usize> for DictType {
     type Output = DictType;
     fn index(&self, s: usize) -> &DictType {
         match self {
-            Self::Vec(v) => v.get(s).unwrap(),
-            _ => panic!("You cannot index this type with number"),
+            Self::Vec(v) => v.get(s).map(|v| v.as_ref()).unwrap_or(&NULL),
+            _ => &NULL,
         }
     }
 }
 
+/// Non vec values become vecs and the vec is padded with `Null` up to the index
 impl IndexMut<usize> for DictType {
     fn index_mut(&mut self, s: usize) -> &mut Self::Output {
-        match self {
-            Self::Vec(v) => v.get_mut(s).unwrap(),
-            _ => panic!("You cannot index this type with number"),
+        let v = self.as_vec_mut();
+        if v.len() <= s {
+            v.resize_with(s + 1, || Box::new(DictType::Null));
         }
+        &mut v[s]
     }
 }
 
@@ -152,6 +157,20 @@ impl From<i32> for DictType {
     }
 }
 
+impl From<i64> for DictType {
+    fn from(v: i64) -> DictType {
+        i32::try_from(v)
+            .map(DictType::Number)
+            .unwrap_or(DictType::Long(v))
+    }
+}
+
+impl From<f64> for DictType {
+    fn from(v: f64) -> DictType {
+        DictType::Float(v)
+    }
+}
+
 impl From<&str> for DictType {
     fn from(v: &str) -> DictType {
         DictType::String(v.to_owned())
@@ -170,6 +189,21 @@ impl From<bool> for DictType {
     }
 }
 
+impl From<()> for DictType {
+    fn from(_: ()) -> DictType {
+        DictType::Null
+    }
+}
+
+impl<T> From<Option<T>> for DictType
+where
+    T: Into<DictType>,
+{
+    fn from(v: Option<T>) -> DictType {
+        v.map(|v| v.into()).unwrap_or(DictType::Null)
+    }
+}
+
 impl<T> From<Vec<T>> for DictType
 where
     T: Into<DictType> + Clone,
@@ -205,27 +239,75 @@ where
     }
 }
 
+/// Integers become `Number` or `Long`, other numbers become `Float`
+impl From<serde_json::Value> for DictType {
+    fn from(v: serde_json::Value) -> DictType {
+        match v {
+            serde_json::Value::Null => DictType::Null,
+            serde_json::Value::Bool(b) => DictType::Bool(b),
+            serde_json::Value::Number(n) => match n.as_i64() {
+                Some(n) => n.into(),
+                None => DictType::Float(n.as_f64().unwrap_or(f64::NAN)),
+            },
+            serde_json::Value::String(s) => DictType::String(s),
+            serde_json::Value::Array(a) => {
+                DictType::Vec(a.into_iter().map(|v| Box::new(v.into())).collect())
+            }
+            serde_json::Value::Object(o) => DictType::Map(
+                o.into_iter()
+                    .map(|(k, v)| (k, Box::new(v.into())))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Non finite floats become `Null`, like `serde_json` does
+impl From<DictType> for serde_json::Value {
+    fn from(v: DictType) -> serde_json::Value {
+        match v {
+            DictType::Null => serde_json::Value::Null,
+            DictType::Bool(b) => serde_json::Value::Bool(b),
+            DictType::Number(n) => n.into(),
+            DictType::Long(n) => n.into(),
+            DictType::Float(n) => serde_json::Number::from_f64(n)
+                .map(serde_json::Value::Number)
+                .unwrap_or(serde_json::Value::Null),
+            DictType::String(s) => serde_json::Value::String(s),
+            DictType::Vec(v) => {
+                serde_json::Value::Array(v.into_iter().map(|v| (*v).into()).collect())
+            }
+            DictType::Map(m) => {
+                serde_json::Value::Object(m.into_iter().map(|(k, v)| (k, (*v).into())).collect())
+            }
+        }
+    }
+}
+
 impl Serialize for DictType {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        /// Serialize while copying
         match self {
+            Self::Null => serializer.serialize_unit(),
             Self::Bool(val) => serializer.serialize_bool(*val),
             Self::Number(val) => serializer.serialize_i32(*val),
-            Self::String(val) => serializer.serialize_str(&val),
+            Self::Long(val) => serializer.serialize_i64(*val),
+            Self::Float(val) if val.is_finite() => serializer.serialize_f64(*val),
+            Self::Float(_) => serializer.serialize_unit(),
+            Self::String(val) => serializer.serialize_str(val),
             Self::Vec(val) => {
                 let mut seq = serializer.serialize_seq(Some(val.len()))?;
                 for el in val {
-                    seq.serialize_element(el);
+                    seq.serialize_element(el)?;
                 }
                 seq.end()
             }
             Self::Map(val) => {
                 let mut ser = serializer.serialize_map(Some(val.len()))?;
                 for (k, v) in val.iter() {
-                    ser.serialize_entry::<String, DictType>(k, &v).unwrap();
+                    ser.serialize_entry::<String, DictType>(k, v)?;
                 }
                 ser.end()
             }
@@ -233,12 +315,27 @@ impl Serialize for DictType {
     }
 }
 
+impl<'de> Deserialize<'de> for DictType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        serde_json::Value::deserialize(deserializer).map(DictType::from)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Dict {
     values: DictType, // A map type infact
     num_entries: usize,
 }
 
+impl Default for Dict {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Dict {
     /// A container logic to hold parameters to be determined on compile time. We pass
     /// string views owned by the main application and the idea is all these variables are known at
@@ -252,18 +349,33 @@ impl Dict {
     }
 
     pub fn insert<V: Into<DictType>>(&mut self, k: &str, new_value: V) {
-        DictType::insert(self.values.take_map_mut(), k, new_value.into());
-        self.num_entries = self.values.len();
+        DictType::insert(self.values.as_map_mut(), k, new_value.into());
+        self.num_entries = self.values.len().unwrap_or_default();
     }
 
     pub fn extend(&mut self, dict: Dict) {
         self.values.extend(dict.values);
-        self.num_entries = self.values.len();
+        self.num_entries = self.values.len().unwrap_or_default();
+    }
+
+    pub fn get(&self, key: &str) -> Option<&DictType> {
+        self.values.get(key)
     }
 
     pub fn len(&self) -> Option<usize> {
         Some(self.num_entries)
     }
+
+    pub fn is_empty(&self) -> bool {
+        self.num_entries == 0
+    }
+}
+
+impl Index<&'_ str> for Dict {
+    type Output = DictType;
+    fn index(&self, s: &str) -> &DictType {
+        &self.values[s]
+    }
 }
 
 impl From<&HashMap<&str, &str>> for Dict {
@@ -282,16 +394,46 @@ impl From<Dict> for DictType {
     }
 }
 
+impl From<Dict> for serde_json::Value {
+    fn from(v: Dict) -> serde_json::Value {
+        v.values.into()
+    }
+}
+
+/// Only JSON objects can become a `Dict`
+impl TryFrom<serde_json::Value> for Dict {
+    type Error = GeckError;
+    fn try_from(v: serde_json::Value) -> Result<Dict, GeckError> {
+        match DictType::from(v) {
+            DictType::Map(values) => Ok(Dict {
+                num_entries: values.len(),
+                values: DictType::Map(values),
+            }),
+            other => Err(GeckError::new(
+                ErrorKind::Other,
+                None::<GeckError>,
+                &format!("Dict can only hold a map, got {:?}", other),
+            )),
+        }
+    }
+}
+
 impl Serialize for Dict {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let mut map_ser = serializer.serialize_map(self.len())?;
-        for (k, v) in self.values.clone().take_map().iter() {
-            map_ser.serialize_entry::<String, DictType>(k, v).unwrap();
-        }
-        map_ser.end()
+        self.values.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Dict {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        Dict::try_from(value).map_err(de::Error::custom)
     }
 }
 
@@ -299,10 +441,7 @@ impl Serialize for Dict {
 mod tests {
 
     use super::*;
-    use assert_json_diff::assert_json_include;
-    use serde_json;
-    use simplelog::*;
-    use std::{thread, time};
+    use serde_json::json;
 
     #[test]
     fn test_dict() {
@@ -385,4 +524,59 @@ mod tests {
 
         assert!(actual == expected);
     }
+
+    #[test]
+    fn test_dict_deserialize() {
+        let json = r#"{"a": {"option1": 123, "option2": true, "option3": ["x"]}}"#;
+        let dict: Dict = serde_json::from_str(json).unwrap();
+        assert_eq!(dict.len(), Some(1));
+        let actual: serde_json::Value = serde_json::to_value(&dict).unwrap();
+        let expected: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert!(actual == expected);
+        assert!(serde_json::from_str::<Dict>("[1]").is_err());
+    }
+
+    #[test]
+    fn test_value_conversion() {
+        let value = json!({
+            "float": 1.5,
+            "null": null,
+            "long": 1u64 << 40,
+            "nested": {"list": [1, "two", false, null, 0.25]}
+        });
+        let dict = Dict::try_from(value.clone()).unwrap();
+        assert_eq!(dict["float"], DictType::Float(1.5));
+        assert!(dict["null"].is_null());
+        assert_eq!(dict["long"], DictType::Long(1 << 40));
+        assert_eq!(dict["nested"]["list"][1], DictType::from("two"));
+        assert!(dict["missing"]["deeper"][3].is_null());
+        assert_eq!(serde_json::to_value(&dict).unwrap(), value);
+        assert_eq!(serde_json::Value::from(dict), value);
+        assert!(Dict::try_from(json!([1, 2])).is_err());
+        assert_eq!(
+            serde_json::Value::from(DictType::Float(f64::NAN)),
+            serde_json::Value::Null
+        );
+    }
+
+    #[test]
+    fn test_no_panics() {
+        let mut value = DictType::from(1);
+        assert_eq!(value.len(), None);
+        value.extend(DictType::from("replaced"));
+        assert_eq!(value, DictType::from("replaced"));
+        value["key"] = DictType::from(2.5);
+        assert_eq!(value["key"], DictType::Float(2.5));
+        value["list"][2] = DictType::from(true);
+        assert_eq!(value["list"].len(), Some(3));
+
+        let mut dict = Dict::new();
+        dict.insert("a", 1);
+        let mut other = Dict::new();
+        other.insert("b", 2);
+        dict.insert("a", other);
+        assert_eq!(dict["a"]["b"], DictType::Number(2));
+        dict.insert("a", None::<i32>);
+        assert!(dict["a"].is_null());
+    }
 }
@@ -1,32 +1,42 @@
-use crate::utils::error::GeckError;
+use crate::utils::error::{ErrorKind, GeckError};
 use handlebars::Handlebars;
-use serde::{self, de, Deserialize, Serialize};
+use serde::{self, Deserialize, Serialize};
 use serde_json::json;
 /// Firefox level driver constants and commands
 use std::collections::HashMap;
 
+#[allow(non_snake_case)]
 pub mod Driver {
-    pub const HOST: &'static str = "http://127.0.0.1";
-    pub const PORT: &'static str = "4444";
-    pub const ARGS_PORT: &'static str = "-p";
-    pub const ARGS_VERBOSITY: &'static str = "-v";
+    pub const HOST: &str = "http://127.0.0.1";
+    pub const PORT: &str = "4444";
+    pub const ARGS_PORT: &str = "-p";
+    pub const ARGS_VERBOSITY: &str = "-v";
 }
 
 /// Generate template string to parse driver paths
 pub fn template_str(cmd: &str, args: &str) -> Result<String, GeckError> {
-    let mut handle = Handlebars::new();
-    handle.register_template_string("tpl_name", cmd).unwrap();
-    Ok(handle
-        .render(
-            "tpl_name",
-            &json!(serde_json::from_str::<serde_json::Value>(args).unwrap()),
+    let template_error = |e: Box<dyn std::error::Error + Send + Sync>| {
+        GeckError::new(
+            ErrorKind::Driver,
+            Some(e),
+            &format!("Cannot render command path {:?} with {}", cmd, args),
         )
-        .unwrap())
+    };
+    let mut handle = Handlebars::new();
+    handle
+        .register_template_string("tpl_name", cmd)
+        .map_err(|e| template_error(e.into()))?;
+    let args =
+        serde_json::from_str::<serde_json::Value>(args).map_err(|e| template_error(e.into()))?;
+    handle
+        .render("tpl_name", &json!(args))
+        .map_err(|e| template_error(e.into()))
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CmdTemplate {
-    sessionId: String,
+    #[serde(rename = "sessionId")]
+    session_id: String,
     id: String,
 }
 pub struct WebdriverCmd<'a> {
@@ -50,6 +60,12 @@ pub struct WebDriver<'a> {
     pub command_dict: HashMap<&'a str, WebdriverCmd<'a>>,
 }
 
+impl Default for WebDriver<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a> WebDriver<'a> {
     pub fn new() -> Self {
         Self {
@@ -138,6 +154,17 @@ impl<'a> WebDriver<'a> {
             ])
         }
     }
+    /// Looks up a command by name
+    pub fn get(&self, cmd: &str) -> Result<&WebdriverCmd<'a>, GeckError> {
+        self.command_dict.get(cmd).ok_or_else(|| {
+            GeckError::new(
+                ErrorKind::Driver,
+                None::<GeckError>,
+                &format!("Unknown webdriver command {}", cmd),
+            )
+        })
+    }
+
     pub fn insert(&mut self, cmd: &'a str, request_verb: &'a str, request_path: &'a str) {
         self.command_dict
             .insert(cmd, WebdriverCmd::from((request_verb, request_path)));